@@ -1,13 +1,17 @@
 use std::error::Error;
 use std::fs::File;
+use std::io::{stdin, stdout, BufRead, Write};
 use std::process::exit;
-use clover::{Clover, Program, State};
+use clover::{Clover, Object, Program, Repl, State};
 use clover_std::clover_std_inject_to;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
-#[clap(version)]
+#[clap(version, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// compile input file
     #[clap(short, long, action)]
     compile: bool,
@@ -16,9 +20,67 @@ struct Args {
     #[clap(short, long = "output", value_parser)]
     output_filename: Option<String>,
 
-    /// source filename to run/compile
+    /// remove file and debug information when compile
+    #[clap(short, long, action)]
+    strip: bool,
+
+    /// source, assembly (.lasm) or bytecode (.lucky) filename to run/compile, start interactive mode if it is not given
     #[clap(value_parser)]
-    pub filename: String,
+    pub filename: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// print the instructions, constants and models of a source or bytecode file
+    Disasm {
+        /// source, assembly or bytecode filename to disassemble
+        #[clap(value_parser)]
+        filename: String
+    }
+}
+
+fn load_program(clover: &Clover, filename: &str) -> Result<Program, Box<dyn Error>> {
+    if filename.ends_with(".lucky") {
+        let mut file = File::open(filename)?;
+        Ok(Program::deserialize(&mut file)?)
+    } else if filename.ends_with(".lasm") {
+        Ok(clover.assemble_file(filename)?)
+    } else {
+        Ok(clover.compile_file(filename)?)
+    }
+}
+
+// read lines until the input is a complete definition or statement, then run it
+fn run_repl() -> Result<(), Box<dyn Error>> {
+    let mut repl = Repl::new();
+
+    clover_std_inject_to(repl.state_mut());
+
+    let mut source = String::new();
+    let mut lines = stdin().lock().lines();
+
+    loop {
+        print!("{}", if source.is_empty() { "> " } else { "... " });
+        stdout().flush()?;
+
+        let line = if let Some(line) = lines.next() { line? } else { break; };
+
+        source.push_str(&line);
+        source.push('\n');
+
+        match repl.eval(&source) {
+            Ok(Object::Null) => {},
+            Ok(object) => println!("{}", object),
+            Err(error) if error.is_incomplete() => continue,
+            Err(error) => eprintln!("{}", error)
+        };
+
+        source.clear();
+    };
+
+    println!();
+
+    Ok(())
 }
 
 
@@ -27,23 +89,29 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let clover = Clover::new();
 
-    let filename: String = args.filename.clone();
+    if let Some(Command::Disasm { filename }) = args.command {
+        print!("{}", load_program(&clover, &filename)?.disassemble());
+        return Ok(());
+    };
 
-    let program = if filename.ends_with(".lucky") {
-        if args.compile {
-            // can not compile a lucky file
-            println!("can not compile lucky file");
-            exit(-1);
-        }
+    let filename: String = if let Some(filename) = args.filename { filename } else {
+        return run_repl();
+    };
 
-        let mut file = File::open(filename)?;
-        Program::deserialize(&mut file)?
-    } else {
-        clover.compile_file(filename.as_str())?
+    if filename.ends_with(".lucky") && args.compile {
+        // can not compile a lucky file
+        println!("can not compile lucky file");
+        exit(-1);
     };
 
+    let mut program = load_program(&clover, &filename)?;
+
     if args.compile {
-        let output_filename = args.output_filename.unwrap_or(if args.filename.ends_with("luck") { args.filename + "y" } else { args.filename + ".lucky" });
+        let output_filename = args.output_filename.unwrap_or(if filename.ends_with("luck") { filename + "y" } else { filename + ".lucky" });
+
+        if args.strip {
+            program.strip_debug_info();
+        };
 
         let mut file = File::create(output_filename)?;
 
@@ -54,7 +122,10 @@ fn main() -> Result<(), Box<dyn Error>> {
 
         clover_std_inject_to(&mut state);
 
-        state.execute()?;
+        if let Err(error) = state.execute() {
+            eprintln!("{}", error);
+            exit(-1);
+        };
     }
 
     Ok(())
@@ -0,0 +1,426 @@
+//! derive macros to export rust types to Clover
+//!
+//! `#[native_methods]` on an impl block exports the functions marked with `#[method]`, `#[function]`, `#[constant]` or `#[constructor]`,
+//! use `#[method(name = "...")]` when the name in script is different,
+//! `#[derive(NativeModelInstance)]` exports the fields marked with `#[clover(property)]` and the methods,
+//! `#[derive(NativeModel)]` exports the functions and the constructor, use `#[clover(instance = Type)]` when they are in another type
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, FnArg, ImplItem, ImplItemFn, ItemImpl, LitStr, Meta, Type};
+
+enum ExportKind {
+    Method,
+    Function,
+    Constant,
+    Constructor
+}
+
+fn export_kind(attribute: &Attribute) -> Option<ExportKind> {
+    let path = attribute.path();
+
+    if path.is_ident("method") {
+        Some(ExportKind::Method)
+    } else if path.is_ident("function") {
+        Some(ExportKind::Function)
+    } else if path.is_ident("constant") {
+        Some(ExportKind::Constant)
+    } else if path.is_ident("constructor") {
+        Some(ExportKind::Constructor)
+    } else {
+        None
+    }
+}
+
+// the name in script, default is the name of rust function
+fn export_name(attribute: &Attribute, function: &ImplItemFn) -> Result<String, Error> {
+    let mut name = function.sig.ident.to_string();
+
+    if let Meta::List(_) = attribute.meta {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("unknown attribute, only name is supported"))
+            }
+        })?;
+    };
+
+    Ok(name)
+}
+
+// types of the parameters from script, self is not included
+fn parameter_types(function: &ImplItemFn) -> Vec<Type> {
+    function.sig.inputs.iter().filter_map(|input| match input {
+        FnArg::Typed(pattern) => Some(pattern.ty.as_ref().clone()),
+        FnArg::Receiver(_) => None
+    }).collect()
+}
+
+// check parameter count and convert every parameter
+fn convert_parameters(function: &ImplItemFn) -> (TokenStream2, Vec<TokenStream2>) {
+    let types = parameter_types(function);
+    let count = types.len();
+
+    let check = quote! {
+        ::clover::helper::expect_max_parameter_count(state, parameters, #count)?;
+    };
+
+    let parameters = types.iter().enumerate().map(|(index, parameter_type)| quote! {
+        ::clover::helper::expect_parameter::<#parameter_type>(state, parameters, #index)?
+    }).collect();
+
+    (check, parameters)
+}
+
+#[proc_macro_attribute]
+pub fn native_methods(_attribute: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item_impl = parse_macro_input!(item as ItemImpl);
+
+    let mut method_names = Vec::new();
+    let mut method_arms = Vec::new();
+    let mut model_arms = Vec::new();
+    let mut constructor = None;
+
+    for impl_item in item_impl.items.iter_mut() {
+        let function = if let ImplItem::Fn(function) = impl_item { function } else { continue; };
+
+        let attribute_index = if let Some(index) = function.attrs.iter().position(|attribute| export_kind(attribute).is_some()) { index } else { continue; };
+        let attribute = function.attrs.remove(attribute_index);
+        let kind = export_kind(&attribute).unwrap();
+
+        let name = match export_name(&attribute, function) {
+            Ok(name) => name,
+            Err(error) => return error.to_compile_error().into()
+        };
+
+        let identifier = &function.sig.ident;
+        let has_receiver = function.sig.receiver().is_some();
+
+        match kind {
+            ExportKind::Method => {
+                if !has_receiver {
+                    return Error::new_spanned(&function.sig, "#[method] need self").to_compile_error().into();
+                };
+
+                let (check, parameters) = convert_parameters(function);
+
+                method_names.push(name.clone());
+                method_arms.push(quote! {
+                    #name => Some((|| {
+                        #check
+                        let result = self.#identifier(#(#parameters),*);
+                        ::clover::helper::IntoNativeResult::into_native_result(result, state)
+                    })()),
+                });
+            },
+            ExportKind::Function => {
+                if has_receiver {
+                    return Error::new_spanned(&function.sig, "#[function] can not have self").to_compile_error().into();
+                };
+
+                model_arms.push(quote! {
+                    #name => Some(::clover::Object::NativeFunction(::clover::helper::make_typed_function(Self::#identifier))),
+                });
+            },
+            ExportKind::Constant => {
+                if has_receiver || !function.sig.inputs.is_empty() {
+                    return Error::new_spanned(&function.sig, "#[constant] can not have parameters").to_compile_error().into();
+                };
+
+                model_arms.push(quote! {
+                    #name => Some(::clover::IntoObject::into_object(Self::#identifier())),
+                });
+            },
+            ExportKind::Constructor => {
+                if has_receiver {
+                    return Error::new_spanned(&function.sig, "#[constructor] can not have self").to_compile_error().into();
+                };
+
+                if constructor.is_some() {
+                    return Error::new_spanned(&function.sig, "only one #[constructor] is allowed").to_compile_error().into();
+                };
+
+                let (check, parameters) = convert_parameters(function);
+
+                constructor = Some(quote! {
+                    Some((|| {
+                        #check
+                        let result = Self::#identifier(#(#parameters),*);
+                        ::clover::helper::IntoNativeInstance::into_native_instance(result, state)
+                    })())
+                });
+            }
+        };
+    };
+
+    let constructor = constructor.unwrap_or_else(|| quote! { None });
+
+    let (impl_generics, _, where_clause) = item_impl.generics.split_for_impl();
+    let self_type = &item_impl.self_ty;
+
+    let expanded = quote! {
+        #item_impl
+
+        impl #impl_generics ::clover::helper::NativeMethods for #self_type #where_clause {
+            fn has_method(key: &str) -> bool {
+                [ #(#method_names),* ].contains(&key)
+            }
+
+            #[allow(unused_variables)]
+            fn call_method(&mut self, state: &mut ::clover::State, key: &str, parameters: &[ ::clover::Object ]) -> Option<Result<::clover::Object, ::clover::debug::RuntimeError>> {
+                match key {
+                    #(#method_arms)*
+                    _ => None
+                }
+            }
+
+            fn model_get(key: &str) -> Option<::clover::Object> {
+                match key {
+                    #(#model_arms)*
+                    _ => None
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn construct(state: &mut ::clover::State, parameters: &[ ::clover::Object ]) -> Option<Result<::clover::Object, ::clover::debug::RuntimeError>> {
+                #constructor
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+struct Property {
+    identifier: syn::Ident,
+    field_type: Type,
+    readonly: bool
+}
+
+fn parse_properties(input: &DeriveInput) -> Result<Vec<Property>, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(Error::new_spanned(input, "NativeModelInstance can only be derived for struct"))
+    };
+
+    let mut properties = Vec::new();
+
+    if let Fields::Named(fields) = fields {
+        for field in fields.named.iter() {
+            let mut is_property = false;
+            let mut readonly = false;
+
+            for attribute in field.attrs.iter().filter(|attribute| attribute.path().is_ident("clover")) {
+                attribute.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("property") {
+                        is_property = true;
+                        Ok(())
+                    } else if meta.path.is_ident("readonly") {
+                        readonly = true;
+                        Ok(())
+                    } else {
+                        Err(meta.error("unknown clover attribute"))
+                    }
+                })?;
+            };
+
+            if is_property {
+                properties.push(Property {
+                    identifier: field.ident.clone().unwrap(),
+                    field_type: field.ty.clone(),
+                    readonly
+                });
+            } else if readonly {
+                return Err(Error::new_spanned(field, "readonly need property"));
+            };
+        };
+    };
+
+    Ok(properties)
+}
+
+// #[clover(lenient)] on the type, getting an unknown key gives null and setting it is ignored
+fn parse_lenient(input: &DeriveInput) -> Result<bool, Error> {
+    let mut lenient = false;
+
+    for attribute in input.attrs.iter().filter(|attribute| attribute.path().is_ident("clover")) {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("lenient") {
+                lenient = true;
+                Ok(())
+            } else {
+                Err(meta.error("unknown clover attribute"))
+            }
+        })?;
+    };
+
+    Ok(lenient)
+}
+
+// results of getting and setting a key which is not exported, the error is given to both
+fn missing_key_results(lenient: bool, error: TokenStream2) -> (TokenStream2, TokenStream2) {
+    if lenient {
+        (quote! { Ok(::clover::Object::Null) }, quote! { Ok(()) })
+    } else {
+        (quote! { Err(#error) }, quote! { Err(#error) })
+    }
+}
+
+#[proc_macro_derive(NativeModelInstance, attributes(clover))]
+pub fn derive_native_model_instance(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let properties = match parse_properties(&input) {
+        Ok(properties) => properties,
+        Err(error) => return error.to_compile_error().into()
+    };
+
+    let lenient = match parse_lenient(&input) {
+        Ok(lenient) => lenient,
+        Err(error) => return error.to_compile_error().into()
+    };
+
+    let (missing_get, missing_set) = missing_key_results(lenient, quote! { ::clover::debug::RuntimeError::new(&format!("index [{}] does not exists", key), ::clover::debug::Position::none()) });
+    let (missing_index_get, missing_index_set) = missing_key_results(lenient, quote! { ::clover::debug::RuntimeError::new("index does not exists", ::clover::debug::Position::none()) });
+
+    let get_arms = properties.iter().map(|property| {
+        let identifier = &property.identifier;
+        let name = identifier.to_string();
+
+        quote! {
+            #name => Ok(::clover::IntoObject::into_object(self.#identifier.clone())),
+        }
+    });
+
+    let set_arms = properties.iter().map(|property| {
+        let identifier = &property.identifier;
+        let field_type = &property.field_type;
+        let name = identifier.to_string();
+
+        if property.readonly {
+            quote! {
+                #name => Err(::clover::debug::RuntimeError::new(&format!("can not change property [{}]", key), ::clover::debug::Position::none())),
+            }
+        } else {
+            quote! {
+                #name => {
+                    self.#identifier = ::clover::helper::expect_property::<#field_type>(key, &value)?;
+                    Ok(())
+                },
+            }
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::clover::NativeModelInstance for #name #type_generics #where_clause {
+            fn index_get(&self, this: ::clover::Reference<dyn ::clover::NativeModelInstance>, index: &::clover::Object) -> Result<::clover::Object, ::clover::debug::RuntimeError> {
+                if let ::clover::Object::String(key) = index {
+                    let key = key.borrow().clone();
+                    self.instance_get(this, &key)
+                } else {
+                    #missing_index_get
+                }
+            }
+
+            fn index_set(&mut self, this: ::clover::Reference<dyn ::clover::NativeModelInstance>, index: &::clover::Object, value: ::clover::Object) -> Result<(), ::clover::debug::RuntimeError> {
+                if let ::clover::Object::String(key) = index {
+                    let key = key.borrow().clone();
+                    self.instance_set(this, &key, value)
+                } else {
+                    #missing_index_set
+                }
+            }
+
+            fn instance_get(&self, this: ::clover::Reference<dyn ::clover::NativeModelInstance>, key: &str) -> Result<::clover::Object, ::clover::debug::RuntimeError> {
+                match key {
+                    #(#get_arms)*
+                    _ if <Self as ::clover::helper::NativeMethods>::has_method(key) => Ok(::clover::Object::InstanceNativeFunction(this, key.to_string())),
+                    _ => #missing_get
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn instance_set(&mut self, this: ::clover::Reference<dyn ::clover::NativeModelInstance>, key: &str, value: ::clover::Object) -> Result<(), ::clover::debug::RuntimeError> {
+                match key {
+                    #(#set_arms)*
+                    _ if <Self as ::clover::helper::NativeMethods>::has_method(key) => Err(::clover::debug::RuntimeError::new(&format!("can not change property [{}]", key), ::clover::debug::Position::none())),
+                    _ => #missing_set
+                }
+            }
+
+            fn call(&mut self, this: ::clover::Reference<dyn ::clover::NativeModelInstance>, state: &mut ::clover::State, key: &str, parameters: &[ ::clover::Object ]) -> Result<::clover::Object, ::clover::debug::RuntimeError> {
+                match ::clover::helper::NativeMethods::call_method(self, state, key, parameters) {
+                    Some(result) => result,
+                    None => Err(::clover::debug::RuntimeError::new(&format!("index [{}] does not exists", key), state.last_position()))
+                }
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+#[proc_macro_derive(NativeModel, attributes(clover))]
+pub fn derive_native_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let mut instance_type: Option<Type> = None;
+    let mut lenient = false;
+
+    for attribute in input.attrs.iter().filter(|attribute| attribute.path().is_ident("clover")) {
+        let result = attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("instance") {
+                instance_type = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("lenient") {
+                lenient = true;
+                Ok(())
+            } else {
+                Err(meta.error("unknown clover attribute"))
+            }
+        });
+
+        if let Err(error) = result {
+            return error.to_compile_error().into();
+        };
+    };
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    // functions and constructor are in the instance type if there is one
+    let methods_type = match instance_type {
+        Some(instance_type) => quote! { #instance_type },
+        None => quote! { Self }
+    };
+
+    let name_string = name.to_string();
+
+    let (missing_get, _) = missing_key_results(lenient, quote! { ::clover::debug::RuntimeError::new(&format!("{} do not have property [{}]", #name_string, key), ::clover::debug::Position::none()) });
+
+    let expanded = quote! {
+        impl #impl_generics ::clover::NativeModel for #name #type_generics #where_clause {
+            fn call(&mut self, state: &mut ::clover::State, parameters: &[ ::clover::Object ]) -> Result<::clover::Object, ::clover::debug::RuntimeError> {
+                match <#methods_type as ::clover::helper::NativeMethods>::construct(state, parameters) {
+                    Some(result) => result,
+                    None => Err(::clover::debug::RuntimeError::new(&format!("{} do not have constructor", #name_string), state.last_position()))
+                }
+            }
+
+            fn model_get(&self, key: &str) -> Result<::clover::Object, ::clover::debug::RuntimeError> {
+                match <#methods_type as ::clover::helper::NativeMethods>::model_get(key) {
+                    Some(object) => Ok(object),
+                    None => #missing_get
+                }
+            }
+        }
+    };
+
+    expanded.into()
+}
@@ -0,0 +1,164 @@
+use clover::{Clover, State, Object};
+use clover::helper::make_reference;
+use clover_derive::{NativeModel, NativeModelInstance, native_methods};
+
+#[derive(NativeModel)]
+#[clover(instance = CounterInstance)]
+struct Counter;
+
+#[derive(Debug, NativeModelInstance)]
+struct CounterInstance {
+    #[clover(property, readonly)]
+    count: i64,
+    #[clover(property)]
+    step: i64,
+    #[clover(property, readonly)]
+    name: String
+}
+
+#[native_methods]
+impl CounterInstance {
+    #[constructor]
+    fn new(count: Option<i64>) -> Result<CounterInstance, String> {
+        let count = count.unwrap_or(0);
+
+        if count < 0 {
+            return Err("count can not be negative".to_string());
+        };
+
+        Ok(CounterInstance { count, step: 1, name: "counter".to_string() })
+    }
+
+    #[method]
+    fn increase(&mut self) {
+        self.count += self.step;
+    }
+
+    #[method]
+    fn add(&mut self, value: i64) -> i64 {
+        self.count += value;
+        self.count
+    }
+
+    #[method(name = "to_string")]
+    fn describe(&self) -> String {
+        format!("{}: {}", self.name, self.count)
+    }
+
+    #[function]
+    fn double(value: i64) -> i64 {
+        value * 2
+    }
+
+    #[constant(name = "MAX")]
+    fn max() -> i64 {
+        100
+    }
+}
+
+#[derive(NativeModel)]
+#[clover(instance = LenientInstance, lenient)]
+struct Lenient;
+
+#[derive(NativeModelInstance)]
+#[clover(lenient)]
+struct LenientInstance {
+    #[clover(property, readonly)]
+    value: i64
+}
+
+#[native_methods]
+impl LenientInstance {
+    #[constructor]
+    fn new() -> LenientInstance {
+        LenientInstance { value: 1 }
+    }
+}
+
+fn create_state() -> State {
+    let clover = Clover::new();
+    let mut state = clover.create_state_by_filename("tests/derive.luck").unwrap();
+
+    state.add_native_model("Counter", make_reference(Counter));
+    state.add_native_model("Lenient", make_reference(Lenient));
+
+    state
+}
+
+fn execute_function(state: &mut State, function_name: &str) -> Result<Object, clover::debug::RuntimeError> {
+    state.call(function_name, &[])
+}
+
+#[test]
+fn native_model_instance() {
+    let mut state = create_state();
+
+    for function_name in [ "properties", "methods", "renamed_method", "model_functions" ] {
+        let result = execute_function(&mut state, function_name);
+        assert!(matches!(result, Ok(Object::Boolean(true))), "function [{}] returns {:?}", function_name, result);
+    };
+}
+
+#[test]
+fn native_model_instance_errors() {
+    let mut state = create_state();
+
+    let messages: Vec<String> = [ "set_readonly_property", "wrong_property_type", "wrong_parameter_type", "constructor_error" ].iter()
+        .map(|function_name| execute_function(&mut state, function_name).unwrap_err().message)
+        .collect();
+
+    assert_eq!(messages, vec![
+        "can not change property [name]",
+        "property [step] need Integer, got String",
+        "parameter 1 need Integer, got String",
+        "count can not be negative"
+    ]);
+}
+
+#[test]
+fn lenient_native_model_instance() {
+    let mut state = create_state();
+
+    let result = execute_function(&mut state, "lenient_unknown_keys");
+    assert!(matches!(result, Ok(Object::Boolean(true))), "function [lenient_unknown_keys] returns {:?}", result);
+
+    // known keys are still checked
+    assert_eq!(execute_function(&mut state, "lenient_set_readonly_property").unwrap_err().message, "can not change property [value]");
+    assert_eq!(execute_function(&mut state, "unknown_property").unwrap_err().message, "index [unknown] does not exists");
+}
+
+#[derive(Debug, NativeModelInstance)]
+struct Other;
+
+#[native_methods]
+impl Other {}
+
+#[test]
+fn downcast_native_instance() {
+    let mut state = create_state();
+
+    state.add_typed_function("total", |counters: Vec<Object>| {
+        let mut total = 0;
+
+        for counter in counters.iter() {
+            total += counter.native_instance_as::<CounterInstance>().map_err(|error| error.message)?.count;
+        };
+
+        Ok::<i64, String>(total)
+    });
+
+    let result = execute_function(&mut state, "total_of_counters");
+    assert!(matches!(result, Ok(Object::Boolean(true))), "function [total_of_counters] returns {:?}", result);
+
+    let counter = execute_function(&mut state, "make_counter").unwrap();
+
+    counter.native_instance_as_mut::<CounterInstance>().unwrap().increase();
+    assert_eq!(counter.native_instance_as::<CounterInstance>().unwrap().count, 8);
+
+    let borrowed = counter.native_instance_as::<CounterInstance>().unwrap();
+    assert_eq!(counter.native_instance_as_mut::<CounterInstance>().unwrap_err().message, "native instance is already borrowed");
+    drop(borrowed);
+
+    assert!(counter.native_instance_as::<Other>().unwrap_err().message.starts_with("native instance is not ["));
+    assert_eq!(Object::Integer(1).native_instance_as::<CounterInstance>().unwrap_err().message, "value is not a native instance");
+}
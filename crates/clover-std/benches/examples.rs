@@ -0,0 +1,58 @@
+use std::env::set_current_dir;
+use std::fs::read_dir;
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use clover::{Clover, Object, State};
+use clover_std::clover_std_inject_to;
+
+// run at the root of workspace like the cli, so the include paths in examples can be resolved
+const WORKSPACE_DIRECTORY: &str = "../..";
+const EXAMPLES_DIRECTORY: &str = "examples";
+
+fn example_filenames() -> Vec<String> {
+    let mut filenames: Vec<String> = read_dir(EXAMPLES_DIRECTORY).unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|extension| extension == "luck").unwrap_or(false))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+
+    filenames.sort();
+    filenames
+}
+
+fn create_state(clover: &Clover, filename: &str) -> State {
+    let mut state = clover.create_state_by_filename(filename).unwrap_or_else(|error| panic!("example [{}] can not compile: {}", filename, error));
+
+    clover_std_inject_to(&mut state);
+
+    // keep the output of benchmark clean
+    state.add_native_function("print", |_, _| Ok(Object::Null));
+
+    state
+}
+
+fn examples(criterion: &mut Criterion) {
+    set_current_dir(WORKSPACE_DIRECTORY).unwrap();
+
+    let clover = Clover::new();
+    let mut group = criterion.benchmark_group("examples");
+
+    for filename in example_filenames() {
+        // every example must run to the end, a broken one fails the benchmark instead of being skipped
+        if let Err(error) = create_state(&clover, &filename).execute() {
+            panic!("example [{}] can not run: {}", filename, error);
+        };
+
+        let name = Path::new(&filename).file_stem().unwrap().to_string_lossy().to_string();
+
+        group.bench_function(name, |bencher| {
+            bencher.iter_batched(|| create_state(&clover, &filename), |mut state| state.execute().unwrap(), BatchSize::SmallInput)
+        });
+    };
+
+    group.finish();
+}
+
+criterion_group!(benches, examples);
+criterion_main!(benches);
@@ -1,14 +1,14 @@
 use clover::{State, Object, NativeModel};
 use clover::debug::RuntimeError;
-use clover::helper::make_reference;
+use clover::helper::{make_native_function, make_typed_function};
 
 pub struct IO;
 
 impl NativeModel for IO {
     fn model_get(&self, key: &str) -> Result<Object, RuntimeError> {
         match key {
-            "print" => Ok(Object::NativeFunction(print)),
-            "readline" => Ok(Object::NativeFunction(readline)),
+            "print" => Ok(Object::NativeFunction(make_native_function(print))),
+            "readline" => Ok(Object::NativeFunction(make_typed_function(readline))),
             _ => Ok(Object::Null)
         }
     }
@@ -18,7 +18,7 @@ impl NativeModel for IO {
 pub fn print(_state: &mut State, parameters: &[ Object ]) -> Result<Object, RuntimeError> {
 
     for object in parameters {
-        print!("{}", object.to_string());
+        print!("{}", object);
     };
 
     println!();
@@ -26,11 +26,9 @@ pub fn print(_state: &mut State, parameters: &[ Object ]) -> Result<Object, Runt
     Ok(Object::Null)
 }
 
-fn readline(state: &mut State, _parameters: &[ Object ]) -> Result<Object, RuntimeError> {
+fn readline() -> std::io::Result<String> {
     let mut line = String::new();
-    if let Err(error) = std::io::stdin().read_line(&mut line) {
-        Err(RuntimeError::new(error.to_string().as_str(), state.last_position()))
-    } else {
-        Ok(Object::String(make_reference(line)))
-    }
+    std::io::stdin().read_line(&mut line)?;
+
+    Ok(line)
 }
\ No newline at end of file
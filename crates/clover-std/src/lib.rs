@@ -4,9 +4,12 @@ use clover::helper::make_reference;
 mod io;
 mod random;
 mod math;
-mod helper;
 mod map;
 
+// native instances which can be downcast by host with Object::native_instance_as
+pub use map::MapInstance;
+pub use random::RandomInstance;
+
 pub fn clover_std_inject_to(state: &mut State) {
     state.add_native_function("print", io::print);
 
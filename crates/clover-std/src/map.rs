@@ -1,7 +1,7 @@
 use std::collections::HashMap;
-use clover::{NativeModel, NativeModelInstance, Object, Reference, State};
+use clover::{NativeModel, NativeModelInstance, Object, Reference, State, MapKey};
 use clover::debug::{Position, RuntimeError};
-use clover::helper::make_reference;
+use clover::helper::{make_reference, expect_parameter_count};
 
 pub struct Map;
 
@@ -11,12 +11,16 @@ impl NativeModel for Map {
     }
 }
 
-pub struct MapInstance(HashMap<String, Object>);
+pub struct MapInstance(pub HashMap<MapKey, Object>);
+
+fn map_key(index: &Object) -> Result<MapKey, RuntimeError> {
+    MapKey::from_object(index).ok_or_else(|| RuntimeError::new(&format!("can not use {} as map key", index.type_name()), Position::none()))
+}
 
 
 impl NativeModelInstance for MapInstance {
     fn index_get(&self, _this: Reference<dyn NativeModelInstance>, index: &Object) -> Result<Object, RuntimeError> {
-        let key = index.to_string();
+        let key = map_key(index)?;
 
         if let Some(object) = self.0.get(&key) {
             Ok(object.clone())
@@ -26,7 +30,7 @@ impl NativeModelInstance for MapInstance {
     }
 
     fn index_set(&mut self, _this: Reference<dyn NativeModelInstance>, index: &Object, value: Object) -> Result<(), RuntimeError> {
-        let key = index.to_string();
+        let key = map_key(index)?;
 
         self.0.insert(key, value);
 
@@ -51,13 +55,10 @@ impl NativeModelInstance for MapInstance {
     fn call(&mut self, _this: Reference<dyn NativeModelInstance>, state: &mut State, key: &str, parameters: &[Object]) -> Result<Object, RuntimeError> {
         match key {
             "contain_key" => {
-                // accept one parameter only
-                if parameters.len() == 1 {
-                    let map_key = parameters[0].to_string();
-                    Ok(Object::Boolean(self.0.contains_key(&map_key)))
-                } else {
-                    Err(RuntimeError::new(&format!("wrong number of parameters, expect 1 got {}", parameters.len()), state.last_position()))
-                }
+                expect_parameter_count(state, parameters, 1)?;
+
+                let contain_key = MapKey::from_object(&parameters[0]).map(|key| self.0.contains_key(&key)).unwrap_or(false);
+                Ok(Object::Boolean(contain_key))
             },
             _ =>  Err(RuntimeError::new("index does not exists", state.last_position()))
         }
@@ -1,23 +1,35 @@
-use clover::{NativeModel, Object};
-use clover::debug::RuntimeError;
+use clover::Object;
+use clover_derive::{NativeModel, native_methods};
 use std::f64::consts::PI;
 
 mod pow;
 mod trigonometric;
 
+// unknown keys give null, same as before the derive
+#[derive(NativeModel)]
+#[clover(lenient)]
 pub struct Math;
 
-impl NativeModel for Math {
-    fn model_get(&self, key: &str) -> Result<Object, RuntimeError> {
-        match key {
-            "pow" => Ok(Object::NativeFunction(pow::pow)),
+#[native_methods]
+impl Math {
+    #[function]
+    fn pow(base: Object, exponent: Object) -> Result<Object, String> {
+        pow::pow(base, exponent)
+    }
+
+    // trigonometric
+    #[function]
+    fn sin(value: f64) -> f64 {
+        trigonometric::sin(value)
+    }
 
-            // trigonometric
-            "sin" => Ok(Object::NativeFunction(trigonometric::sin)),
-            "cos" => Ok(Object::NativeFunction(trigonometric::cos)),
+    #[function]
+    fn cos(value: f64) -> f64 {
+        trigonometric::cos(value)
+    }
 
-            "PI" => Ok(Object::Float(PI)),
-            _ => Ok(Object::Null)
-        }
+    #[constant(name = "PI")]
+    fn pi() -> f64 {
+        PI
     }
 }
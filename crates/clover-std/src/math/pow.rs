@@ -1,14 +1,7 @@
-use clover::{State, Object};
-use clover::debug::RuntimeError;
-use crate::helper::expect_parameter_count;
+use clover::Object;
 
-pub fn pow(state: &mut State, parameters: &[ Object ]) -> Result<Object, RuntimeError> {
-    expect_parameter_count(state, parameters, 2)?;
-
-    let base_object = parameters[0].clone();
-    let exponent_object = parameters[1].clone();
-
-    let type_error = Err(RuntimeError::new("Math.pow can accept Integer or Float only", state.last_position()));
+pub fn pow(base_object: Object, exponent_object: Object) -> Result<Object, String> {
+    let type_error = Err(format!("Math.pow need Integer or Float, got {} and {}", base_object.type_name(), exponent_object.type_name()));
 
     Ok(match base_object {
         Object::Integer(base) => {
@@ -17,7 +10,7 @@ pub fn pow(state: &mut State, parameters: &[ Object ]) -> Result<Object, Runtime
                     if exponent >= 0 {
                         Object::Integer(base.pow(exponent as u32))
                     } else {
-                        return Err(RuntimeError::new("Integer can have exponent greater or equal zero", state.last_position()));
+                        return Err("Integer can have exponent greater or equal zero".to_string());
                     }
                 },
                 Object::Float(exponent) => {
@@ -1,116 +1,79 @@
-use rand::Rng;
-use rand::rngs::ThreadRng;
-use clover::{NativeModel, NativeModelInstance, Object, State, Reference};
-use clover::debug::{Position, RuntimeError};
-use clover::helper::make_reference;
-
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use clover::Object;
+use clover_derive::{NativeModel, NativeModelInstance, native_methods};
+
+// unknown keys give null and setting them is ignored, same as before the derives
+#[derive(NativeModel)]
+#[clover(instance = RandomInstance, lenient)]
 pub struct Random;
 
-impl NativeModel for Random {
-    fn call(&mut self, state: &mut State, parameters: &[Object]) -> Result<Object, RuntimeError> {
-        Random::new_random(state, parameters)
-    }
-}
-
-impl Random {
-    pub fn new_random(_state: &mut State, _parameters: &[ Object ]) -> Result<Object, RuntimeError> {
-        let random_instance = RandomInstance {
-            random: rand::rng()
-        };
-
-        Ok(Object::NativeInstance(make_reference(random_instance)))
-    }
-}
-
+#[derive(NativeModelInstance)]
+#[clover(lenient)]
 pub struct RandomInstance {
-    pub random: ThreadRng
+    // seeded by the thread rng, it can be sent to other threads unlike ThreadRng
+    pub random: StdRng
 }
 
-impl NativeModelInstance for RandomInstance {
-    fn index_get(&self, _this: Reference<dyn NativeModelInstance>, _index: &Object) -> Result<Object, RuntimeError> {
-        Ok(Object::Null)
-    }
-
-    fn index_set(&mut self, _this: Reference<dyn NativeModelInstance>, _index: &Object, _value: Object) -> Result<(), RuntimeError> {
-        Ok(())
-    }
-
-    fn instance_get(&self, this: Reference<dyn NativeModelInstance>, key: &str) -> Result<Object, RuntimeError> {
-        match key {
-            "next_integer" | "next_float" | "within" | "pick" => Ok(Object::InstanceNativeFunction(this, key.to_string())),
-            _ => Err(RuntimeError::new("index does not exists", Position::none()))
-        }
-    }
-
-    fn instance_set(&mut self, _this: Reference<dyn NativeModelInstance>, _key: &str, _value: Object) -> Result<(), RuntimeError> {
-        Ok(())
-    }
-
-    fn call(&mut self, _this: Reference<dyn NativeModelInstance>, state: &mut State, key: &str, parameters: &[Object]) -> Result<Object, RuntimeError> {
-        match key {
-            "next_integer" => self.next_integer(state, parameters),
-            "next_float" => self.next_float(state, parameters),
-            "within" => self.within(state, parameters),
-            "pick" => self.pick(state, parameters),
-            _ =>  Err(RuntimeError::new("index does not exists", Position::none()))
-        }
+impl Default for RandomInstance {
+    fn default() -> Self {
+        RandomInstance::new()
     }
 }
 
+#[native_methods]
 impl RandomInstance {
-    pub fn next_integer(&mut self, _state: &mut State, _parameters: &[ Object ]) -> Result<Object, RuntimeError> {
-        Ok(Object::Integer(self.random.random()))
+    #[constructor]
+    pub fn new() -> RandomInstance {
+        RandomInstance {
+            random: StdRng::from_rng(&mut rand::rng())
+        }
     }
 
-    pub fn next_float(&mut self, _state: &mut State, _parameters: &[ Object ]) -> Result<Object, RuntimeError> {
-        Ok(Object::Float(self.random.random()))
+    #[method]
+    pub fn next_integer(&mut self) -> i64 {
+        self.random.random()
     }
 
-    pub fn within(&mut self, _state: &mut State, parameters: &[ Object ]) -> Result<Object, RuntimeError> {
-        if parameters.len() == 0 {
-            return Ok(Object::Null);
-        };
-
-        let number = &parameters[0];
+    #[method]
+    pub fn next_float(&mut self) -> f64 {
+        self.random.random()
+    }
 
-        Ok(match number {
-            Object::Integer(value) => {
-                if *value > 0 {
-                    Object::Integer(self.random.random_range(0..*value))
+    #[method]
+    pub fn within(&mut self, number: Option<Object>) -> Object {
+        match number {
+            Some(Object::Integer(value)) => {
+                if value > 0 {
+                    Object::Integer(self.random.random_range(0..value))
                 } else {
                     Object::Integer(0)
                 }
             },
-            Object::Float(value) => {
-                if *value > 0.0 {
-                    Object::Float(self.random.random_range(0.0..*value))
+            Some(Object::Float(value)) => {
+                if value > 0.0 {
+                    Object::Float(self.random.random_range(0.0..value))
                 } else {
                     Object::Float(0.0)
                 }
             },
             _ => Object::Null
-        })
+        }
     }
 
-    pub fn pick(&mut self, _state: &mut State, parameters: &[ Object ]) -> Result<Object, RuntimeError> {
-        if parameters.len() == 0 {
-            return Ok(Object::Null);
-        };
-
-        let value = &parameters[0];
-
+    #[method]
+    pub fn pick(&mut self, value: Option<Object>) -> Object {
         match value {
-            Object::Array(array) => {
-                if array.borrow().len() > 0 {
+            Some(Object::Array(array)) => {
+                if !array.borrow().is_empty() {
                     let index = self.random.random_range(0..array.borrow().len());
-                    Ok(array.borrow()[index].clone())
+                    array.borrow()[index].clone()
                 } else {
-                    Ok(Object::Null)
+                    Object::Null
                 }
             },
 
-            _ => Ok(Object::Null)
+            _ => Object::Null
         }
-
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,682 @@
+use std::collections::HashMap;
+use crate::intermediate::{CompileErrorList, Position, Positions, Token, TokenValue};
+use crate::runtime::assembly_information::{DebugInfo, FileInfo};
+use crate::runtime::disassembler::{operation_symbol, constant_to_string};
+use crate::runtime::object::{Object, make_reference};
+use crate::runtime::opcode::{OpCode, Instruction};
+use crate::runtime::opcode::{OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, OPERATION_DIVIDE, OPERATION_MOD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL, OPERATION_AND, OPERATION_OR};
+use crate::runtime::program::{Program, Model, Function, Upvalue, Handler};
+
+const OPERATIONS: &[ usize ] = &[ OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, OPERATION_DIVIDE, OPERATION_MOD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL, OPERATION_AND, OPERATION_OR ];
+
+#[derive(Debug, Clone)]
+enum WordValue {
+    Text(String),
+    // quoted, escapes are resolved
+    String(String),
+    // [line:column]
+    Position(Position)
+}
+
+#[derive(Debug, Clone)]
+struct Word {
+    value: WordValue,
+    position: Position
+}
+
+impl Word {
+    fn to_token(&self) -> Token {
+        let value = match &self.value {
+            WordValue::Text(text) => TokenValue::Identifier(text.clone()),
+            WordValue::String(string) => TokenValue::String(string.clone()),
+            WordValue::Position(position) => TokenValue::Invalid(format!("[{}:{}]", position.line, position.column))
+        };
+
+        Token::new(value, self.position)
+    }
+
+    fn text(&self) -> Option<&str> {
+        if let WordValue::Text(text) = &self.value { Some(text) } else { None }
+    }
+}
+
+// instruction operand or handler field, a label is resolved after the whole function is read
+#[derive(Debug, Clone)]
+enum Operand {
+    Value(u64),
+    Label(Word)
+}
+
+struct FunctionBuilder {
+    name: Option<String>,
+    file_index: usize,
+    parameter_count: usize,
+    local_count: usize,
+    is_instance: bool,
+    upvalues: Vec<Upvalue>,
+    handlers: Vec<(Operand, Operand, Operand, usize)>,
+    instructions: Vec<(OpCode, Operand)>,
+    positions: Vec<Option<Position>>,
+    // label name to instruction index
+    labels: HashMap<String, usize>
+}
+
+struct ModelBuilder {
+    name: Option<String>,
+    file_index: usize,
+    model: Model
+}
+
+enum Section {
+    Program,
+    Model,
+    Function
+}
+
+struct Assembler {
+    errors: CompileErrorList,
+    filename: String,
+    line: u16,
+    section: Section,
+
+    entry_point: usize,
+    local_count: usize,
+    local_values: HashMap<usize, usize>,
+    definitions: HashMap<String, usize>,
+    global_dependencies: Vec<usize>,
+    filenames: Vec<String>,
+    constants: Vec<Object>,
+    models: Vec<ModelBuilder>,
+    functions: Vec<FunctionBuilder>
+}
+
+// split a line to words, a ; outside of strings starts a comment
+fn split_line(line: &str, line_number: u16, errors: &mut CompileErrorList) -> Vec<Word> {
+    let characters: Vec<char> = line.chars().collect();
+    let mut words = Vec::new();
+    let mut index = 0;
+
+    while index < characters.len() {
+        let character = characters[index];
+        let position = Position::new(line_number, index as u16 + 1);
+
+        if character.is_whitespace() {
+            index += 1;
+            continue;
+        };
+
+        if character == ';' {
+            break;
+        };
+
+        let start = index;
+
+        let value = match character {
+            '"' => {
+                let mut string = String::new();
+                index += 1;
+
+                while index < characters.len() && characters[index] != '"' {
+                    if characters[index] == '\\' && index + 1 < characters.len() {
+                        index += 1;
+
+                        match characters[index] {
+                            'n' => string.push('\n'),
+                            'r' => string.push('\r'),
+                            't' => string.push('\t'),
+                            '0' => string.push('\0'),
+                            'u' => {
+                                let end = characters[index..].iter().position(|&character| character == '}').map(|offset| index + offset);
+                                let code = end.and_then(|end| u32::from_str_radix(&characters[index + 2..end].iter().collect::<String>(), 16).ok()).and_then(char::from_u32);
+
+                                if let (Some(end), Some(code)) = (end, code) {
+                                    string.push(code);
+                                    index = end;
+                                } else {
+                                    errors.push_error(&Token::new(TokenValue::Invalid(characters[start..].iter().collect()), position), "invalid unicode escape");
+                                };
+                            },
+                            other => string.push(other)
+                        };
+                    } else {
+                        string.push(characters[index]);
+                    };
+
+                    index += 1;
+                };
+
+                if index >= characters.len() {
+                    errors.push_error(&Token::new(TokenValue::Invalid(string), position), "string is not closed");
+                    return words;
+                };
+
+                index += 1;
+                WordValue::String(string)
+            },
+            '[' => {
+                while index < characters.len() && characters[index] != ']' {
+                    index += 1;
+                };
+
+                let text: String = characters[start + 1..index.min(characters.len())].iter().collect();
+                index += 1;
+
+                let mut parts = text.split(':').map(|part| part.trim().parse::<u16>());
+
+                if let (Some(Ok(source_line)), Some(Ok(source_column)), None) = (parts.next(), parts.next(), parts.next()) {
+                    WordValue::Position(Position::new(source_line, source_column))
+                } else {
+                    errors.push_error(&Token::new(TokenValue::Invalid(text), position), "position should be [line:column]");
+                    continue;
+                }
+            },
+            _ => {
+                while index < characters.len() && !characters[index].is_whitespace() && characters[index] != ';' {
+                    index += 1;
+                };
+
+                WordValue::Text(characters[start..index].iter().collect())
+            }
+        };
+
+        words.push(Word { value, position });
+    };
+
+    words
+}
+
+impl Assembler {
+    fn new(filename: &str) -> Assembler {
+        Assembler {
+            errors: CompileErrorList::new(filename),
+            filename: filename.to_string(),
+            line: 0,
+            section: Section::Program,
+
+            entry_point: 0,
+            local_count: 0,
+            local_values: HashMap::new(),
+            definitions: HashMap::new(),
+            global_dependencies: Vec::new(),
+            filenames: Vec::new(),
+            constants: Program::DEFAULT_CONSTANTS.to_vec(),
+            models: Vec::new(),
+            functions: Vec::new()
+        }
+    }
+
+    fn push_error(&mut self, word: Option<&Word>, message: &str) {
+        let token = match word {
+            Some(word) => word.to_token(),
+            None => Token::new(TokenValue::None, Position::new(self.line, 0))
+        };
+
+        self.errors.push_error(&token, message);
+    }
+
+    fn expect_text<'a>(&mut self, word: Option<&'a Word>, message: &str) -> Option<&'a str> {
+        let text = word.and_then(Word::text);
+
+        if text.is_none() {
+            self.push_error(word, message);
+        };
+
+        text
+    }
+
+    fn expect_number(&mut self, word: Option<&Word>) -> Option<usize> {
+        let number = word.and_then(Word::text).and_then(|text| text.parse::<usize>().ok());
+
+        if number.is_none() {
+            self.push_error(word, "expect a number");
+        };
+
+        number
+    }
+
+    fn expect_end(&mut self, words: &[Word]) {
+        if let Some(word) = words.first() {
+            self.push_error(Some(word), "unexpected word");
+        };
+    }
+
+    // the index written in front of a model, function or constant must be the next one
+    fn expect_index(&mut self, word: Option<&Word>, next_index: usize) -> bool {
+        match self.expect_number(word) {
+            Some(index) if index == next_index => true,
+            Some(_) => {
+                self.push_error(word, &format!("expect index {}", next_index));
+                false
+            },
+            None => false
+        }
+    }
+
+    // [name] [file index]
+    fn parse_name_and_file(&mut self, words: &[Word]) -> (Option<String>, usize) {
+        let mut name = None;
+        let mut file_index = 0;
+        let mut rest = words;
+
+        if let Some(text) = rest.first().and_then(Word::text) {
+            if text != "file" {
+                name = Some(text.to_string());
+                rest = &rest[1..];
+            };
+        };
+
+        if let Some("file") = rest.first().and_then(Word::text) {
+            file_index = self.expect_number(rest.get(1)).unwrap_or(0);
+            rest = if rest.len() > 2 { &rest[2..] } else { &[] };
+        };
+
+        self.expect_end(rest);
+
+        (name, file_index)
+    }
+
+    fn parse_constant(&mut self, words: &[Word]) -> Option<Object> {
+        let first = if let Some(first) = words.first() { first } else {
+            self.push_error(None, "expect constant value");
+            return None;
+        };
+
+        let (constant, rest) = match &first.value {
+            WordValue::String(string) => (Object::String(make_reference(string.clone())), &words[1..]),
+            WordValue::Text(text) => match text.as_str() {
+                "null" => (Object::Null, &words[1..]),
+                "true" => (Object::Boolean(true), &words[1..]),
+                "false" => (Object::Boolean(false), &words[1..]),
+                "function" => (Object::Function(self.expect_number(words.get(1))?), words.get(2..).unwrap_or(&[])),
+                "model" => (Object::Model(self.expect_number(words.get(1))?), words.get(2..).unwrap_or(&[])),
+                _ => if let Ok(integer) = text.parse::<i64>() {
+                    (Object::Integer(integer), &words[1..])
+                } else if let Ok(float) = text.parse::<f64>() {
+                    (Object::Float(float), &words[1..])
+                } else {
+                    self.push_error(Some(first), "unknown constant value");
+                    return None;
+                }
+            },
+            WordValue::Position(_) => {
+                self.push_error(Some(first), "unknown constant value");
+                return None;
+            }
+        };
+
+        self.expect_end(rest);
+
+        Some(constant)
+    }
+
+    fn assemble_constant(&mut self, words: &[Word]) {
+        let index = if let Some(index) = self.expect_number(words.first()) { index } else { return; };
+        let constant = if let Some(constant) = self.parse_constant(&words[1..]) { constant } else { return; };
+
+        if index < Program::DEFAULT_CONSTANTS.len() {
+            // null, true and false are always at the front
+            let expected = constant_to_string(&Program::DEFAULT_CONSTANTS[index]);
+
+            if constant_to_string(&constant) != expected {
+                self.push_error(words.get(1), &format!("constant {} must be {}", index, expected));
+            };
+        } else if self.expect_index(words.first(), self.constants.len()) {
+            self.constants.push(constant);
+        };
+    }
+
+    fn assemble_file(&mut self, words: &[Word]) {
+        if !self.expect_index(words.first(), self.filenames.len()) {
+            return;
+        };
+
+        match words.get(1).map(|word| &word.value) {
+            Some(WordValue::String(filename)) => {
+                self.filenames.push(filename.clone());
+                self.expect_end(&words[2..]);
+            },
+            _ => self.push_error(words.get(1), "expect quoted filename")
+        };
+    }
+
+    fn assemble_program_line(&mut self, keyword: &Word, words: &[Word]) {
+        match keyword.text().unwrap_or_default() {
+            "entry" => {
+                self.entry_point = self.expect_number(words.first()).unwrap_or(0);
+                self.expect_end(words.get(1..).unwrap_or(&[]));
+            },
+            "locals" => {
+                self.local_count = self.expect_number(words.first()).unwrap_or(0);
+                self.expect_end(words.get(1..).unwrap_or(&[]));
+            },
+            "local" => {
+                if let (Some(local_index), Some(constant_index)) = (self.expect_number(words.first()), self.expect_number(words.get(1))) {
+                    self.local_values.insert(local_index, constant_index);
+                    self.expect_end(&words[2..]);
+                };
+            },
+            _ => self.push_error(Some(keyword), "unknown directive")
+        };
+    }
+
+    fn assemble_model_line(&mut self, keyword: &Word, words: &[Word]) {
+        match keyword.text().unwrap_or_default() {
+            "property" => {
+                if let Some(name) = self.expect_text(words.first(), "expect property name") {
+                    if !self.models.last_mut().unwrap().model.add_property(name) {
+                        self.push_error(words.first(), "property already exists");
+                    };
+                    self.expect_end(&words[1..]);
+                };
+            },
+            "method" => {
+                let name = self.expect_text(words.first(), "expect method name").map(str::to_string);
+
+                if let (Some(name), Some(function_index)) = (name, self.expect_number(words.get(1))) {
+                    self.models.last_mut().unwrap().model.functions.insert(name, function_index);
+                    self.expect_end(&words[2..]);
+                };
+            },
+            _ => self.push_error(Some(keyword), "unknown directive in model")
+        };
+    }
+
+    fn parse_operand(&mut self, word: &Word, opcode: OpCode) -> Option<Operand> {
+        let text = self.expect_text(Some(word), "expect operand")?;
+
+        if let Ok(value) = text.parse::<u64>() {
+            if value > Instruction::MAX_OPERAND {
+                self.push_error(Some(word), "operand is too large");
+                return None;
+            };
+
+            return Some(Operand::Value(value));
+        };
+
+        if matches!(opcode, OpCode::Operation | OpCode::CompareJump) {
+            if let Some(&operation) = OPERATIONS.iter().find(|&&operation| operation_symbol(operation) == Some(text)) {
+                return Some(Operand::Value(operation as u64));
+            };
+        };
+
+        Some(Operand::Label(word.clone()))
+    }
+
+    // [index] [[line:column]] opcode [operand], the index written by disassembler is ignored
+    fn assemble_instruction(&mut self, words: &[Word]) {
+        let mut rest = words;
+
+        if rest.len() > 1 && rest[0].text().is_some_and(|text| text.parse::<usize>().is_ok()) {
+            rest = &rest[1..];
+        };
+
+        let position = if let Some(WordValue::Position(position)) = rest.first().map(|word| &word.value) {
+            rest = &rest[1..];
+            Some(*position)
+        } else {
+            None
+        };
+
+        let opcode = if let Some(opcode) = rest.first().and_then(Word::text).and_then(OpCode::from_name) { opcode } else {
+            self.push_error(rest.first(), "unknown instruction");
+            return;
+        };
+
+        let operand = match rest.get(1) {
+            Some(word) => if let Some(operand) = self.parse_operand(word, opcode) { operand } else { return; },
+            None => Operand::Value(0)
+        };
+
+        self.expect_end(rest.get(2..).unwrap_or(&[]));
+
+        let function = self.functions.last_mut().unwrap();
+        function.instructions.push((opcode, operand));
+        function.positions.push(position);
+    }
+
+    fn assemble_function_line(&mut self, keyword: &Word, words: &[Word]) {
+        let text = keyword.text().unwrap_or_default();
+
+        match text {
+            "parameters" => {
+                self.functions.last_mut().unwrap().parameter_count = self.expect_number(words.get(1)).unwrap_or(0);
+                self.expect_end(words.get(2..).unwrap_or(&[]));
+            },
+            "locals" => {
+                self.functions.last_mut().unwrap().local_count = self.expect_number(words.get(1)).unwrap_or(0);
+                self.expect_end(words.get(2..).unwrap_or(&[]));
+            },
+            "instance" => {
+                self.functions.last_mut().unwrap().is_instance = true;
+                self.expect_end(&words[1..]);
+            },
+            "upvalue" => {
+                let index = self.expect_number(words.get(2));
+
+                let upvalue = match (words.get(1).and_then(Word::text), index) {
+                    (Some("local"), Some(index)) => Upvalue::Local(index),
+                    (Some("upvalue"), Some(index)) => Upvalue::Upvalue(index),
+                    (_, Some(_)) => {
+                        self.push_error(words.get(1), "upvalue should be from local or upvalue");
+                        return;
+                    },
+                    _ => return
+                };
+
+                self.functions.last_mut().unwrap().upvalues.push(upvalue);
+                self.expect_end(words.get(3..).unwrap_or(&[]));
+            },
+            "handler" => {
+                let mut fields = Vec::new();
+
+                for word in words.iter().skip(1).take(3) {
+                    if let Some(operand) = self.parse_operand(word, OpCode::Jump) {
+                        fields.push(operand);
+                    };
+                };
+
+                let stack_size = self.expect_number(words.get(4));
+
+                if let (3, Some(stack_size)) = (fields.len(), stack_size) {
+                    let target = fields.pop().unwrap();
+                    let end = fields.pop().unwrap();
+                    let start = fields.pop().unwrap();
+
+                    self.functions.last_mut().unwrap().handlers.push((start, end, target, stack_size));
+                    self.expect_end(&words[5..]);
+                };
+            },
+            _ if text.len() > 1 && text.ends_with(':') => {
+                let function = self.functions.last_mut().unwrap();
+                let label = text[..text.len() - 1].to_string();
+
+                if function.labels.insert(label, function.instructions.len()).is_some() {
+                    self.push_error(Some(keyword), "label already exists");
+                };
+
+                // an instruction can follow the label in the same line
+                if words.len() > 1 {
+                    self.assemble_instruction(&words[1..]);
+                };
+            },
+            _ => self.assemble_instruction(words)
+        };
+    }
+
+    fn assemble_line(&mut self, words: &[Word]) {
+        let keyword = if let Some(keyword) = words.first() { keyword } else { return; };
+
+        match keyword.text().unwrap_or_default() {
+            "model" => {
+                if self.expect_index(words.get(1), self.models.len()) {
+                    let (name, file_index) = self.parse_name_and_file(&words[2..]);
+                    self.models.push(ModelBuilder { name, file_index, model: Model::new() });
+                    self.section = Section::Model;
+                };
+            },
+            "function" => {
+                if self.expect_index(words.get(1), self.functions.len()) {
+                    let (name, file_index) = self.parse_name_and_file(&words[2..]);
+                    self.functions.push(FunctionBuilder {
+                        name,
+                        file_index,
+                        parameter_count: 0,
+                        local_count: 0,
+                        is_instance: false,
+                        upvalues: Vec::new(),
+                        handlers: Vec::new(),
+                        instructions: Vec::new(),
+                        positions: Vec::new(),
+                        labels: HashMap::new()
+                    });
+                    self.section = Section::Function;
+                };
+            },
+            "constant" => self.assemble_constant(&words[1..]),
+            "file" => self.assemble_file(&words[1..]),
+            "definition" => {
+                let name = self.expect_text(words.get(1), "expect definition name").map(str::to_string);
+
+                if let (Some(name), Some(constant_index)) = (name, self.expect_number(words.get(2))) {
+                    self.definitions.insert(name, constant_index);
+                    self.expect_end(&words[3..]);
+                };
+            },
+            "global" => {
+                if let Some(constant_index) = self.expect_number(words.get(1)) {
+                    self.global_dependencies.push(constant_index);
+                    self.expect_end(&words[2..]);
+                };
+            },
+            _ => match self.section {
+                Section::Program => self.assemble_program_line(keyword, &words[1..]),
+                Section::Model => self.assemble_model_line(keyword, &words[1..]),
+                Section::Function => self.assemble_function_line(keyword, words)
+            }
+        };
+    }
+
+    fn resolve_operand(&mut self, operand: &Operand, labels: &HashMap<String, usize>) -> u64 {
+        match operand {
+            Operand::Value(value) => *value,
+            Operand::Label(word) => {
+                if let Some(&index) = word.text().and_then(|label| labels.get(label)) {
+                    index as u64
+                } else {
+                    self.push_error(Some(word), "label not found");
+                    0
+                }
+            }
+        }
+    }
+
+    fn build_function(&mut self, builder: &FunctionBuilder) -> Function {
+        let mut instructions = Vec::new();
+
+        for (opcode, operand) in builder.instructions.iter() {
+            let operand = self.resolve_operand(operand, &builder.labels);
+            instructions.push(opcode.to_instruction(operand));
+
+            // scripts check global variables by the names used
+            if matches!(opcode, OpCode::GlobalGet | OpCode::GlobalSet) && !self.global_dependencies.contains(&(operand as usize)) {
+                self.global_dependencies.push(operand as usize);
+            };
+        };
+
+        let mut handlers = Vec::new();
+
+        for (start, end, target, stack_size) in builder.handlers.iter() {
+            handlers.push(Handler {
+                start: self.resolve_operand(start, &builder.labels) as usize,
+                end: self.resolve_operand(end, &builder.labels) as usize,
+                target: self.resolve_operand(target, &builder.labels) as usize,
+                stack_size: *stack_size
+            });
+        };
+
+        Function {
+            parameter_count: builder.parameter_count,
+            local_count: builder.local_count,
+            is_instance: builder.is_instance,
+            handlers,
+            upvalues: builder.upvalues.clone(),
+            instructions
+        }
+    }
+
+    fn build_program(&mut self) -> Program {
+        let function_builders = std::mem::take(&mut self.functions);
+        let functions: Vec<Function> = function_builders.iter().map(|builder| self.build_function(builder)).collect();
+
+        // debug information is kept only when positions are written
+        let debug_info = if function_builders.iter().any(|builder| builder.positions.iter().any(Option::is_some)) {
+            Some(DebugInfo {
+                functions: function_builders.iter().map(|builder| builder.positions.iter().map(|position| position.unwrap_or_else(Position::none)).collect::<Positions>()).collect()
+            })
+        } else {
+            None
+        };
+
+        let has_names = function_builders.iter().any(|builder| builder.name.is_some()) || self.models.iter().any(|builder| builder.name.is_some());
+
+        let file_info = if has_names || !self.filenames.is_empty() {
+            let mut filenames = self.filenames.clone();
+            if filenames.is_empty() {
+                filenames.push(self.filename.clone());
+            };
+
+            Some(FileInfo {
+                filenames,
+                model_files: self.models.iter().map(|builder| builder.file_index).collect(),
+                model_names: self.models.iter().map(|builder| builder.name.clone().unwrap_or_else(|| "<anonymous>".to_string())).collect(),
+                function_files: function_builders.iter().map(|builder| builder.file_index).collect(),
+                function_names: function_builders.iter().map(|builder| builder.name.clone().unwrap_or_else(|| "<anonymous>".to_string())).collect()
+            })
+        } else {
+            None
+        };
+
+        Program {
+            models: self.models.iter().map(|builder| builder.model.clone()).collect(),
+            functions,
+            constants: self.constants.clone(),
+            global_dependencies: self.global_dependencies.clone(),
+
+            local_count: self.local_count,
+            local_values: self.local_values.clone(),
+
+            entry_point: self.entry_point,
+
+            definitions: self.definitions.clone(),
+
+            file_info,
+            debug_info
+        }
+    }
+}
+
+// build a program from the text written by disassembler or by hand, the program is verified so it is safe to run
+pub fn assemble(source: &str, filename: &str) -> Result<Program, CompileErrorList> {
+    let mut assembler = Assembler::new(filename);
+
+    for (line_index, line) in source.lines().enumerate() {
+        assembler.line = line_index as u16 + 1;
+
+        let words = split_line(line, assembler.line, &mut assembler.errors);
+        assembler.assemble_line(&words);
+    };
+
+    let program = assembler.build_program();
+
+    if assembler.errors.is_empty() {
+        if let Err(error) = program.verify() {
+            assembler.errors.push_error(&Token::none(), &error.to_string());
+        };
+    };
+
+    if assembler.errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(assembler.errors)
+    }
+}
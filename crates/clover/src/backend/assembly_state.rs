@@ -6,7 +6,10 @@ pub struct AssemblyState {
     pub index: usize,
 
     // key is public name, value is constant index
-    pub public_indices: HashMap<String, usize>
+    pub public_indices: HashMap<String, usize>,
+
+    // top level functions and models, key is name, value is constant index
+    pub definition_indices: HashMap<String, usize>
 }
 
 impl AssemblyState {
@@ -14,7 +17,8 @@ impl AssemblyState {
         AssemblyState {
             filename: filename.to_string(),
             index: 0,
-            public_indices: HashMap::new()
+            public_indices: HashMap::new(),
+            definition_indices: HashMap::new()
         }
     }
 }
\ No newline at end of file
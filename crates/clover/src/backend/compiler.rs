@@ -1,11 +1,14 @@
 use std::collections::{HashMap, HashSet};
+use indexmap::IndexSet;
 use std::fs::{read_to_string, File};
 
 use crate::backend::dependency_solver::DependencySolver;
-use crate::backend::function_state::{Scope, FunctionState};
-use crate::frontend::parser::parse;
+use crate::backend::peephole;
+use crate::backend::constant_folding::{fold_expression, fold_infix_expression, fold_prefix_expression};
+use crate::backend::function_state::{Scope, FunctionState, FunctionStateMark, TryState};
+use crate::frontend::parser::{parse, parse_interactive};
 use crate::intermediate::{CompileErrorList, Position, Token, TokenValue};
-use crate::intermediate::ast::{Definition, Document, IncludeDefinition, ModelDefinition, FunctionDefinition, ImplementDefinition, ApplyDefinition, Statement, Expression, IntegerExpression, FloatExpression, StringExpression, BooleanExpression, IdentifierExpression, InfixExpression, CallExpression, InstanceGetExpression, ThisExpression, PrefixExpression, IfExpression, ArrayExpression, IndexGetExpression, ForStatement, LocalDefinition};
+use crate::intermediate::ast::{Definition, Document, InteractiveItem, LocalStatement, IncludeDefinition, ModelDefinition, FunctionDefinition, ImplementDefinition, ApplyDefinition, Statement, Expression, IntegerExpression, FloatExpression, StringExpression, BooleanExpression, IdentifierExpression, InfixExpression, CallExpression, InstanceGetExpression, ThisExpression, PrefixExpression, IfExpression, ArrayExpression, IndexGetExpression, ForStatement, WhileStatement, TryStatement, ReturnStatement, BreakStatement, LocalDefinition, FunctionExpression, MapExpression};
 use crate::runtime::object::{Object, make_reference};
 use crate::runtime::opcode::{OpCode, Instruction};
 use crate::runtime::program::{Program, Model, Function};
@@ -15,7 +18,14 @@ use crate::runtime::opcode::{OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, O
 use std::ops::Deref;
 use std::io::{Read, Write, BufReader, BufWriter};
 
-#[derive(Debug)]
+// sizes of what compiling statements adds to context
+struct CompilerContextMark {
+    functions: usize,
+    constants: usize,
+    global_dependencies: usize
+}
+
+#[derive(Debug, Clone)]
 pub struct CompilerContext {
     models: Vec<Model>,
     functions: Vec<Function>,
@@ -24,7 +34,8 @@ pub struct CompilerContext {
     integer_constants_indices: HashMap<i64, usize>,
     string_constants_indices: HashMap<String, usize>,
 
-    global_dependencies: HashSet<usize>,
+    // in order of adding, so a mark can take them back
+    global_dependencies: IndexSet<usize>,
 
     local_count: usize,
     assemblies: HashMap<String, AssemblyState>,
@@ -32,6 +43,9 @@ pub struct CompilerContext {
 
     entry_point: usize,
 
+    // top level functions and models of the compiled files, not include the files they include
+    definitions: HashMap<String, usize>,
+
     file_info: FileInfo,
     debug_info: DebugInfo
 }
@@ -46,7 +60,7 @@ impl CompilerContext {
             integer_constants_indices: HashMap::new(),
             string_constants_indices: HashMap::new(),
 
-            global_dependencies: HashSet::new(),
+            global_dependencies: IndexSet::new(),
 
             local_count: 0,
             assemblies: HashMap::new(),
@@ -54,6 +68,8 @@ impl CompilerContext {
 
             entry_point: 0,
 
+            definitions: HashMap::new(),
+
             file_info: FileInfo::new(),
             debug_info: DebugInfo::new()
         }
@@ -96,28 +112,56 @@ impl CompilerContext {
 
         let &constant_index = self.local_values.get(&local_index).unwrap();
 
-        if let Some(object) = self.constants.get(constant_index) {
-            Some(object.clone())
-        } else {
-            None
+        self.constants.get(constant_index).cloned()
+    }
+
+    fn mark(&self) -> CompilerContextMark {
+        CompilerContextMark {
+            functions: self.functions.len(),
+            constants: self.constants.len(),
+            global_dependencies: self.global_dependencies.len()
         }
     }
 
+    // statements only add functions, constants and globals to context
+    fn rollback(&mut self, mark: CompilerContextMark) {
+        for constant in self.constants.drain(mark.constants..) {
+            match constant {
+                Object::Integer(value) => { self.integer_constants_indices.remove(&value); },
+                Object::String(value) => { self.string_constants_indices.remove(value.borrow().deref()); },
+                _ => {}
+            };
+        };
+
+        self.functions.truncate(mark.functions);
+        self.debug_info.functions.truncate(mark.functions);
+        self.file_info.function_names.truncate(mark.functions);
+        self.file_info.function_files.truncate(mark.functions);
+        self.global_dependencies.truncate(mark.global_dependencies);
+    }
+
     fn add_model(&mut self, model: Model) -> usize {
         let index = self.models.len();
         self.models.push(model);
         index
     }
 
-    fn add_function(&mut self, function_state: FunctionState, name: &str, assembly_index: usize) -> usize {
+    fn add_function(&mut self, mut function_state: FunctionState, name: &str, assembly_index: usize) -> usize {
         let index = self.functions.len();
 
+        function_state.box_captured_locals();
+        function_state.resolve_handler_stack_sizes();
+        peephole::optimize(&mut function_state);
+
         let function = Function {
             parameter_count: function_state.parameter_count,
             local_count: function_state.local_count,
-            rescue_position: function_state.rescue_position,
             is_instance: function_state.is_instance,
 
+            handlers: function_state.handlers,
+
+            upvalues: function_state.upvalues,
+
             instructions: function_state.instructions
         };
 
@@ -167,6 +211,8 @@ impl CompilerContext {
 
             entry_point: self.entry_point,
 
+            definitions: self.definitions.clone(),
+
             file_info: Some(self.file_info.clone()),
             debug_info: Some(self.debug_info.clone())
         }
@@ -177,7 +223,11 @@ impl CompilerContext {
 pub struct CompilerState {
     pub assembly_state: AssemblyState,
     pub locals: Scope,
-    pub errors: CompileErrorList
+    // function states of the functions which enclose the compiling anonymous function
+    pub enclosing_function_states: Vec<FunctionState>,
+    pub errors: CompileErrorList,
+    // interactive mode can define a name again, the new definition replaces the old one
+    pub redefinable: bool
 }
 
 pub trait Storage {
@@ -231,8 +281,8 @@ impl DefaultStorage {
 
 impl CompilerState {
     fn define_local(&mut self, context: &mut CompilerContext, name: &str) -> Option<usize> {
-        if self.locals.contains_key(name) {
-            None
+        if let Some(&index) = self.locals.get(name) {
+            if self.redefinable { Some(index) } else { None }
         } else {
             let index = context.local_count;
             self.locals.insert(name.to_string(), index);
@@ -279,11 +329,25 @@ impl CompilerState {
         }
     }
 
+    // push the value of a folded expression
+    fn compile_constant(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, constant: Object, position: Position) {
+        let index = match constant {
+            Object::Null => Program::NULL_CONSTANT_INDEX,
+            Object::Boolean(true) => Program::TRUE_CONSTANT_INDEX,
+            Object::Boolean(false) => Program::FALSE_CONSTANT_INDEX,
+            _ => context.add_constant(constant)
+        };
+
+        function_state.emit(OpCode::PushConstant.to_instruction(index as u64), position);
+    }
+
     fn compile_identifier_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, identifier_expression: &IdentifierExpression) {
         let identifier = identifier_expression.token.value.to_string();
 
         if let Some(index) = function_state.find_local(&identifier) {
             function_state.emit(OpCode::LocalGet.to_instruction(index as u64), identifier_expression.token.position);
+        } else if let Some(index) = function_state.resolve_upvalue(&mut self.enclosing_function_states, &identifier) {
+            function_state.emit(OpCode::UpvalueGet.to_instruction(index as u64), identifier_expression.token.position);
         } else if let Some(&index) = self.locals.get(&identifier) {
             function_state.emit(OpCode::ContextGet.to_instruction(index as u64), identifier_expression.token.position);
         } else {
@@ -294,7 +358,7 @@ impl CompilerState {
     }
 
     fn compile_this_expression(&mut self, _context: &mut CompilerContext, function_state: &mut FunctionState, this_expression: &ThisExpression) {
-        function_state.emit(OpCode::LocalGet.to_instruction(0 as u64), this_expression.token.position);
+        function_state.emit(OpCode::LocalGet.to_instruction(0), this_expression.token.position);
     }
 
     fn compile_assign_expression_left_part(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, infix_expression: &InfixExpression) {
@@ -306,6 +370,8 @@ impl CompilerState {
 
                 if let Some(index) = function_state.find_local(&identifier) {
                     function_state.emit(OpCode::LocalSet.to_instruction(index as u64), infix_expression.infix.position);
+                } else if let Some(index) = function_state.resolve_upvalue(&mut self.enclosing_function_states, &identifier) {
+                    function_state.emit(OpCode::UpvalueSet.to_instruction(index as u64), infix_expression.infix.position);
                 } else if let Some(&index) = self.locals.get(&identifier) {
                     function_state.emit(OpCode::ContextSet.to_instruction(index as u64), infix_expression.infix.position);
                 } else {
@@ -340,6 +406,10 @@ impl CompilerState {
             return self.compile_assign_expression(context, function_state, infix_expression);
         };
 
+        if let Some(constant) = fold_infix_expression(infix_expression) {
+            return self.compile_constant(context, function_state, constant, infix_expression.infix.position);
+        };
+
         if let Some(instruction) = get_operation_instruction_by_token(&infix_expression.infix) {
             self.compile_expression(context, function_state, infix_expression.left.deref());
             self.compile_expression(context, function_state, infix_expression.right.deref());
@@ -362,6 +432,10 @@ impl CompilerState {
     }
 
     fn compile_prefix_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, prefix_expression: &PrefixExpression) {
+        if let Some(constant) = fold_prefix_expression(prefix_expression) {
+            return self.compile_constant(context, function_state, constant, prefix_expression.prefix.position);
+        };
+
         self.compile_expression(context, function_state, prefix_expression.right.deref());
 
         match prefix_expression.prefix.value {
@@ -390,6 +464,28 @@ impl CompilerState {
         function_state.emit(OpCode::Array.to_instruction(array_expression.values.len() as u64), array_expression.token.position);
     }
 
+    fn compile_map_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, map_expression: &MapExpression) {
+        let map_local_index = function_state.define_anonymous_local();
+
+        function_state.emit_opcode(OpCode::PushNewMap, map_expression.token.position);
+        function_state.emit(OpCode::LocalInit.to_instruction(map_local_index as u64), map_expression.token.position);
+
+        // the key is evaluated before the value, IndexSet needs the value under the key, so the key waits in a local
+        let key_local_index = function_state.define_anonymous_local();
+
+        for (key, value) in map_expression.keys.iter().zip(map_expression.values.iter()) {
+            self.compile_expression(context, function_state, key);
+            function_state.emit(OpCode::LocalInit.to_instruction(key_local_index as u64), map_expression.token.position);
+            self.compile_expression(context, function_state, value);
+            function_state.emit(OpCode::LocalGet.to_instruction(map_local_index as u64), map_expression.token.position);
+            function_state.emit(OpCode::LocalGet.to_instruction(key_local_index as u64), map_expression.token.position);
+            function_state.emit_opcode_without_position(OpCode::IndexSet);
+            function_state.emit_opcode_without_position(OpCode::Pop);
+        };
+
+        function_state.emit(OpCode::LocalGet.to_instruction(map_local_index as u64), map_expression.token.position);
+    }
+
     fn compile_instance_get_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, instance_get_expression: &InstanceGetExpression) {
         self.compile_expression(context, function_state, instance_get_expression.instance.deref());
         self.compile_expression(context, function_state, instance_get_expression.index.deref());
@@ -404,7 +500,57 @@ impl CompilerState {
         function_state.emit_opcode(OpCode::IndexGet, index_get_expression.token.position);
     }
 
+    // the condition is known, only the branch which runs is kept
+    fn compile_constant_if_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, if_expression: &IfExpression, condition: bool) {
+        let (taken_part, dropped_part) = if condition {
+            (Some(&if_expression.true_part), if_expression.false_part.as_ref())
+        } else {
+            (if_expression.false_part.as_ref(), Some(&if_expression.true_part))
+        };
+
+        // the dropped branch is still compiled so its errors are reported, then everything it adds is taken back,
+        // its code, functions, constants and upvalues of enclosing functions do not go to the program
+        if let Some(statements) = dropped_part {
+            let context_mark = context.mark();
+            let function_mark = function_state.mark();
+            let enclosing_marks: Vec<FunctionStateMark> = self.enclosing_function_states.iter().map(FunctionState::mark).collect();
+
+            function_state.enter_scope();
+            for statement in statements {
+                self.compile_statement(context, function_state, statement);
+            }
+            function_state.exit_scope();
+
+            context.rollback(context_mark);
+            function_state.rollback(function_mark);
+            for (enclosing_function_state, mark) in self.enclosing_function_states.iter_mut().zip(enclosing_marks) {
+                enclosing_function_state.rollback(mark);
+            };
+        };
+
+        let start_index = function_state.get_next_instruction_index();
+
+        if let Some(statements) = taken_part {
+            function_state.enter_scope();
+            for statement in statements {
+                self.compile_statement(context, function_state, statement);
+            }
+            function_state.exit_scope();
+        };
+
+        // an empty branch must not remove the pop of the statement before
+        if function_state.get_next_instruction_index() == start_index {
+            function_state.emit(OpCode::PushConstant.to_instruction(Program::NULL_CONSTANT_INDEX as u64), function_state.get_last_position());
+        } else {
+            function_state.remove_pop_or_push_null();
+        };
+    }
+
     fn compile_if_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, if_expression: &IfExpression) {
+        if let Some(condition) = fold_expression(&if_expression.condition) {
+            return self.compile_constant_if_expression(context, function_state, if_expression, condition.to_bool());
+        };
+
         self.compile_expression(context, function_state, if_expression.condition.deref());
 
         let true_part_instruction_index = function_state.emit_opcode_without_position(OpCode::JumpIf);
@@ -437,6 +583,28 @@ impl CompilerState {
 
     }
 
+    fn compile_function_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, function_expression: &FunctionExpression) {
+        // make current function as the enclosing function when compiling the anonymous function, so it can find the upvalues
+        self.enclosing_function_states.push(std::mem::replace(function_state, FunctionState::new()));
+        let anonymous_function_state = self.compile_function_body(context, &function_expression.parameters, &function_expression.body);
+        *function_state = self.enclosing_function_states.pop().unwrap();
+
+        if anonymous_function_state.is_instance {
+            self.errors.push_error(&function_expression.token, "instance function can inside implement block only");
+            return;
+        };
+
+        let has_upvalues = !anonymous_function_state.upvalues.is_empty();
+        let function_index = context.add_function(anonymous_function_state, "<anonymous>", self.assembly_state.index);
+
+        if has_upvalues {
+            function_state.emit(OpCode::Closure.to_instruction(function_index as u64), function_expression.token.position);
+        } else {
+            let constant_index = context.add_constant(Object::Function(function_index));
+            function_state.emit(OpCode::PushConstant.to_instruction(constant_index as u64), function_expression.token.position);
+        };
+    }
+
     fn compile_expression(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, expression: &Expression) {
         match expression {
             Expression::Integer(integer_expression) => self.compile_integer_expression(context, function_state, integer_expression),
@@ -445,6 +613,7 @@ impl CompilerState {
             Expression::Boolean(bool_expression) => self.compile_boolean_expression(context, function_state, bool_expression),
             Expression::Null(null_expression) => { function_state.emit(OpCode::PushConstant.to_instruction(Program::NULL_CONSTANT_INDEX as u64), null_expression.token.position); },
             Expression::Array(array_expression) => self.compile_array_expression(context, function_state, array_expression),
+            Expression::Map(map_expression) => self.compile_map_expression(context, function_state, map_expression),
             Expression::Identifier(identifier_expression) => self.compile_identifier_expression(context, function_state, identifier_expression),
             Expression::Prefix(prefix_expression) => self.compile_prefix_expression(context, function_state, prefix_expression),
             Expression::Infix(infix_expression) => self.compile_infix_expression(context, function_state, infix_expression),
@@ -452,7 +621,8 @@ impl CompilerState {
             Expression::InstanceGet(instance_get_expression) => self.compile_instance_get_expression(context, function_state, instance_get_expression),
             Expression::IndexGet(index_get_expression) => self.compile_index_get_expression(context, function_state, index_get_expression),
             Expression::This(this_expression) => self.compile_this_expression(context, function_state, this_expression),
-            Expression::If(if_expression) => self.compile_if_expression(context, function_state, if_expression)
+            Expression::If(if_expression) => self.compile_if_expression(context, function_state, if_expression),
+            Expression::Function(function_expression) => self.compile_function_expression(context, function_state, function_expression)
         }
     }
 
@@ -483,9 +653,8 @@ impl CompilerState {
         let jump_to_end_if_true_instruction_index = function_state.get_next_instruction_index();
         function_state.emit_opcode_without_position(OpCode::JumpIf);
 
-        // set the iterator to local
-        function_state.emit(OpCode::LocalSet.to_instruction(local_variable_index as u64), for_statement.identifier.position);
-        function_state.emit_opcode_without_position(OpCode::Pop);
+        // set the iterator to local, init it every time so closures can capture the value of each loop
+        function_state.emit(OpCode::LocalInit.to_instruction(local_variable_index as u64), for_statement.identifier.position);
 
         for statement in &for_statement.statements {
             self.compile_statement(context, function_state, statement);
@@ -502,11 +671,173 @@ impl CompilerState {
 
     }
 
+    fn compile_while_statement(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, while_statement: &WhileStatement) {
+        function_state.enter_scope();
+        function_state.enter_break_scope();
+
+        let start_loop_position = function_state.get_next_instruction_index();
+
+        // jump to end when condition is false
+        self.compile_expression(context, function_state, &while_statement.condition);
+        function_state.emit_opcode(OpCode::Not, while_statement.token.position);
+
+        let jump_to_end_if_true_instruction_index = function_state.get_next_instruction_index();
+        function_state.emit_opcode_without_position(OpCode::JumpIf);
+
+        for statement in &while_statement.statements {
+            self.compile_statement(context, function_state, statement);
+        };
+        function_state.emit(OpCode::Jump.to_instruction(start_loop_position as u64), function_state.get_last_position());
+
+        let end_position = function_state.get_next_instruction_index();
+
+        function_state.replace_instruction(jump_to_end_if_true_instruction_index, OpCode::JumpIf.to_instruction(end_position as u64));
+
+        function_state.exit_break_scope();
+        function_state.exit_scope();
+    }
+
+    fn compile_block(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, statements: &[Statement]) {
+        function_state.enter_scope();
+        for statement in statements {
+            self.compile_statement(context, function_state, statement);
+        };
+        function_state.exit_scope();
+    }
+
+    // run the ensure blocks of try blocks which are jumping out by return or break, from inner to outer
+    fn compile_leave_try_blocks(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, remain_count: usize) {
+        let mut leaving_try_states = Vec::new();
+
+        while function_state.try_states.len() > remain_count {
+            let mut try_state = function_state.try_states.pop().unwrap();
+
+            // the ensure block should not be protected by its own handlers
+            if let Some(handler_state) = try_state.rescue_handler.as_mut() {
+                function_state.close_handler_range(handler_state);
+            };
+            if let Some(handler_state) = try_state.ensure_handler.as_mut() {
+                function_state.close_handler_range(handler_state);
+            };
+
+            let ensure_statements = try_state.ensure_statements.clone();
+            self.compile_block(context, function_state, &ensure_statements);
+
+            leaving_try_states.push(try_state);
+        };
+
+        for mut try_state in leaving_try_states.into_iter().rev() {
+            if let Some(handler_state) = try_state.rescue_handler.as_mut() {
+                function_state.reopen_handler_range(handler_state);
+            };
+            if let Some(handler_state) = try_state.ensure_handler.as_mut() {
+                function_state.reopen_handler_range(handler_state);
+            };
+
+            function_state.try_states.push(try_state);
+        };
+    }
+
+    fn compile_return_statement(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, return_statement: &ReturnStatement) {
+        if function_state.try_states.iter().all(|try_state| try_state.ensure_handler.is_none()) {
+            function_state.emit_return(return_statement.token.position);
+            return;
+        };
+
+        // keep the return value on the stack when running ensure blocks
+        function_state.remove_pop_or_push_null();
+        self.compile_leave_try_blocks(context, function_state, 0);
+        function_state.emit_opcode(OpCode::Return, return_statement.token.position);
+    }
+
+    fn compile_break_statement(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, break_statement: &BreakStatement) {
+        if function_state.break_scopes.is_empty() {
+            return;
+        };
+
+        let break_scope_count = function_state.break_scopes.len();
+        let remain_count = function_state.try_states.iter().take_while(|try_state| try_state.break_scope_count < break_scope_count).count();
+
+        self.compile_leave_try_blocks(context, function_state, remain_count);
+        function_state.emit_break(break_statement.token.position);
+    }
+
+    fn compile_try_statement(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, try_statement: &TryStatement) {
+        let handler_state = function_state.begin_handler();
+
+        function_state.try_states.push(TryState {
+            rescue_handler: try_statement.rescue.as_ref().map(|_| handler_state.clone()),
+            ensure_handler: try_statement.ensure.as_ref().map(|_| handler_state.clone()),
+            ensure_statements: try_statement.ensure.clone().unwrap_or_default(),
+            break_scope_count: function_state.break_scopes.len()
+        });
+
+        // the value of try block is the value of last statement in try block or rescue block
+        self.compile_block(context, function_state, &try_statement.statements);
+        function_state.remove_pop_or_push_null();
+
+        let rescue_handler = function_state.try_states.last_mut().unwrap().rescue_handler.take();
+
+        if let (Some(rescue), Some(mut handler_state)) = (&try_statement.rescue, rescue_handler) {
+            function_state.close_handler_range(&mut handler_state);
+
+            let jump_to_ensure_instruction_index = function_state.emit_opcode_without_position(OpCode::Jump);
+
+            function_state.set_handler_target(&handler_state, function_state.get_next_instruction_index());
+
+            function_state.enter_scope();
+
+            // error object is on the top of stack when jump to rescue block
+            if let Some(identifier) = &rescue.identifier {
+                if let Some(index) = function_state.define_local(&identifier.value.to_string()) {
+                    function_state.emit(OpCode::LocalInit.to_instruction(index as u64), identifier.position);
+                } else {
+                    self.errors.push_error(identifier, "variable already exists");
+                };
+            } else {
+                function_state.emit_opcode(OpCode::Pop, rescue.token.position);
+            };
+
+            for statement in &rescue.statements {
+                self.compile_statement(context, function_state, statement);
+            };
+            function_state.exit_scope();
+            function_state.remove_pop_or_push_null();
+
+            function_state.replace_instruction(jump_to_ensure_instruction_index, OpCode::Jump.to_instruction(function_state.get_next_instruction_index() as u64));
+        };
+
+        let try_state = function_state.try_states.pop().unwrap();
+
+        if let Some(mut handler_state) = try_state.ensure_handler {
+            function_state.close_handler_range(&mut handler_state);
+
+            // finish without error
+            self.compile_block(context, function_state, &try_state.ensure_statements);
+            let jump_to_end_instruction_index = function_state.emit_opcode_without_position(OpCode::Jump);
+
+            // finish with error, raise the error again after ensure block
+            function_state.set_handler_target(&handler_state, function_state.get_next_instruction_index());
+
+            let error_local_index = function_state.define_anonymous_local();
+            function_state.emit(OpCode::LocalInit.to_instruction(error_local_index as u64), try_statement.token.position);
+
+            self.compile_block(context, function_state, &try_state.ensure_statements);
+
+            function_state.emit(OpCode::LocalGet.to_instruction(error_local_index as u64), function_state.get_last_position());
+            function_state.emit_opcode_without_position(OpCode::Raise);
+
+            function_state.replace_instruction(jump_to_end_instruction_index, OpCode::Jump.to_instruction(function_state.get_next_instruction_index() as u64));
+        };
+
+        function_state.emit_opcode_without_position(OpCode::Pop);
+    }
+
     fn compile_statement(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, statement: &Statement) {
         function_state.depth += 1;
 
         match statement {
-            Statement::Return(return_statement) => function_state.emit_return(return_statement.token.position),
+            Statement::Return(return_statement) => self.compile_return_statement(context, function_state, return_statement),
             Statement::Expression(expression) => {
                 self.compile_expression(context, function_state, expression);
                 function_state.emit_opcode_without_position(OpCode::Pop);
@@ -523,20 +854,56 @@ impl CompilerState {
                     };
                 }
             },
-            Statement::Break(break_statement) => function_state.emit_break(break_statement.token.position),
+            Statement::Break(break_statement) => self.compile_break_statement(context, function_state, break_statement),
             Statement::Rescue(rescue_statement) => {
                 if function_state.depth > 1 {
                     self.errors.push_error(&rescue_statement.token, "rescue can only in the layer of function");
                 } else {
                     function_state.emit_return(rescue_statement.token.position);
-                    function_state.rescue_position = function_state.get_next_instruction_index();
+
+                    // rescue the whole function before rescue statement
+                    let rescue_position = function_state.get_next_instruction_index();
+                    function_state.add_handler(0, rescue_position, rescue_position, 0);
+
+                    // error object is on the top of stack when jump to rescue position
+                    if let Some(identifier) = &rescue_statement.identifier {
+                        if let Some(index) = function_state.define_local(&identifier.value.to_string()) {
+                            function_state.emit(OpCode::LocalInit.to_instruction(index as u64), identifier.position);
+                        } else {
+                            self.errors.push_error(identifier, "variable already exists");
+                        };
+                    } else {
+                        function_state.emit_opcode(OpCode::Pop, rescue_statement.token.position);
+                    };
                 }
             },
-            Statement::For(for_statement) => self.compile_for_statement(context, function_state, for_statement)
+            Statement::Raise(raise_statement) => {
+                self.compile_expression(context, function_state, &raise_statement.value);
+                function_state.emit_opcode(OpCode::Raise, raise_statement.token.position);
+            },
+            Statement::For(for_statement) => self.compile_for_statement(context, function_state, for_statement),
+            Statement::While(while_statement) => self.compile_while_statement(context, function_state, while_statement),
+            Statement::Try(try_statement) => self.compile_try_statement(context, function_state, try_statement)
         }
         function_state.depth -= 1;
     }
 
+    // top level locals of interactive mode are kept in context, so the later inputs can use them
+    fn compile_context_local_statement(&mut self, context: &mut CompilerContext, function_state: &mut FunctionState, local_statement: &LocalStatement) {
+        for (token, value) in local_statement.variables.iter().zip(local_statement.values.iter()) {
+            if let Some(index) = self.define_local_by_identifier(context, token) {
+                if let Some(expression) = value {
+                    self.compile_expression(context, function_state, expression);
+                } else {
+                    function_state.emit(OpCode::PushConstant.to_instruction(Program::NULL_CONSTANT_INDEX as u64), token.position);
+                };
+
+                function_state.emit(OpCode::ContextSet.to_instruction(index as u64), token.position);
+                function_state.emit_opcode_without_position(OpCode::Pop);
+            };
+        };
+    }
+
     fn compile_include_definition(&mut self, context: &mut CompilerContext, include_definition: &IncludeDefinition) {
         for (i, alias) in include_definition.aliases.iter().enumerate() {
             if let Some(index) = self.define_local_by_identifier(context, alias) {
@@ -600,6 +967,8 @@ impl CompilerState {
         context.file_info.model_files.push(self.assembly_state.index);
         context.file_info.model_names.push(model_definition.name.value.to_string());
 
+        self.assembly_state.definition_indices.insert(model_definition.name.value.to_string(), constant_index);
+
         constant_index
     }
 
@@ -609,10 +978,10 @@ impl CompilerState {
         self.assembly_state.public_indices.insert(model_definition.name.value.to_string(), constant_index);
     }
 
-    fn compile_function_definition_base(&mut self, context: &mut CompilerContext, function_definition: &FunctionDefinition) -> FunctionState {
+    fn compile_function_body(&mut self, context: &mut CompilerContext, parameters: &[Token], body: &[Statement]) -> FunctionState {
         let mut function_state = FunctionState::new();
 
-        for parameter in function_definition.parameters.iter() {
+        for parameter in parameters.iter() {
             if TokenValue::This == parameter.value {
                 function_state.is_instance = true;
             };
@@ -622,9 +991,9 @@ impl CompilerState {
             };
         };
 
-        function_state.parameter_count = function_definition.parameters.len();
+        function_state.parameter_count = parameters.len();
 
-        for statement in function_definition.body.iter() {
+        for statement in body.iter() {
             self.compile_statement(context, &mut function_state, statement);
         };
 
@@ -638,7 +1007,7 @@ impl CompilerState {
         // define function before compile function body, so we can do recursive call
         let local_index = self.define_local_by_identifier(context, &function_definition.name);
 
-        let function_state = self.compile_function_definition_base(context, function_definition);
+        let function_state = self.compile_function_body(context, &function_definition.parameters, &function_definition.body);
 
         // can not have instance function here
         if function_state.is_instance {
@@ -656,6 +1025,8 @@ impl CompilerState {
                 context.entry_point = function_index;
             };
 
+            self.assembly_state.definition_indices.insert(function_definition.name.value.to_string(), constant_index);
+
             constant_index
         }
     }
@@ -684,7 +1055,7 @@ impl CompilerState {
         let mut functions: HashMap<String, usize> = HashMap::new();
 
         for function_definition in implement_definition.functions.iter() {
-            let function_state = self.compile_function_definition_base(context, function_definition);
+            let function_state = self.compile_function_body(context, &function_definition.parameters, &function_definition.body);
             let index = context.add_function(function_state, &function_definition.name.value.to_string(), self.assembly_state.index);
 
             functions.insert(function_definition.name.value.to_string(), index);
@@ -744,7 +1115,9 @@ pub fn compile_document(document: &Document, context: &mut CompilerContext) -> R
     let mut state = CompilerState {
         assembly_state: AssemblyState::new(&document.filename),
         locals: Scope::new(),
-        errors: CompileErrorList::new(&document.filename)
+        enclosing_function_states: Vec::new(),
+        errors: CompileErrorList::new(&document.filename),
+        redefinable: false
     };
 
     state.assembly_state.index = context.assemblies.len();
@@ -761,12 +1134,13 @@ pub fn compile_document(document: &Document, context: &mut CompilerContext) -> R
     }
 }
 
-pub fn compile_to(context: &mut CompilerContext, source: &str, filename: &str, file_loader: &dyn Storage) -> Result<(), CompileErrorList> {
+// compile the document and the files it includes which are not loaded yet
+fn compile_with_dependencies(context: &mut CompilerContext, document: Document, file_loader: &dyn Storage) -> Result<(), CompileErrorList> {
     let mut documents: HashMap<String, Document> = HashMap::new();
 
     let mut dependency_solver = DependencySolver::new();
 
-    let document = parse(&source, filename)?;
+    let filename = document.filename.clone();
 
     let loaded_assemblies = context.get_loaded_assemblies();
 
@@ -791,7 +1165,7 @@ pub fn compile_to(context: &mut CompilerContext, source: &str, filename: &str, f
     }
 
     if !dependency_solver.is_empty() {
-        let mut errors = CompileErrorList::new(filename);
+        let mut errors = CompileErrorList::new(&filename);
         let cycle_filenames = dependency_solver.get_cycle_reference_list().join(", ");
 
         errors.push_error(&Token::new(TokenValue::None, Position::none()), &format!("there may have cycle reference in this files [{}]", cycle_filenames));
@@ -801,6 +1175,20 @@ pub fn compile_to(context: &mut CompilerContext, source: &str, filename: &str, f
     Ok(())
 }
 
+pub fn compile_to(context: &mut CompilerContext, source: &str, filename: &str, file_loader: &dyn Storage) -> Result<(), CompileErrorList> {
+    let document = parse(source, filename)?;
+    let document_filename = document.filename.clone();
+
+    compile_with_dependencies(context, document, file_loader)?;
+
+    if let Some(assembly_state) = context.assemblies.get(&document_filename) {
+        let definition_indices = assembly_state.definition_indices.clone();
+        context.definitions.extend(definition_indices);
+    };
+
+    Ok(())
+}
+
 pub fn compile_file(filename: &str, file_loader: &dyn Storage) -> Result<Program, CompileErrorList> {
     let source = file_loader.load_file(filename)?;
 
@@ -810,11 +1198,105 @@ pub fn compile_file(filename: &str, file_loader: &dyn Storage) -> Result<Program
 pub fn compile(source: &str, filename: &str, file_loader: &dyn Storage) -> Result<Program, CompileErrorList> {
     let mut context = CompilerContext::new();
 
-    compile_to(&mut context, &source, filename, file_loader)?;
+    compile_to(&mut context, source, filename, file_loader)?;
 
     Ok(context.to_program())
 }
 
+// compile the inputs of interactive mode one by one, the names defined by earlier inputs can be used by later ones
+#[derive(Debug, Clone)]
+pub struct InteractiveCompiler {
+    context: CompilerContext,
+    state: CompilerState
+}
+
+impl InteractiveCompiler {
+    pub fn new(filename: &str) -> InteractiveCompiler {
+        let mut context = CompilerContext::new();
+
+        let mut state = CompilerState {
+            assembly_state: AssemblyState::new(filename),
+            locals: Scope::new(),
+            enclosing_function_states: Vec::new(),
+            errors: CompileErrorList::new(filename),
+            redefinable: true
+        };
+
+        state.assembly_state.index = context.add_assembly(state.assembly_state.clone());
+        context.file_info.filenames.push(filename.to_string());
+
+        InteractiveCompiler {
+            context,
+            state
+        }
+    }
+
+    // return the function index of the statements in source, nothing is changed when there is an error
+    pub fn compile(&mut self, source: &str, file_loader: &dyn Storage) -> Result<usize, CompileErrorList> {
+        let backup = self.clone();
+
+        let result = self.compile_items(source, file_loader);
+
+        if result.is_err() {
+            *self = backup;
+        };
+
+        result
+    }
+
+    fn compile_items(&mut self, source: &str, file_loader: &dyn Storage) -> Result<usize, CompileErrorList> {
+        let filename = self.state.assembly_state.filename.clone();
+
+        let (includes, items): (Vec<InteractiveItem>, Vec<InteractiveItem>) = parse_interactive(source, &filename)?
+            .into_iter()
+            .partition(|item| matches!(item, InteractiveItem::Definition(Definition::Include(_))));
+
+        let mut include_document = Document {
+            definitions: includes.into_iter().filter_map(|item| if let InteractiveItem::Definition(definition) = item { Some(definition) } else { None }).collect(),
+            filename: filename.clone()
+        };
+
+        include_document.normalize_include_paths();
+
+        for dependency_filename in include_document.get_dependencies() {
+            if !self.context.assemblies.contains_key(&dependency_filename) {
+                let dependency_source = file_loader.load_file(&dependency_filename)?;
+                let dependency_document = parse(&dependency_source, &dependency_filename)?;
+
+                compile_with_dependencies(&mut self.context, dependency_document, file_loader)?;
+            };
+        };
+
+        self.state.compile(&mut self.context, &include_document);
+
+        let mut function_state = FunctionState::new();
+
+        for item in items.iter() {
+            match item {
+                InteractiveItem::Definition(definition) => self.state.compile_definition(&mut self.context, definition),
+                InteractiveItem::Statement(Statement::Local(local_statement)) => self.state.compile_context_local_statement(&mut self.context, &mut function_state, local_statement),
+                InteractiveItem::Statement(statement) => self.state.compile_statement(&mut self.context, &mut function_state, statement)
+            };
+        };
+
+        function_state.emit_return(function_state.get_last_position());
+
+        let function_index = self.context.add_function(function_state, "<interactive>", self.state.assembly_state.index);
+
+        self.context.definitions.extend(self.state.assembly_state.definition_indices.clone());
+
+        if self.state.errors.is_empty() {
+            Ok(function_index)
+        } else {
+            Err(std::mem::replace(&mut self.state.errors, CompileErrorList::new(&filename)))
+        }
+    }
+
+    pub fn to_program(&self) -> Program {
+        self.context.to_program()
+    }
+}
+
 // helpers
 fn get_operation_instruction_by_token(token: &Token) -> Option<Instruction> {
     let operand: usize = match token.value {
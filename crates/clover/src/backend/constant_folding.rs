@@ -0,0 +1,98 @@
+use crate::intermediate::TokenValue;
+use crate::intermediate::ast::{Expression, InfixExpression, PrefixExpression};
+use crate::runtime::object::Object;
+
+// the value of an expression made of literals only, it is computed here instead of at runtime
+// None when the expression is not constant, or running it raises an error or overflows, so it still fails at runtime
+pub fn fold_expression(expression: &Expression) -> Option<Object> {
+    match expression {
+        Expression::Integer(integer_expression) => match integer_expression.token.value {
+            TokenValue::Integer(value) => Some(Object::Integer(value)),
+            _ => None
+        },
+        Expression::Float(float_expression) => match float_expression.token.value {
+            TokenValue::Float(value) => Some(Object::Float(value)),
+            _ => None
+        },
+        Expression::Boolean(bool_expression) => match bool_expression.token.value {
+            TokenValue::True => Some(Object::Boolean(true)),
+            TokenValue::False => Some(Object::Boolean(false)),
+            _ => None
+        },
+        Expression::Null(_) => Some(Object::Null),
+        Expression::Prefix(prefix_expression) => fold_prefix_expression(prefix_expression),
+        Expression::Infix(infix_expression) => fold_infix_expression(infix_expression),
+        _ => None
+    }
+}
+
+pub fn fold_prefix_expression(prefix_expression: &PrefixExpression) -> Option<Object> {
+    let right = fold_expression(&prefix_expression.right)?;
+
+    match prefix_expression.prefix.value {
+        TokenValue::Minus => match right {
+            Object::Integer(value) => value.checked_neg().map(Object::Integer),
+            Object::Float(value) => Some(Object::Float(-value)),
+            _ => None
+        },
+        TokenValue::Not => Some(Object::Boolean(!right.to_bool())),
+        _ => None
+    }
+}
+
+pub fn fold_infix_expression(infix_expression: &InfixExpression) -> Option<Object> {
+    let left = fold_expression(&infix_expression.left)?;
+    let right = fold_expression(&infix_expression.right)?;
+
+    match infix_expression.infix.value {
+        // both sides are evaluated at runtime too, so only the values matter
+        TokenValue::And => Some(Object::Boolean(left.to_bool() && right.to_bool())),
+        TokenValue::Or => Some(Object::Boolean(left.to_bool() || right.to_bool())),
+        TokenValue::NotEqual => fold_operation(&left, &right, &TokenValue::Equal).map(|value| Object::Boolean(!value.to_bool())),
+        ref operation => fold_operation(&left, &right, operation)
+    }
+}
+
+// same rules as the operations of state
+fn fold_operation(left: &Object, right: &Object, operation: &TokenValue) -> Option<Object> {
+    match (left, right) {
+        (Object::Integer(left), Object::Integer(right)) => fold_integer_operation(*left, *right, operation),
+        (Object::Integer(left), Object::Float(right)) => fold_float_operation(*left as f64, *right, operation),
+        (Object::Float(left), Object::Integer(right)) => fold_float_operation(*left, *right as f64, operation),
+        (Object::Float(left), Object::Float(right)) => fold_float_operation(*left, *right, operation),
+        (Object::Null, right) if *operation == TokenValue::Equal => Some(Object::Boolean(right.is_null())),
+        _ => None
+    }
+}
+
+fn fold_integer_operation(left: i64, right: i64, operation: &TokenValue) -> Option<Object> {
+    Some(match operation {
+        TokenValue::Plus => Object::Integer(left.checked_add(right)?),
+        TokenValue::Minus => Object::Integer(left.checked_sub(right)?),
+        TokenValue::Star => Object::Integer(left.checked_mul(right)?),
+        TokenValue::Slash => Object::Integer(left.checked_div(right)?),
+        TokenValue::Percent => Object::Integer(left.checked_rem(right)?),
+        TokenValue::Equal => Object::Boolean(left == right),
+        TokenValue::Greater => Object::Boolean(left > right),
+        TokenValue::Less => Object::Boolean(left < right),
+        TokenValue::GreaterEqual => Object::Boolean(left >= right),
+        TokenValue::LessEqual => Object::Boolean(left <= right),
+        _ => return None
+    })
+}
+
+fn fold_float_operation(left: f64, right: f64, operation: &TokenValue) -> Option<Object> {
+    Some(match operation {
+        TokenValue::Plus => Object::Float(left + right),
+        TokenValue::Minus => Object::Float(left - right),
+        TokenValue::Star => Object::Float(left * right),
+        TokenValue::Slash => Object::Float(left / right),
+        TokenValue::Percent => Object::Float(left % right),
+        TokenValue::Equal => Object::Boolean(left == right),
+        TokenValue::Greater => Object::Boolean(left > right),
+        TokenValue::Less => Object::Boolean(left < right),
+        TokenValue::GreaterEqual => Object::Boolean(left >= right),
+        TokenValue::LessEqual => Object::Boolean(left <= right),
+        _ => return None
+    })
+}
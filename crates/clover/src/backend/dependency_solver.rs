@@ -31,11 +31,7 @@ impl DependencySolver {
     }
 
     pub fn get_unsolved_filename(&self) -> Option<String> {
-        if let Some(filename) = self.unsolved.iter().next() {
-            Some(filename.clone())
-        } else {
-            None
-        }
+        self.unsolved.iter().next().cloned()
     }
 
     pub fn get_next_no_dependency_filename(&self) -> Option<String> {
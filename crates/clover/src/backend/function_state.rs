@@ -1,22 +1,62 @@
 use std::collections::HashMap;
+use indexmap::IndexSet;
 
 use crate::runtime::opcode::{Instruction, OpCode};
 use crate::intermediate::{Positions, Position};
-use crate::runtime::program::Program;
+use crate::runtime::program::{Program, Upvalue, Handler};
+use crate::intermediate::ast::Statement;
 
 pub type Scope = HashMap<String, usize>;
 
 pub type BreakScope = Vec<usize>;
 
+// a handler which is still compiling, its protected range can be split when jumping out of the try block
+#[derive(Debug, Clone)]
+pub struct HandlerState {
+    // where the try block starts, the stack size at there will be restored when error occurs
+    pub try_start: usize,
+    pub range_start: usize,
+    // indices of the handlers which target need to be filled in
+    pub handler_indices: Vec<usize>
+}
+
+#[derive(Debug, Clone)]
+pub struct TryState {
+    pub rescue_handler: Option<HandlerState>,
+    pub ensure_handler: Option<HandlerState>,
+    pub ensure_statements: Vec<Statement>,
+    // break scope count when entering the try block, break out of the loops outside need to run ensure block
+    pub break_scope_count: usize
+}
+
+// sizes of what compiling adds to a function state, the code compiled after the mark can be taken back
+#[derive(Debug, Clone)]
+pub struct FunctionStateMark {
+    local_count: usize,
+    // jumps of break and split handlers are added to the enclosing loops and try blocks, they only grow with nesting
+    break_scopes: Vec<BreakScope>,
+    try_states: Vec<TryState>,
+    handlers: usize,
+    upvalues: usize,
+    captured_locals: usize,
+    instructions: usize
+}
+
 #[derive(Debug, Clone)]
 pub struct FunctionState {
     pub is_instance: bool,
     pub parameter_count: usize,
     pub local_count: usize,
     pub depth: usize,
-    pub rescue_position: usize,
     pub scopes: Vec<Scope>,
     pub break_scopes: Vec<BreakScope>,
+    pub try_states: Vec<TryState>,
+    pub handlers: Vec<Handler>,
+    // where the try block of each handler starts, use to find the stack size of handlers
+    pub handler_try_starts: Vec<usize>,
+    pub upvalues: Vec<Upvalue>,
+    // locals which captured by closures inside this function, in order of capturing so a mark can take them back
+    pub captured_locals: IndexSet<usize>,
     pub instructions: Vec<Instruction>,
     pub positions: Positions
 }
@@ -28,9 +68,13 @@ impl FunctionState {
             parameter_count: 0,
             local_count: 0,
             depth: 0,
-            rescue_position: 0,
             scopes: Vec::new(),
             break_scopes: Vec::new(),
+            try_states: Vec::new(),
+            handlers: Vec::new(),
+            handler_try_starts: Vec::new(),
+            upvalues: Vec::new(),
+            captured_locals: IndexSet::new(),
             instructions: Vec::new(),
             positions: Positions::new()
         };
@@ -42,7 +86,7 @@ impl FunctionState {
 
     pub fn get_last_position(&self) -> Position {
         if let Some(position) = self.positions.last() {
-            position.clone()
+            *position
         } else {
             Position::none()
         }
@@ -68,7 +112,7 @@ impl FunctionState {
     }
 
     pub fn remove_pop_or_push_null(&mut self) {
-        if self.instructions.len() == 0 {
+        if self.instructions.is_empty() {
             self.emit(OpCode::PushConstant.to_instruction(Program::NULL_CONSTANT_INDEX as u64), self.get_last_position());
             return;
         };
@@ -97,7 +141,7 @@ impl FunctionState {
     }
 
     pub fn emit_break(&mut self, position: Position) {
-        if self.break_scopes.len() == 0 {
+        if self.break_scopes.is_empty() {
             return;
         };
 
@@ -122,6 +166,31 @@ impl FunctionState {
         None
     }
 
+    pub fn mark(&self) -> FunctionStateMark {
+        FunctionStateMark {
+            local_count: self.local_count,
+            break_scopes: self.break_scopes.clone(),
+            try_states: self.try_states.clone(),
+            handlers: self.handlers.len(),
+            upvalues: self.upvalues.len(),
+            captured_locals: self.captured_locals.len(),
+            instructions: self.instructions.len()
+        }
+    }
+
+    // scopes are balanced by the compiled statements, so they are not in the mark
+    pub fn rollback(&mut self, mark: FunctionStateMark) {
+        self.local_count = mark.local_count;
+        self.break_scopes = mark.break_scopes;
+        self.try_states = mark.try_states;
+        self.handlers.truncate(mark.handlers);
+        self.handler_try_starts.truncate(mark.handlers);
+        self.upvalues.truncate(mark.upvalues);
+        self.captured_locals.truncate(mark.captured_locals);
+        self.instructions.truncate(mark.instructions);
+        self.positions.truncate(mark.instructions);
+    }
+
     pub fn enter_scope(&mut self) {
         self.scopes.push(Scope::new());
     }
@@ -163,5 +232,153 @@ impl FunctionState {
         index
     }
 
+    fn add_upvalue(&mut self, upvalue: Upvalue) -> usize {
+        if let Some(index) = self.upvalues.iter().position(|&existing| existing == upvalue) {
+            return index;
+        };
+
+        self.upvalues.push(upvalue);
+        self.upvalues.len() - 1
+    }
+
+    // enclosing_function_states is the function states outside this function, the last one is the direct parent
+    pub fn resolve_upvalue(&mut self, enclosing_function_states: &mut [FunctionState], name: &str) -> Option<usize> {
+        let (parent, rest) = enclosing_function_states.split_last_mut()?;
+
+        if let Some(local_index) = parent.find_local(name) {
+            parent.captured_locals.insert(local_index);
+            return Some(self.add_upvalue(Upvalue::Local(local_index)));
+        };
+
+        let parent_upvalue_index = parent.resolve_upvalue(rest, name)?;
+
+        Some(self.add_upvalue(Upvalue::Upvalue(parent_upvalue_index)))
+    }
+
+    // captured locals are shared with closures by cells, so switch the instructions of them to cell version
+    pub fn box_captured_locals(&mut self) {
+        if self.captured_locals.is_empty() {
+            return;
+        };
+
+        for instruction in self.instructions.iter_mut() {
+            let opcode = match instruction.opcode() {
+                OpCode::LocalGet => OpCode::CellGet,
+                OpCode::LocalSet => OpCode::CellSet,
+                OpCode::LocalInit => OpCode::CellInit,
+                _ => continue
+            };
+
+            let local_index = instruction.operand();
+
+            if self.captured_locals.contains(&(local_index as usize)) {
+                *instruction = opcode.to_instruction(local_index);
+            };
+        };
+    }
+
+    pub fn begin_handler(&self) -> HandlerState {
+        let index = self.get_next_instruction_index();
+
+        HandlerState {
+            try_start: index,
+            range_start: index,
+            handler_indices: Vec::new()
+        }
+    }
+
+    pub fn add_handler(&mut self, start: usize, end: usize, target: usize, try_start: usize) -> usize {
+        let index = self.handlers.len();
+
+        self.handlers.push(Handler {
+            start,
+            end,
+            target,
+            // resolved when the function is finished
+            stack_size: 0
+        });
+        self.handler_try_starts.push(try_start);
+
+        index
+    }
+
+    // stop protecting the instructions after here, until the range reopened
+    pub fn close_handler_range(&mut self, handler_state: &mut HandlerState) {
+        let end = self.get_next_instruction_index();
+
+        if handler_state.range_start < end {
+            let index = self.add_handler(handler_state.range_start, end, 0, handler_state.try_start);
+            handler_state.handler_indices.push(index);
+        };
+
+        handler_state.range_start = end;
+    }
+
+    pub fn reopen_handler_range(&self, handler_state: &mut HandlerState) {
+        handler_state.range_start = self.get_next_instruction_index();
+    }
+
+    pub fn set_handler_target(&mut self, handler_state: &HandlerState, target: usize) {
+        for &index in &handler_state.handler_indices {
+            self.handlers[index].target = target;
+        };
+    }
+
+    // stack size above the frame before each instruction, None if the instruction is unreachable
+    fn stack_sizes(&self) -> Vec<Option<usize>> {
+        let mut stack_sizes = vec![None; self.instructions.len()];
+        let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+
+        while !pending.is_empty() {
+            while let Some((index, stack_size)) = pending.pop() {
+                if index >= self.instructions.len() || stack_sizes[index].is_some() {
+                    continue;
+                };
+
+                stack_sizes[index] = Some(stack_size);
+
+                let instruction = self.instructions[index];
+                let (pop_count, push_count) = instruction.stack_effect();
+                let next_stack_size = stack_size.saturating_sub(pop_count) + push_count;
+
+                match instruction.opcode() {
+                    OpCode::Return | OpCode::Raise => {},
+                    OpCode::Jump => pending.push((instruction.operand() as usize, next_stack_size)),
+                    OpCode::JumpIf => {
+                        pending.push((instruction.operand() as usize, next_stack_size));
+
+                        // the value of iterator is left on the stack when the loop continues
+                        if index > 0 && self.instructions[index - 1].opcode() == OpCode::ForNext {
+                            pending.push((index + 1, next_stack_size + 1));
+                        } else {
+                            pending.push((index + 1, next_stack_size));
+                        };
+                    },
+                    _ => pending.push((index + 1, next_stack_size))
+                };
+            };
+
+            // rescue blocks can only be reached by handlers, the error object is on the top of stack
+            for (handler, &try_start) in self.handlers.iter().zip(self.handler_try_starts.iter()) {
+                if let (Some(None), Some(&Some(stack_size))) = (stack_sizes.get(handler.target), stack_sizes.get(try_start)) {
+                    pending.push((handler.target, stack_size + 1));
+                };
+            };
+        };
+
+        stack_sizes
+    }
+
+    pub fn resolve_handler_stack_sizes(&mut self) {
+        if self.handlers.is_empty() {
+            return;
+        };
+
+        let stack_sizes = self.stack_sizes();
+
+        for (handler, &try_start) in self.handlers.iter_mut().zip(self.handler_try_starts.iter()) {
+            handler.stack_size = stack_sizes.get(try_start).cloned().flatten().unwrap_or(0);
+        };
+    }
 
 }
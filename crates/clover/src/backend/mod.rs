@@ -1,4 +1,7 @@
 pub mod compiler;
 mod dependency_solver;
+mod constant_folding;
+mod peephole;
 mod function_state;
-mod assembly_state;
\ No newline at end of file
+mod assembly_state;
+pub mod assembler;
\ No newline at end of file
@@ -0,0 +1,93 @@
+use crate::backend::function_state::FunctionState;
+use crate::runtime::opcode::{Instruction, OpCode, OPERATION_ADD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL};
+
+const COMPARISONS: &[ usize ] = &[ OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL ];
+
+// the instruction replacing two instructions, and which of them gives the position
+fn fuse(first: Instruction, second: Instruction) -> Option<(Instruction, usize)> {
+    match (first.opcode(), second.opcode()) {
+        // LocalInit pops and sets, the locals captured by closures are already cells here, so it never becomes a new cell
+        (OpCode::LocalSet, OpCode::Pop) => Some((OpCode::LocalInit.to_instruction(first.operand()), 0)),
+        // errors of adding are raised at the operator
+        (OpCode::PushConstant, OpCode::Operation) if second.operand() as usize == OPERATION_ADD => Some((OpCode::AddConstant.to_instruction(first.operand()), 1)),
+        (OpCode::Not, OpCode::JumpIf) => Some((OpCode::JumpIfNot.to_instruction(second.operand()), 0)),
+        _ => None
+    }
+}
+
+fn is_jump(opcode: OpCode) -> bool {
+    matches!(opcode, OpCode::Jump | OpCode::JumpIf | OpCode::JumpIfNot)
+}
+
+// instructions which can be reached without running the one before them, they can not be fused into it
+fn find_entries(function_state: &FunctionState) -> Vec<bool> {
+    let mut entries = vec![ false; function_state.instructions.len() + 1 ];
+
+    for instruction in function_state.instructions.iter() {
+        if is_jump(instruction.opcode()) {
+            entries[instruction.operand() as usize] = true;
+        };
+    };
+
+    for handler in function_state.handlers.iter() {
+        entries[handler.start] = true;
+        entries[handler.end] = true;
+        entries[handler.target] = true;
+    };
+
+    entries
+}
+
+// replace common instruction sequences with fused opcodes, run after the function is completely compiled
+pub fn optimize(function_state: &mut FunctionState) {
+    let entries = find_entries(function_state);
+    let instructions = std::mem::take(&mut function_state.instructions);
+    let positions = std::mem::take(&mut function_state.positions);
+
+    // new index of every instruction and the end of function, a fused instruction has the index of the first one
+    let mut new_indices = Vec::with_capacity(instructions.len() + 1);
+    let mut index = 0;
+
+    while index < instructions.len() {
+        new_indices.push(function_state.get_next_instruction_index());
+
+        let fused = match instructions.get(index + 1) {
+            Some(&next) if !entries[index + 1] => fuse(instructions[index], next),
+            _ => None
+        };
+
+        if let Some((instruction, position_offset)) = fused {
+            new_indices.push(function_state.get_next_instruction_index());
+            function_state.emit(instruction, positions[index + position_offset]);
+            index += 2;
+        } else {
+            function_state.emit(instructions[index], positions[index]);
+            index += 1;
+        };
+    };
+
+    new_indices.push(function_state.get_next_instruction_index());
+
+    for instruction in function_state.instructions.iter_mut() {
+        let opcode = instruction.opcode();
+
+        if is_jump(opcode) {
+            *instruction = opcode.to_instruction(new_indices[instruction.operand() as usize] as u64);
+        };
+    };
+
+    for handler in function_state.handlers.iter_mut() {
+        handler.start = new_indices[handler.start];
+        handler.end = new_indices[handler.end];
+        handler.target = new_indices[handler.target];
+    };
+
+    // a comparison which result is only used by the jump after it, the jump is kept for objects other than numbers
+    for index in 1..function_state.instructions.len() {
+        let instruction = function_state.instructions[index - 1];
+
+        if instruction.opcode() == OpCode::Operation && COMPARISONS.contains(&(instruction.operand() as usize)) && matches!(function_state.instructions[index].opcode(), OpCode::JumpIf | OpCode::JumpIfNot) {
+            function_state.replace_instruction(index - 1, OpCode::CompareJump.to_instruction(instruction.operand()));
+        };
+    };
+}
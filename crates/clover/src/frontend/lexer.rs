@@ -222,10 +222,7 @@ impl<'a> Iterator for LexState<'a> {
 // character helpers
 
 fn is_space(character: char) -> bool {
-    match character {
-        ' ' | '\t' | '\r' | '\n' => true,
-        _ => false
-    }
+    matches!(character, ' ' | '\t' | '\r' | '\n')
 }
 
 fn is_string(character: char) -> bool {
@@ -233,17 +230,11 @@ fn is_string(character: char) -> bool {
 }
 
 fn is_number(character: char) -> bool {
-    match character {
-        '0'..='9' => true,
-        _ => false
-    }
+    character.is_ascii_digit()
 }
 
 fn is_alpha(character: char) -> bool {
-    match character {
-        'a'..='z' | 'A'..='Z' => true,
-        _ => false
-    }
+    character.is_ascii_alphabetic()
 }
 
 fn is_identifier(character: char) -> bool {
@@ -294,7 +285,10 @@ fn get_keyword(keyword: &str) -> Option<TokenValue> {
         "in"            => TokenValue::In,
         "break"         => TokenValue::Break,
 
-        "rescue"        => TokenValue::Rescue
+        "rescue"        => TokenValue::Rescue,
+        "raise"         => TokenValue::Raise,
+        "try"           => TokenValue::Try,
+        "ensure"        => TokenValue::Ensure
     }
 }
 
@@ -312,6 +306,8 @@ fn get_symbol(symbol: &str) -> Option<TokenValue> {
         ")"     =>  TokenValue::RightParentheses,
         "["     =>  TokenValue::LeftBracket,
         "]"     =>  TokenValue::RightBracket,
+        "{"     =>  TokenValue::LeftBrace,
+        "}"     =>  TokenValue::RightBrace,
         ","     =>  TokenValue::Comma,
         ":"     =>  TokenValue::Colon,
         "&"     =>  TokenValue::BitAnd,
@@ -346,7 +342,7 @@ pub fn lex(source: &str) -> Result<TokenList, CompileErrorList> {
 
     let mut tokens = TokenList::new();
 
-    while let Some(token) = state.next() {
+    for token in state.by_ref() {
         tokens.push(token);
     };
 
@@ -1,5 +1,5 @@
 use crate::intermediate::{Token, CompileErrorList, TokenValue, CompileError};
-use crate::intermediate::ast::{Document, Definition, ModelDefinition, FunctionDefinition, Statement, ImplementDefinition, ApplyDefinition, LocalDefinition, IncludeDefinition, ReturnStatement, Expression, IdentifierExpression, IntegerExpression, FloatExpression, BooleanExpression, ThisExpression, NullExpression, PrefixExpression, IfExpression, InfixExpression, CallExpression, StringExpression, InstanceGetExpression, LocalStatement, ArrayExpression, IndexGetExpression, ForStatement, BreakStatement, RescueStatement};
+use crate::intermediate::ast::{Document, InteractiveItem, Definition, ModelDefinition, FunctionDefinition, Statement, ImplementDefinition, ApplyDefinition, LocalDefinition, IncludeDefinition, ReturnStatement, Expression, IdentifierExpression, IntegerExpression, FloatExpression, BooleanExpression, ThisExpression, NullExpression, PrefixExpression, IfExpression, InfixExpression, CallExpression, StringExpression, InstanceGetExpression, LocalStatement, ArrayExpression, IndexGetExpression, ForStatement, WhileStatement, BreakStatement, RescueStatement, RaiseStatement, TryStatement, RescueBlock, FunctionExpression, MapExpression};
 use crate::frontend::lexer::lex;
 use std::slice::Iter;
 use std::mem::discriminant;
@@ -168,14 +168,10 @@ impl<'a> ParserState<'a> {
 
         match token.value {
             TokenValue::Minus | TokenValue::Not => {
-                if let Some(expression) = self.parse_expression(SymbolPriority::Prefix) {
-                    Some(Expression::Prefix(PrefixExpression {
-                        prefix: token,
-                        right: Box::new(expression)
-                    }))
-                } else {
-                    None
-                }
+                self.parse_expression(SymbolPriority::Prefix).map(|expression| Expression::Prefix(PrefixExpression {
+                    prefix: token,
+                    right: Box::new(expression)
+                }))
             },
             _ => None
         }
@@ -285,6 +281,87 @@ impl<'a> ParserState<'a> {
         }
     }
 
+    fn parse_map_key_expression(&mut self) -> Option<Expression> {
+        // identifier key is same as string key
+        if let TokenValue::Identifier(identifier) = self.current_token.value.clone() {
+            if self.peek_token.value == TokenValue::Colon {
+                let token = Token::new(TokenValue::String(identifier), self.current_token.position);
+                self.next_token();
+
+                return Some(Expression::String(StringExpression { token }));
+            };
+        };
+
+        self.parse_expression(SymbolPriority::Lowest)
+    }
+
+    fn parse_map_expression(&mut self) -> Option<Expression> {
+        self.expect_token(TokenValue::LeftBrace);
+
+        let token = self.current_token.clone();
+        self.next_token();
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+
+        let mut last_comma = None;
+
+        while !self.current_token_is_any_of(&[ TokenValue::RightBrace, TokenValue::Eof ]) {
+            last_comma = None;
+
+            keys.push(self.parse_map_key_expression()?);
+
+            if !self.expect_and_pop_token(TokenValue::Colon) {
+                return None;
+            };
+
+            values.push(self.parse_expression(SymbolPriority::Lowest)?);
+
+            if self.current_token.value == TokenValue::Comma {
+                last_comma = Some(self.current_token.clone());
+                self.next_token();
+            } else {
+                break;
+            };
+        };
+
+        if let Some(token) = last_comma {
+            self.push_error(&token, "Unexpect token".to_string());
+        };
+
+        if !self.expect_and_pop_token(TokenValue::RightBrace) {
+            return None;
+        };
+
+        Some(Expression::Map(MapExpression {
+            token,
+            keys,
+            values
+        }))
+    }
+
+    fn parse_function_expression(&mut self) -> Option<Expression> {
+        let token = self.current_token.clone();
+
+        if !self.expect_and_pop_token(TokenValue::Function) {
+            return None;
+        };
+
+        let parameters = self.parse_function_parameters()?;
+
+        let body = self.parse_body(&[ TokenValue::End, TokenValue::Eof ]);
+
+        if !self.expect_and_pop_token(TokenValue::End) {
+            return None;
+        };
+
+        Some(Expression::Function(FunctionExpression {
+            token,
+            parameters,
+            body
+        }))
+    }
+
     fn parse_start_expression(&mut self) -> Option<Expression> {
         match self.current_token.value {
             TokenValue::Identifier(_) => self.parse_identifier_expression(),
@@ -296,7 +373,9 @@ impl<'a> ParserState<'a> {
             TokenValue::Minus | TokenValue::Not => self.parse_prefix_expression(),
             TokenValue::LeftParentheses => self.parse_group_expression(),
             TokenValue::LeftBracket => self.parse_array_expression(),
+            TokenValue::LeftBrace => self.parse_map_expression(),
             TokenValue::If => self.parse_if_expression(),
+            TokenValue::Function => self.parse_function_expression(),
             _ => {
                 self.push_error(&self.current_token.clone(), "Unexpect token when parse expression".to_string());
                 None
@@ -369,14 +448,9 @@ impl<'a> ParserState<'a> {
 
     fn parse_infix_expression(&mut self, expression: Expression) -> Option<Expression> {
         // if '-' or '(' or '[' is the first token at line, it's not a infix expression
-        match self.current_token.value {
-            TokenValue::Minus | TokenValue::LeftParentheses | TokenValue::LeftBracket => {
-                if self.current_token.position.line > self.last_token.position.line {
-                    return None;
-                };
-            },
-            _ => {}
-        }
+        if matches!(self.current_token.value, TokenValue::Minus | TokenValue::LeftParentheses | TokenValue::LeftBracket) && self.current_token.position.line > self.last_token.position.line {
+            return None;
+        };
 
         match self.current_token.value {
             TokenValue::Assign | TokenValue::PlusAssign | TokenValue::MinusAssign | TokenValue::StarAssign | TokenValue::SlashAssign | TokenValue::PercentAssign |
@@ -388,15 +462,11 @@ impl<'a> ParserState<'a> {
 
                 self.next_token();
 
-                if let Some(right) = self.parse_expression(precedence) {
-                    Some(Expression::Infix(InfixExpression {
-                        left: Box::new(expression),
-                        infix: token,
-                        right: Box::new(right)
-                    }))
-                } else {
-                    None
-                }
+                self.parse_expression(precedence).map(|right| Expression::Infix(InfixExpression {
+                    left: Box::new(expression),
+                    infix: token,
+                    right: Box::new(right)
+                }))
             }
             TokenValue::LeftParentheses => self.parse_call_expression(expression),
             TokenValue::Dot | TokenValue::LeftBracket => self.parse_instance_get_expression(expression),
@@ -442,21 +512,93 @@ impl<'a> ParserState<'a> {
         Some(Statement::Break(break_statement))
     }
 
+    // the identifier must at the same line of rescue, otherwise it is the first statement of rescue block
+    fn parse_rescue_identifier(&mut self, rescue_token: &Token) -> Option<Token> {
+        if matches!(self.current_token.value, TokenValue::Identifier(_)) && self.current_token.position.line == rescue_token.position.line {
+            let identifier = self.current_token.clone();
+            self.next_token();
+            Some(identifier)
+        } else {
+            None
+        }
+    }
+
     fn parse_rescue_statement(&mut self) -> Option<Statement> {
+        let token = self.current_token.clone();
+        self.next_token();
+
+        let identifier = self.parse_rescue_identifier(&token);
+
         let rescue_statement = RescueStatement {
-            token: self.current_token.clone()
+            token,
+            identifier
         };
-        self.next_token();
 
         Some(Statement::Rescue(rescue_statement))
     }
 
-    fn parse_expression_statement(&mut self) -> Option<Statement> {
-        if let Some(expression) = self.parse_expression(SymbolPriority::Lowest) {
-            Some(Statement::Expression(expression))
+    fn parse_raise_statement(&mut self) -> Option<Statement> {
+        let token = self.current_token.clone();
+        self.next_token();
+
+        let value = self.parse_expression(SymbolPriority::Lowest)?;
+
+        Some(Statement::Raise(RaiseStatement {
+            token,
+            value
+        }))
+    }
+
+    fn parse_try_statement(&mut self) -> Option<Statement> {
+        let token = self.current_token.clone();
+
+        if !self.expect_and_pop_token(TokenValue::Try) {
+            return None;
+        };
+
+        let statements = self.parse_body(&[ TokenValue::Eof, TokenValue::Rescue, TokenValue::Ensure, TokenValue::End ]);
+
+        let rescue = if self.current_token.value == TokenValue::Rescue {
+            let rescue_token = self.current_token.clone();
+            self.next_token();
+
+            let identifier = self.parse_rescue_identifier(&rescue_token);
+
+            Some(RescueBlock {
+                token: rescue_token,
+                identifier,
+                statements: self.parse_body(&[ TokenValue::Eof, TokenValue::Ensure, TokenValue::End ])
+            })
         } else {
             None
-        }
+        };
+
+        let ensure = if self.current_token.value == TokenValue::Ensure {
+            self.next_token();
+            Some(self.parse_body(&[ TokenValue::Eof, TokenValue::End ]))
+        } else {
+            None
+        };
+
+        if !self.expect_and_pop_token(TokenValue::End) {
+            return None;
+        };
+
+        if rescue.is_none() && ensure.is_none() {
+            self.push_error(&token, "try block need rescue or ensure".to_string());
+            return None;
+        };
+
+        Some(Statement::Try(TryStatement {
+            token,
+            statements,
+            rescue,
+            ensure
+        }))
+    }
+
+    fn parse_expression_statement(&mut self) -> Option<Statement> {
+        self.parse_expression(SymbolPriority::Lowest).map(Statement::Expression)
     }
 
     fn parse_local_statement(&mut self) -> Option<Statement> {
@@ -512,22 +654,40 @@ impl<'a> ParserState<'a> {
             return None;
         };
 
-        let expression = self.parse_expression(SymbolPriority::Lowest);
+        let expression = self.parse_expression(SymbolPriority::Lowest)?;
+
+        let statements = self.parse_body(&[ TokenValue::Eof, TokenValue::End ]);
+
+        if !self.expect_and_pop_token(TokenValue::End) {
+            return None;
+        };
+
+        Some(Statement::For(ForStatement{
+            token,
+            identifier,
+            enumerable: expression,
+            statements
+        }))
+    }
+
+    fn parse_while_statement(&mut self) -> Option<Statement> {
+        let token = self.current_token.clone();
 
-        if expression.is_none() {
+        if !self.expect_and_pop_token(TokenValue::While) {
             return None;
         };
 
+        let condition = self.parse_expression(SymbolPriority::Lowest)?;
+
         let statements = self.parse_body(&[ TokenValue::Eof, TokenValue::End ]);
 
         if !self.expect_and_pop_token(TokenValue::End) {
             return None;
         };
 
-        Some(Statement::For(ForStatement{
+        Some(Statement::While(WhileStatement {
             token,
-            identifier,
-            enumerable: expression.unwrap(),
+            condition,
             statements
         }))
     }
@@ -538,7 +698,10 @@ impl<'a> ParserState<'a> {
             TokenValue::Return => self.parse_return_statement(),
             TokenValue::Break => self.parse_break_statement(),
             TokenValue::Rescue => self.parse_rescue_statement(),
+            TokenValue::Raise => self.parse_raise_statement(),
+            TokenValue::Try => self.parse_try_statement(),
             TokenValue::For => self.parse_for_statement(),
+            TokenValue::While => self.parse_while_statement(),
             _ => self.parse_expression_statement()
         }
     }
@@ -577,8 +740,13 @@ impl<'a> ParserState<'a> {
         let mut statements = Vec::new();
 
         while !self.current_token_is_any_of(terminators) {
+            let remain_count = self.tokens.len();
+
             if let Some(statement) = self.parse_statement() {
                 statements.push(statement);
+            } else if self.tokens.len() == remain_count {
+                // skip the token which can not start a statement, or it is parsed again and again
+                self.next_token();
             };
         };
 
@@ -596,18 +764,34 @@ impl<'a> ParserState<'a> {
         let name = self.current_token.clone();
         self.next_token();
 
+        let parameters = self.parse_function_parameters()?;
+
+        // parse function body
+        let body = self.parse_body(&[ TokenValue::End, TokenValue::Eof ]);
+
+        if !self.expect_and_pop_token(TokenValue::End) {
+            return None;
+        };
+
+        Some(Definition::Function(FunctionDefinition {
+            name,
+            parameters,
+            body
+        }))
+    }
+
+    fn parse_function_parameters(&mut self) -> Option<Vec<Token>> {
         if !self.expect_and_pop_token(TokenValue::LeftParentheses) {
             return None;
         };
 
-        // parse parameters
         let mut parameters = Vec::new();
         let mut last_comma = None;
 
         while self.current_token.value != TokenValue::RightParentheses {
             last_comma = None;
 
-            if parameters.len() == 0 {
+            if parameters.is_empty() {
                 if !self.current_token_is_any_of(&[ TokenValue::Identifier("".to_string()), TokenValue::This ]) {
                     self.errors.push_error(&self.current_token.clone(), "Unexpect token");
                     self.skip_until(&[ TokenValue::Identifier("".to_string()), TokenValue::This, TokenValue::Eof ]);
@@ -633,18 +817,7 @@ impl<'a> ParserState<'a> {
             return None;
         };
 
-        // parse function body
-        let body = self.parse_body(&[ TokenValue::End, TokenValue::Eof ]);
-
-        if !self.expect_and_pop_token(TokenValue::End) {
-            return None;
-        };
-
-        Some(Definition::Function(FunctionDefinition {
-            name,
-            parameters,
-            body
-        }))
+        Some(parameters)
     }
 
     fn parse_implement_definition(&mut self) -> Option<Definition> {
@@ -659,10 +832,8 @@ impl<'a> ParserState<'a> {
         let mut functions = Vec::new();
 
         while self.current_token.value == TokenValue::Function {
-            if let Some(definition) = self.parse_function_definition() {
-                if let Definition::Function(function_definition) = definition {
-                    functions.push(function_definition);
-                };
+            if let Some(Definition::Function(function_definition)) = self.parse_function_definition() {
+                functions.push(function_definition);
             };
         };
 
@@ -752,7 +923,7 @@ impl<'a> ParserState<'a> {
 
         let mut last_comma = None;
 
-        while models.len() == 0 || self.current_token_is_any_of(&[ Identifier("".to_string()) ]) {
+        while models.is_empty() || self.current_token_is_any_of(&[ Identifier("".to_string()) ]) {
             last_comma = None;
 
             if !self.expect_token(TokenValue::Identifier("".to_string())) {
@@ -835,7 +1006,7 @@ impl<'a> ParserState<'a> {
             TokenValue::Public => self.parse_public_definition(),
             _ => {
                 self.push_error(&self.current_token.clone(), format!("Unexcpet token [{:?}]", self.current_token.clone()));
-                self.skip_until(&[ TokenValue::Include, TokenValue::Public, TokenValue::Model, TokenValue::Implement, TokenValue::Apply, TokenValue::Local, TokenValue::Function ]);
+                self.skip_until(&[ TokenValue::Include, TokenValue::Public, TokenValue::Model, TokenValue::Implement, TokenValue::Apply, TokenValue::Local, TokenValue::Function, TokenValue::Eof, TokenValue::None ]);
                 None
             }
         }
@@ -866,6 +1037,31 @@ impl<'a> ParserState<'a> {
         definitions
     }
 
+    // a function with name is a definition, otherwise it is an anonymous function expression
+    fn parse_interactive_item(&mut self) -> Option<InteractiveItem> {
+        match self.current_token.value {
+            TokenValue::Model | TokenValue::Implement | TokenValue::Apply | TokenValue::Include | TokenValue::Public => self.parse_definition().map(InteractiveItem::Definition),
+            TokenValue::Function if matches!(self.peek_token.value, TokenValue::Identifier(_)) => self.parse_definition().map(InteractiveItem::Definition),
+            _ => self.parse_statement().map(InteractiveItem::Statement)
+        }
+    }
+
+    fn parse_interactive_items(&mut self) -> Vec<InteractiveItem> {
+        let mut items = Vec::new();
+
+        while !self.current_token_is_any_of(&[ TokenValue::Eof, TokenValue::None ]) {
+            let remain_count = self.tokens.len();
+
+            if let Some(item) = self.parse_interactive_item() {
+                items.push(item);
+            } else if self.tokens.len() == remain_count {
+                self.next_token();
+            };
+        };
+
+        items
+    }
+
     fn parse_document(&mut self, filename: String) -> Document {
         let mut document = Document {
             definitions: self.parse_definitions(),
@@ -899,4 +1095,27 @@ pub fn parse(source: &str, filename: &str) -> Result<Document, CompileErrorList>
     } else {
         Err(state.errors)
     }
-}
\ No newline at end of file
+}
+
+pub fn parse_interactive(source: &str, filename: &str) -> Result<Vec<InteractiveItem>, CompileErrorList> {
+    let token_list = lex(source)?;
+
+    let mut state = ParserState {
+        tokens: token_list.iter(),
+        last_token: Token::none(),
+        current_token: Token::none(),
+        peek_token: Token::none(),
+        errors: CompileErrorList::new(filename)
+    };
+
+    state.next_token();
+    state.next_token();
+
+    let items = state.parse_interactive_items();
+
+    if state.errors.is_empty() {
+        Ok(items)
+    } else {
+        Err(state.errors)
+    }
+}
@@ -12,12 +12,14 @@ pub enum Expression {
     String(StringExpression),
     Boolean(BooleanExpression),
     Array(ArrayExpression),
+    Map(MapExpression),
     Prefix(PrefixExpression),
     Infix(InfixExpression),
     If(IfExpression),
     Call(CallExpression),
     InstanceGet(InstanceGetExpression),
-    IndexGet(IndexGetExpression)
+    IndexGet(IndexGetExpression),
+    Function(FunctionExpression)
 }
 
 #[derive(Clone, Debug)]
@@ -61,6 +63,13 @@ pub struct ArrayExpression {
     pub values: Vec<Expression>
 }
 
+#[derive(Clone, Debug)]
+pub struct MapExpression {
+    pub token: Token,
+    pub keys: Vec<Expression>,
+    pub values: Vec<Expression>
+}
+
 #[derive(Clone, Debug)]
 pub struct PrefixExpression {
     pub prefix: Token,
@@ -102,6 +111,13 @@ pub struct IndexGetExpression {
     pub index: Box<Expression>
 }
 
+#[derive(Clone, Debug)]
+pub struct FunctionExpression {
+    pub token: Token,
+    pub parameters: Vec<Token>,
+    pub body: Vec<Statement>
+}
+
 #[derive(Clone, Debug)]
 pub enum Definition {
     Model(ModelDefinition),
@@ -158,8 +174,11 @@ pub enum Statement {
     Local(LocalStatement),
     Return(ReturnStatement),
     For(ForStatement),
+    While(WhileStatement),
     Break(BreakStatement),
     Rescue(RescueStatement),
+    Raise(RaiseStatement),
+    Try(TryStatement),
     Expression(Expression)
 }
 
@@ -181,7 +200,31 @@ pub struct BreakStatement {
 
 #[derive(Clone, Debug)]
 pub struct RescueStatement {
-    pub token: Token
+    pub token: Token,
+    // local name to store the error object
+    pub identifier: Option<Token>
+}
+
+#[derive(Clone, Debug)]
+pub struct RaiseStatement {
+    pub token: Token,
+    pub value: Expression
+}
+
+#[derive(Clone, Debug)]
+pub struct RescueBlock {
+    pub token: Token,
+    // local name to store the error object
+    pub identifier: Option<Token>,
+    pub statements: Vec<Statement>
+}
+
+#[derive(Clone, Debug)]
+pub struct TryStatement {
+    pub token: Token,
+    pub statements: Vec<Statement>,
+    pub rescue: Option<RescueBlock>,
+    pub ensure: Option<Vec<Statement>>
 }
 
 #[derive(Clone, Debug)]
@@ -192,6 +235,20 @@ pub struct ForStatement {
     pub statements: Vec<Statement>
 }
 
+#[derive(Clone, Debug)]
+pub struct WhileStatement {
+    pub token: Token,
+    pub condition: Expression,
+    pub statements: Vec<Statement>
+}
+
+// one input of interactive mode, definitions and statements can be mixed
+#[derive(Clone, Debug)]
+pub enum InteractiveItem {
+    Definition(Definition),
+    Statement(Statement)
+}
+
 #[derive(Clone, Debug)]
 pub struct Document {
     pub definitions: Vec<Definition>,
@@ -49,6 +49,8 @@ pub enum TokenValue {
     RightParentheses,
     LeftBracket,
     RightBracket,
+    LeftBrace,
+    RightBrace,
 
     Comma,
     Colon,
@@ -76,13 +78,16 @@ pub enum TokenValue {
     Break,
 
     Rescue,
+    Raise,
+    Try,
+    Ensure,
 
     None
 }
 
-impl TokenValue {
-    pub fn to_string(&self) -> String {
-        match self {
+impl Display for TokenValue {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
             TokenValue::Identifier(value) => value.clone(),
             TokenValue::String(value) => value.clone(),
             TokenValue::Integer(value) => value.to_string(),
@@ -93,7 +98,9 @@ impl TokenValue {
             TokenValue::This => "this".to_string(),
 
             _ => format!("{:?}", self)
-        }
+        };
+
+        formatter.write_str(&string)
     }
 }
 
@@ -141,7 +148,7 @@ pub struct CompileError {
 
 impl Display for CompileError {
     fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
-        formatter.write_fmt(format_args!("at ({}, {}) near {} - {}", self.token.position.line, self.token.position.column, self.token.value.to_string(), self.message))
+        formatter.write_fmt(format_args!("at ({}, {}) near {} - {}", self.token.position.line, self.token.position.column, self.token.value, self.message))
     }
 }
 
@@ -175,6 +182,11 @@ impl CompileErrorList {
     pub fn is_empty(&self) -> bool {
         self.errors.is_empty()
     }
+
+    // the source ends before a complete definition or statement, more input may fix it
+    pub fn is_incomplete(&self) -> bool {
+        self.errors.iter().any(|error| error.token.value == TokenValue::Eof)
+    }
 }
 
 impl Display for CompileErrorList {
@@ -3,13 +3,20 @@ mod intermediate;
 mod backend;
 mod runtime;
 mod version;
+mod repl;
 
 pub use runtime::program::Program;
 pub use runtime::state::State;
+pub use runtime::state::InterruptHandle;
+pub use runtime::state::Limits;
 pub use runtime::object::Object;
+pub use runtime::object::MapKey;
 pub use runtime::object::NativeModel;
 pub use runtime::object::NativeModelInstance;
 pub use runtime::object::Reference;
+pub use runtime::conversion::FromObject;
+pub use runtime::conversion::IntoObject;
+pub use repl::Repl;
 
 use backend::compiler::DefaultStorage;
 use backend::compiler::compile_file;
@@ -17,6 +24,22 @@ use std::ops::{Deref, DerefMut};
 
 pub mod helper {
     pub use crate::runtime::object::make_reference;
+    pub use crate::runtime::object::make_native_function;
+    pub use crate::runtime::object::make_native_function_mut;
+    pub use crate::runtime::object::NativeFunction;
+    pub use crate::runtime::conversion::make_typed_function;
+    pub use crate::runtime::conversion::expect_parameter_count;
+    pub use crate::runtime::conversion::expect_parameter;
+    pub use crate::runtime::conversion::expect_max_parameter_count;
+    pub use crate::runtime::conversion::expect_property;
+    pub use crate::runtime::conversion::IntoNativeResult;
+    pub use crate::runtime::conversion::IntoNativeInstance;
+    pub use crate::runtime::object::NativeMethods;
+    pub use crate::runtime::object::NativeInstanceRef;
+    pub use crate::runtime::object::NativeInstanceRefMut;
+    pub use crate::runtime::shared::Shared;
+    pub use crate::runtime::shared::Lock;
+    pub use crate::runtime::shared::MaybeSync;
     pub use crate::backend::compiler::Storage;
 
     pub use crate::runtime::object::ensure_parameters_length;
@@ -25,6 +48,10 @@ pub mod helper {
 pub mod debug {
     pub use crate::intermediate::CompileErrorList;
     pub use crate::runtime::program::RuntimeError;
+    pub use crate::runtime::program::RuntimeErrorKind;
+    pub use crate::runtime::program::TraceFrame;
+    pub use crate::runtime::program::BytecodeError;
+    pub use crate::repl::ReplError;
     pub use crate::intermediate::Position;
 }
 
@@ -32,6 +59,12 @@ pub struct Clover {
     storage: Box<dyn helper::Storage>
 }
 
+impl Default for Clover {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clover {
     pub fn new_with_file_loader(storage: Box<dyn helper::Storage>) -> Clover {
         Clover {
@@ -49,6 +82,13 @@ impl Clover {
         compile_file(filename, self.storage.deref())
     }
 
+    // build a program from the text format of Program::disassemble
+    pub fn assemble_file(&self, filename: &str) -> Result<Program, debug::CompileErrorList> {
+        let source = self.storage.load_file(filename)?;
+
+        Program::assemble(&source, filename)
+    }
+
     pub fn save_program(&self, filename: &str, program: &Program) -> Result<(), debug::CompileErrorList> {
 
         let mut writer = self.storage.get_writer(filename)?;
@@ -61,7 +101,11 @@ impl Clover {
     pub fn load_program(&self, filename: &str) -> Result<Program, debug::CompileErrorList> {
         let mut reader = self.storage.get_reader(filename)?;
 
-        Ok(Program::deserialize(&mut reader).unwrap())
+        Program::deserialize(&mut reader).map_err(|error| {
+            let mut errors = debug::CompileErrorList::new(filename);
+            errors.push_error(&intermediate::Token::new(intermediate::TokenValue::None, debug::Position::none()), &error.to_string());
+            errors
+        })
     }
 
     pub fn create_state_by_filename(&self, filename: &str) -> Result<State, debug::CompileErrorList> {
@@ -81,23 +125,17 @@ impl Clover {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Clover, State, Object};
+    use crate::{Clover, State, Object, Program, Limits, Repl, NativeModelInstance, Reference};
+    use crate::debug::{BytecodeError, RuntimeError, RuntimeErrorKind, ReplError};
+    use crate::helper::make_reference;
+    use crate::runtime::opcode::{OpCode, OPCODE_SET_BASE, OPCODE_SET_FUSED};
+    use crate::runtime::program::Upvalue;
+    use crate::runtime::shared::{Shared, Weak};
+    use std::any::Any;
+    use std::ops::Deref;
 
     fn execute_function(state: &mut State, function_name: &str) {
-        let mut function_index = None;
-
-        for (i, name) in state.get_program().file_info.as_ref().unwrap().function_names.iter().enumerate() {
-            if name != function_name {
-                continue;
-            };
-
-            function_index = Some(i);
-            break;
-        };
-
-        assert!(function_index.is_some(), "can not found function [{}] in [{}]", function_name, &state.get_program().file_info.as_ref().unwrap().filenames[0]);
-
-        let result = state.execute_by_function_index(function_index.unwrap(), &[]);
+        let result = state.call(function_name, &[]);
 
         assert!(result.is_ok(), "error occur when executing function [{}] in [{}]", function_name, &state.get_program().file_info.as_ref().unwrap().filenames[0]);
 
@@ -113,14 +151,20 @@ mod tests {
     fn execute(filename: &str, function_names: &[ &str ]) {
         let clover = Clover::new();
 
-        let result = clover.create_state_by_filename(filename);
+        let result = clover.compile_file(filename);
 
         assert!(result.is_ok(), "create state with with file [{}]", filename);
 
-        let mut state = result.unwrap();
+        let program = result.unwrap();
+
+        if let Err(error) = program.verify() {
+            panic!("compiled program of file [{}] is not valid: {}", filename, error);
+        };
+
+        let mut state: State = program.into();
 
         for function_name in function_names {
-            execute_function(&mut state, *function_name)
+            execute_function(&mut state, function_name)
         };
     }
 
@@ -129,14 +173,754 @@ mod tests {
         execute("tests/integer_operations.luck", &[ "add", "sub", "multiply", "divide" ]);
     }
 
+    #[test]
+    fn constant_folding() {
+        execute("tests/constant_folding.luck", &[ "arithmetic", "mixed_numbers", "comparisons", "logic", "divide_by_zero_at_runtime", "dead_branches", "value_of_constant_if", "break_in_dead_branch", "dead_closure", "dead_return_in_try", "dead_closure_in_closure", "dead_global" ]);
+    }
+
+    #[test]
+    fn peephole() {
+        execute("tests/peephole.luck", &[ "counting_loop", "mixed_numbers", "not_a_number", "not_equal_jump", "meta_method_comparison", "add_constant_to_string", "fused_in_try" ]);
+    }
+
     #[test]
     fn for_loop() {
         execute("tests/for_loop.luck", &[ "simple", "nests", "break_loop", "array", "for_model" ]);
     }
 
+    #[test]
+    fn while_loop() {
+        execute("tests/while_loop.luck", &[ "simple", "false_condition", "break_loop", "nests" ]);
+    }
+
     #[test]
     fn error_handling() {
-        execute("tests/error_handling.luck", &[ "in_same_function", "in_child_function" ]);
+        execute("tests/error_handling.luck", &[ "in_same_function", "in_child_function", "error_object", "error_object_in_child_function", "rescue_with_statement_at_next_line", "raise_in_same_function", "raise_in_child_function" ]);
+    }
+
+    #[test]
+    fn try_block() {
+        execute("tests/try_block.luck", &[ "simple", "without_error", "error_object", "value_of_try", "nests", "error_in_expression", "ensure_without_error", "ensure_after_rescue", "ensure_with_error", "ensure_with_error_in_rescue", "ensure_when_return", "ensure_when_break", "keep_stack_after_ensure", "with_function_rescue" ]);
+    }
+
+    #[test]
+    fn raise_to_host() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/error_handling.luck").unwrap();
+
+        let error = state.call("raise_to_host", &[]).unwrap_err();
+
+        assert!(matches!(error.value, Some(Object::Integer(42))), "raised value is not returned to host");
+        assert_eq!(error.message, "42");
+    }
+
+    #[test]
+    fn native_function_with_captures() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/native_function.luck").unwrap();
+
+        let offset = 10;
+        state.add_native_function("add_offset", move |_, parameters| Ok(Object::Integer(parameters[0].integer_value()? + offset)));
+
+        let mut count = 0;
+        state.add_native_function_mut("counter", move |_, _| {
+            count += 1;
+            Ok(Object::Integer(count))
+        });
+
+        let records = make_reference(Vec::new());
+        let records_in_native = records.clone();
+        state.add_native_function("record", move |_, parameters| {
+            records_in_native.borrow_mut().push(parameters[0].to_string());
+            Ok(Object::Null)
+        });
+
+        state.add_native_function_mut("run", |state, parameters| state.execute_by_object(parameters[0].clone(), &[]));
+
+        execute_function(&mut state, "call_counter");
+        execute_function(&mut state, "add_with_offset");
+        execute_function(&mut state, "record_values");
+
+        assert_eq!(records.borrow().deref(), &vec![ "1".to_string(), "two".to_string() ]);
+
+        let error = state.call("call_again", &[]).unwrap_err();
+
+        assert_eq!(error.message, "native function is already running");
+    }
+
+    #[test]
+    fn typed_native_function() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/native_function.luck").unwrap();
+
+        state.add_typed_function("repeat", |value: String, count: i64| value.repeat(count as usize));
+        state.add_typed_function("sum", |values: Vec<i64>| values.iter().sum::<i64>());
+        state.add_typed_function("half", |value: f64| value / 2.0);
+        state.add_typed_function("or_zero", |value: Option<i64>| value.unwrap_or(0));
+        state.add_typed_function("parse", |value: String| value.parse::<i64>());
+
+        execute_function(&mut state, "typed_parameters");
+
+        let errors: Vec<(String, u16)> = [ "typed_wrong_type", "typed_too_many_parameters", "typed_error" ].iter().map(|function_name| {
+            let error = state.call(function_name, &[]).unwrap_err();
+            (error.message, error.position.line)
+        }).collect();
+
+        assert_eq!(errors, vec![
+            ("parameter 1 need String, got Integer".to_string(), 28),
+            ("need 2 parameters, got 3".to_string(), 32),
+            ("invalid digit found in string".to_string(), 36)
+        ]);
+    }
+
+    struct CallBack {
+        value: i64
+    }
+
+    impl NativeModelInstance for CallBack {
+        fn index_get(&self, _this: Reference<dyn NativeModelInstance>, _index: &Object) -> Result<Object, RuntimeError> {
+            Ok(Object::Null)
+        }
+
+        fn index_set(&mut self, _this: Reference<dyn NativeModelInstance>, _index: &Object, _value: Object) -> Result<(), RuntimeError> {
+            Ok(())
+        }
+
+        fn instance_get(&self, this: Reference<dyn NativeModelInstance>, key: &str) -> Result<Object, RuntimeError> {
+            match key {
+                "value" => Ok(Object::Integer(self.value)),
+                _ => Ok(Object::InstanceNativeFunction(this, key.to_string()))
+            }
+        }
+
+        fn instance_set(&mut self, _this: Reference<dyn NativeModelInstance>, _key: &str, _value: Object) -> Result<(), RuntimeError> {
+            Ok(())
+        }
+
+        // script gets the instance while this method still borrows it
+        fn call(&mut self, this: Reference<dyn NativeModelInstance>, state: &mut State, _key: &str, _parameters: &[Object]) -> Result<Object, RuntimeError> {
+            state.call("touch_this", &[ Object::NativeInstance(this) ])
+        }
+    }
+
+    #[test]
+    fn native_instance_called_back_by_script() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/native_function.luck").unwrap();
+
+        let instance: Reference<dyn NativeModelInstance> = make_reference(CallBack { value: 1 });
+
+        // reads without the running call are fine
+        assert!(matches!(state.call("touch_this", &[ Object::NativeInstance(instance.clone()) ]), Ok(Object::Integer(1))));
+
+        // fails instead of waiting for the borrow held by itself
+        let error = state.call("call_back_to_this", &[ Object::NativeInstance(instance.clone()) ]).unwrap_err();
+        assert_eq!(error.message, "native instance is already borrowed");
+
+        assert!(matches!(state.call("touch_this", &[ Object::NativeInstance(instance) ]), Ok(Object::Integer(1))));
+    }
+
+    #[test]
+    fn call_by_name() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/host.luck").unwrap();
+
+        state.set_global("total", Object::Integer(0));
+        state.set_global("scale", Object::Integer(10));
+
+        state.call("on_update", &[ Object::Integer(1) ]).unwrap();
+        let result = state.call("on_update", &[ Object::Integer(2) ]).unwrap();
+
+        assert!(matches!(result, Object::Integer(30)));
+        assert!(matches!(state.get_global("total"), Some(Object::Integer(30))));
+        assert!(state.get_global("not_exists").is_none());
+
+        let point = state.call("Point", &[ Object::Integer(1), Object::Integer(2) ]).unwrap();
+        assert!(matches!(state.get_object_property_by_name(point, "y"), Ok(Object::Integer(2))));
+
+        assert_eq!(state.call("not_exists", &[]).unwrap_err().message, "can not found function [not_exists]");
+
+        let program = state.get_program();
+        assert!(program.find_function("on_update").is_some());
+        assert!(program.find_function("Point").is_none());
+        assert!(program.find_model("Point").is_some());
+    }
+
+    #[test]
+    fn find_function_in_stripped_program() {
+        let clover = Clover::new();
+        let mut program = clover.compile_file("tests/host.luck").unwrap();
+        program.strip_debug_info();
+
+        let program = reload_program(&program);
+        assert!(program.find_function("on_update").is_some());
+        assert!(program.find_model("Point").is_some());
+    }
+
+    #[test]
+    fn rescue_error_from_nested_call() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/host.luck").unwrap();
+
+        state.add_native_function("run", |state, parameters| state.call(&parameters[0].string_value()?, &[]));
+        state.add_native_function("run_and_rescue", |state, parameters| {
+            match state.call(&parameters[0].string_value()?, &[]) {
+                Ok(object) => Ok(object),
+                Err(error) => Ok(Object::String(make_reference(error.message)))
+            }
+        });
+
+        execute_function(&mut state, "rescue_error_from_callback");
+        execute_function(&mut state, "error_handled_by_callback_caller");
+    }
+
+    #[test]
+    fn resume_after_refuel() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/budget.luck").unwrap();
+
+        state.set_fuel(Some(100));
+
+        let error = state.call("sum_to", &[ Object::Integer(1000) ]).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::OutOfFuel);
+        assert!(state.is_suspended());
+        assert_eq!(state.get_fuel(), Some(0));
+
+        // still out of fuel
+        assert_eq!(state.resume().unwrap_err().kind, RuntimeErrorKind::OutOfFuel);
+
+        state.set_fuel(None);
+        assert!(matches!(state.resume(), Ok(Object::Integer(499500))));
+        assert!(!state.is_suspended());
+        assert!(state.resume().is_err());
+    }
+
+    #[test]
+    fn out_of_fuel_can_not_be_rescued() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/budget.luck").unwrap();
+
+        state.set_fuel(Some(100));
+        assert_eq!(state.call("rescue_out_of_fuel", &[]).unwrap_err().kind, RuntimeErrorKind::OutOfFuel);
+
+        state.set_fuel(Some(100_000));
+        assert!(matches!(state.resume(), Ok(Object::Integer(499500))));
+    }
+
+    #[test]
+    fn out_of_fuel_in_nested_execution() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/budget.luck").unwrap();
+
+        state.add_native_function("run", |state, parameters| state.call(&parameters[0].string_value()?, &parameters[1..]));
+        state.set_fuel(Some(100));
+
+        let error = state.call("call_native_with_loop", &[]).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::OutOfFuel);
+        assert!(!state.is_suspended());
+
+        state.set_fuel(None);
+        assert!(matches!(state.call("call_native_with_loop", &[]), Ok(Object::Integer(499500))));
+    }
+
+    #[test]
+    fn interrupt_from_other_thread() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/budget.luck").unwrap();
+
+        let handle = state.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            handle.interrupt();
+        });
+
+        let error = state.call("sum_to", &[ Object::Integer(i64::MAX) ]).unwrap_err();
+        thread.join().unwrap();
+
+        assert_eq!(error.kind, RuntimeErrorKind::Interrupted);
+        assert!(state.is_suspended());
+
+        state.abort();
+        assert!(!state.is_suspended());
+        assert!(matches!(state.call("sum_to", &[ Object::Integer(10) ]), Ok(Object::Integer(45))));
+    }
+
+    #[test]
+    fn frame_limit() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/limits.luck").unwrap();
+
+        state.set_limits(Limits { max_frames: Some(100), ..Limits::default() });
+        assert!(matches!(state.call("recursive", &[ Object::Integer(50) ]), Ok(Object::Integer(50))));
+
+        let error = state.call("recursive", &[ Object::Integer(1000) ]).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::LimitExceeded);
+        assert_eq!(error.message, "frame limit exceeded");
+
+        // the error can not be rescued and the state is still usable
+        assert_eq!(state.call("rescue_limit", &[]).unwrap_err().kind, RuntimeErrorKind::LimitExceeded);
+        assert!(!state.is_suspended());
+        assert!(matches!(state.call("recursive", &[ Object::Integer(10) ]), Ok(Object::Integer(10))));
+    }
+
+    #[test]
+    fn stack_limit() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/limits.luck").unwrap();
+
+        state.set_limits(Limits { max_stack_size: Some(200), ..Limits::default() });
+
+        let error = state.call("recursive", &[ Object::Integer(1000) ]).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::LimitExceeded);
+        assert_eq!(error.message, "stack limit exceeded");
+    }
+
+    #[test]
+    fn heap_limit() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/limits.luck").unwrap();
+
+        state.set_limits(Limits { max_heap_size: Some(1024 * 1024), ..Limits::default() });
+        assert!(matches!(state.call("grow_string", &[ Object::Integer(10) ]), Ok(Object::Integer(6144))));
+
+        let error = state.call("grow_string", &[ Object::Integer(64) ]).unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::LimitExceeded);
+        assert_eq!(error.message, "heap limit exceeded");
+
+        assert_eq!(state.call("grow_map", &[ Object::Integer(1_000_000) ]).unwrap_err().kind, RuntimeErrorKind::LimitExceeded);
+
+        // garbage is not counted after measuring the heap
+        assert!(matches!(state.call("make_garbage", &[ Object::Integer(100_000) ]), Ok(Object::Integer(100_000))));
+        assert!(state.heap_size() < 1024 * 1024);
+    }
+
+    #[test]
+    fn collect_cycles() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/cycle.luck").unwrap();
+
+        for function_name in [ "self_cycle", "array_cycle", "map_cycle", "closure_cycle" ] {
+            let object = state.call(function_name, &[]).unwrap();
+            let weak = match &object {
+                Object::Instance(instance) => Shared::downgrade(instance) as Weak<dyn Any>,
+                Object::Array(array) => Shared::downgrade(array) as Weak<dyn Any>,
+                Object::Map(map) => Shared::downgrade(map) as Weak<dyn Any>,
+                _ => panic!("{} returns {:?}", function_name, object)
+            };
+
+            // still referenced by host
+            state.collect_garbage();
+            assert!(weak.upgrade().is_some());
+
+            drop(object);
+            assert!(weak.upgrade().is_some(), "{} is not a cycle", function_name);
+
+            assert!(state.collect_garbage() > 0);
+            assert!(weak.upgrade().is_none(), "{} is not collected", function_name);
+        };
+    }
+
+    #[test]
+    fn keep_alive_cycle() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/cycle.luck").unwrap();
+
+        state.add_native_function("collect", |state, _| Ok(Object::Integer(state.collect_garbage() as i64)));
+        execute_function(&mut state, "keep_alive_cycle");
+    }
+
+    #[cfg(not(feature = "sync"))]
+    #[test]
+    fn collect_cycles_by_allocation() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/cycle.luck").unwrap();
+
+        assert!(matches!(state.call("make_cycles", &[ Object::Integer(100_000) ]), Ok(Object::Integer(100_000))));
+
+        // most of the cycles are already freed
+        assert!(state.collect_garbage() < 10_000);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn collect_cycles_only_by_host() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/cycle.luck").unwrap();
+
+        assert!(matches!(state.call("make_cycles", &[ Object::Integer(100_000) ]), Ok(Object::Integer(100_000))));
+
+        // objects may be used by other threads, cycles wait for the host
+        assert_eq!(state.collect_garbage(), 100_000);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn collect_cycles_while_other_thread_mutates() {
+        let clover = Clover::new();
+        let program = std::sync::Arc::new(clover.compile_file("tests/cycle.luck").unwrap());
+        let shared = make_reference((1..=4).map(|i| Object::Array(make_reference(vec![ Object::Integer(i) ]))).collect::<Vec<Object>>());
+
+        let mutator = {
+            let mut state: State = program.clone().into();
+            state.set_global("shared", Object::Array(shared.clone()));
+
+            std::thread::spawn(move || state.call("replace_shared", &[ Object::Integer(200_000) ]).unwrap().integer_value().unwrap())
+        };
+
+        let mut state: State = program.into();
+        state.set_global("shared", Object::Array(shared.clone()));
+
+        // cycles refer to the shared array, the collector walks into it while its items are replaced
+        let mut freed_count = 0;
+        while !mutator.is_finished() {
+            state.call("cycles_with_shared", &[ Object::Integer(100) ]).unwrap();
+            freed_count += state.collect_garbage();
+        };
+
+        assert_eq!(mutator.join().unwrap(), 200_000);
+
+        state.call("cycles_with_shared", &[ Object::Integer(100) ]).unwrap();
+        assert!(freed_count + state.collect_garbage() >= 100);
+
+        // the shared array and its items are alive, nothing is cleared
+        assert_eq!(shared.borrow().len(), 4);
+        for item in shared.borrow().iter() {
+            assert!(matches!(item, Object::Array(array) if array.borrow().len() == 1));
+        };
+    }
+
+    #[test]
+    fn share_program_between_states() {
+        let clover = Clover::new();
+        let program = Shared::new(clover.compile_file("tests/local.luck").unwrap());
+
+        let mut states: Vec<State> = (0..3).map(|_| program.clone().into()).collect();
+        states.push(states[0].get_shared_program().into());
+
+        // context locals belong to each state
+        for state in states.iter_mut() {
+            execute_function(state, "in_file");
+            execute_function(state, "in_file_again");
+        };
+
+        assert_eq!(Shared::strong_count(&program), 5);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn share_program_between_threads() {
+        let clover = Clover::new();
+        let program = std::sync::Arc::new(clover.compile_file("tests/budget.luck").unwrap());
+
+        let threads: Vec<_> = (1..=4).map(|count| {
+            let program = program.clone();
+
+            std::thread::spawn(move || {
+                let mut state: State = program.into();
+                state.call("sum_to", &[ Object::Integer(count * 10) ]).unwrap().integer_value().unwrap()
+            })
+        }).collect();
+
+        let results: Vec<i64> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(results, vec![ 45, 190, 435, 780 ]);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn share_object_between_threads() {
+        let clover = Clover::new();
+        let program = std::sync::Arc::new(clover.compile_file("tests/host.luck").unwrap());
+        let shared = Object::Array(make_reference((1..=4).map(Object::Integer).collect()));
+
+        // every state reads and writes the same array, the lock waits for the other threads
+        let threads: Vec<_> = (0..4).map(|_| {
+            let program = program.clone();
+            let shared = shared.clone();
+
+            std::thread::spawn(move || {
+                let mut state: State = program.into();
+                state.set_global("shared", shared);
+                state.call("sum_shared", &[ Object::Integer(2000) ]).unwrap().integer_value().unwrap()
+            })
+        }).collect();
+
+        let results: Vec<i64> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(results, vec![ 5000; 4 ]);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn move_state_to_other_thread() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/native_function.luck").unwrap();
+
+        let records = make_reference(Vec::new());
+        let records_in_native = records.clone();
+        state.add_native_function("record", move |_, parameters| {
+            records_in_native.borrow_mut().push(parameters[0].to_string());
+            Ok(Object::Null)
+        });
+
+        let thread = std::thread::spawn(move || {
+            execute_function(&mut state, "record_values");
+            state
+        });
+
+        let mut state = thread.join().unwrap();
+        execute_function(&mut state, "record_values");
+
+        assert_eq!(records.borrow().len(), 4);
+    }
+
+    fn reload_program(program: &Program) -> Program {
+        let mut buffer = Vec::new();
+        program.serialize(&mut buffer).unwrap();
+
+        Program::deserialize(&mut buffer.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn keep_debug_info_in_bytecode() {
+        let clover = Clover::new();
+        let program = reload_program(&clover.compile_file("tests/error_handling.luck").unwrap());
+
+        assert!(program.file_info.is_some(), "file info is lost after reload");
+        assert!(program.debug_info.is_some(), "debug info is lost after reload");
+
+        let mut state: State = program.into();
+        let error = state.call("error_to_host", &[]).unwrap_err();
+
+        assert_eq!(error.position.line, 10);
+        assert_eq!(error.traceback[0].function_name.as_deref(), Some("error_to_host"));
+    }
+
+    #[test]
+    fn strip_debug_info() {
+        let clover = Clover::new();
+        let mut program = clover.compile_file("tests/error_handling.luck").unwrap();
+        program.strip_debug_info();
+
+        let program = reload_program(&program);
+
+        assert!(program.file_info.is_none(), "file info is not stripped");
+        assert!(program.debug_info.is_none(), "debug info is not stripped");
+    }
+
+    #[test]
+    fn reject_invalid_bytecode() {
+        let clover = Clover::new();
+        let mut program = clover.compile_file("tests/error_handling.luck").unwrap();
+
+        let mut buffer = Vec::new();
+        program.serialize(&mut buffer).unwrap();
+
+        let mut wrong_header = buffer.clone();
+        wrong_header[0] = 0;
+        assert!(matches!(Program::deserialize(&mut wrong_header.as_slice()), Err(BytecodeError::InvalidHeader)));
+
+        let mut wrong_version = buffer.clone();
+        wrong_version[4] += 1;
+        assert!(matches!(Program::deserialize(&mut wrong_version.as_slice()), Err(BytecodeError::VersionMismatch(_))));
+
+        // the old layout has crate version 0.1.13 and a zero byte after the header
+        let mut old_layout = buffer[..4].to_vec();
+        old_layout.extend_from_slice(&[ 0, 1, 13, 0 ]);
+        old_layout.extend_from_slice(&buffer[7..]);
+        assert!(matches!(Program::deserialize(&mut old_layout.as_slice()), Err(BytecodeError::VersionMismatch(_))));
+
+        let truncated = &buffer[..buffer.len() / 2];
+        assert!(matches!(Program::deserialize(&mut &truncated[..]), Err(BytecodeError::Io(_))));
+
+        program.functions[0].instructions[0] = OpCode::PushConstant.to_instruction(program.constants.len() as u64);
+        assert!(matches!(program.verify(), Err(BytecodeError::InvalidInstruction(0, 0, _))));
+
+        program.functions[0].instructions[0] = OpCode::Pop.to_instruction(0);
+        assert!(matches!(program.verify(), Err(BytecodeError::InvalidInstruction(0, 0, _))));
+
+        let mut buffer = Vec::new();
+        program.serialize(&mut buffer).unwrap();
+        assert!(matches!(Program::deserialize(&mut buffer.as_slice()), Err(BytecodeError::InvalidInstruction(_, _, _))));
+
+        // constants which are not made by compiler can not be written
+        program.constants.push(Object::Array(make_reference(Vec::new())));
+        let error = program.serialize(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().ends_with("is Array which can not be serialized"), "{}", error);
+    }
+
+    #[test]
+    fn reject_function_constant_with_upvalues() {
+        let source = "entry 1\nlocals 0\nconstant 0 null\nconstant 1 true\nconstant 2 false\nconstant 3 function 0\n\nfunction 0\n    parameters 0\n    locals 0\n    PushConstant 0\n    Return\n\nfunction 1\n    parameters 0\n    locals 0\n    PushConstant 3\n    Call 0\n    Return\n";
+        let mut program = Program::assemble(source, "upvalue.lasm").unwrap();
+
+        // function 0 reads an upvalue, but it is called without a closure
+        program.functions[0].upvalues.push(Upvalue::Local(0));
+        program.functions[0].instructions[0] = OpCode::UpvalueGet.to_instruction(0);
+        assert!(matches!(program.verify(), Err(BytecodeError::InvalidData(_))));
+
+        let mut buffer = Vec::new();
+        program.serialize(&mut buffer).unwrap();
+        assert!(matches!(Program::deserialize(&mut buffer.as_slice()), Err(BytecodeError::InvalidData(_))));
+
+        let assemble_source = source.replace("    locals 0\n    PushConstant 0", "    locals 0\n    upvalue local 0\n    UpvalueGet 0");
+        assert!(Program::assemble(&assemble_source, "upvalue.lasm").is_err());
+
+        // a program which is not verified raises an error instead of panic
+        let mut state: State = program.into();
+        assert!(state.execute().is_err());
+    }
+
+    #[test]
+    fn disassemble_program() {
+        let clover = Clover::new();
+        let mut program = clover.compile_file("tests/function.luck").unwrap();
+
+        let listing = program.disassemble();
+        let lines: Vec<String> = listing.lines().map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" ")).collect();
+
+        assert!(lines.contains(&"file 1 \"tests/function.luck\"".to_string()));
+        assert!(lines.iter().any(|line| line.starts_with("model 0 Rectangle file 0")));
+
+        let factorial_index = program.find_function("factorial").unwrap();
+        let start = lines.iter().position(|line| line.starts_with(&format!("function {} factorial file 1", factorial_index))).unwrap();
+        let factorial_lines = &lines[start..start + lines[start..].iter().position(String::is_empty).unwrap_or(lines.len() - start)];
+
+        assert_eq!(factorial_lines[1], "parameters 1");
+        assert!(factorial_lines.iter().any(|line| line.starts_with("0 [4:20] PushConstant") && line.ends_with("; 1")));
+        assert!(factorial_lines.iter().any(|line| line.contains("LocalGet 0 ; parameter 0")));
+        assert!(factorial_lines.iter().any(|line| line.contains("Operation 2 ; *")));
+        assert!(factorial_lines.iter().any(|line| line.contains("JumpIf") && line.contains("; to ")));
+        assert_eq!(factorial_lines.len() - 3, program.functions[factorial_index].instructions.len());
+
+        // positions and names are gone with debug information
+        program.strip_debug_info();
+        let listing = program.disassemble();
+        assert!(!listing.contains('['));
+        assert!(!listing.contains("factorial file"));
+    }
+
+    #[test]
+    fn fold_constants() {
+        let clover = Clover::new();
+        let program = clover.compile_file("tests/constant_folding.luck").unwrap();
+        let opcodes = |name: &str| -> Vec<OpCode> {
+            program.functions[program.find_function(name).unwrap()].instructions.iter().map(|instruction| instruction.opcode()).collect()
+        };
+
+        // the whole expression is one constant at the position of its operator
+        let arithmetic_index = program.find_function("arithmetic").unwrap();
+        assert_eq!(opcodes("arithmetic"), vec![ OpCode::PushConstant, OpCode::Return ]);
+        assert_eq!(program.functions[arithmetic_index].instructions[0].operand() as usize, Program::TRUE_CONSTANT_INDEX);
+        assert_eq!(program.debug_info.as_ref().unwrap().functions[arithmetic_index][0].line, 2);
+
+        assert!(!opcodes("comparisons").contains(&OpCode::Operation));
+        assert!(!opcodes("comparisons").contains(&OpCode::Not));
+        assert!(!opcodes("dead_branches").contains(&OpCode::JumpIf));
+        // only the jump back of the loop is left
+        assert_eq!(opcodes("break_in_dead_branch").iter().filter(|&&opcode| opcode == OpCode::Jump).count(), 1);
+
+        // raises at runtime, so it is not folded
+        assert!(opcodes("divide_by_zero_at_runtime").contains(&OpCode::Operation));
+
+        // nothing of the dropped branches is left in the program, the only function which is not a top level one is the closure of dead_closure_in_closure
+        assert_eq!(program.functions.len(), program.definitions.len() + 1);
+        assert!(program.functions.iter().all(|function| function.upvalues.is_empty()));
+        assert!(!program.constants.iter().any(|constant| constant.to_string() == "dead string constant" || constant.to_string() == "dead_global_value"));
+        let dead_closure_index = program.find_function("dead_closure").unwrap();
+        assert!(!opcodes("dead_closure").contains(&OpCode::Closure));
+        assert!(program.functions[dead_closure_index].instructions.iter().all(|instruction| !matches!(instruction.opcode(), OpCode::CellInit | OpCode::CellGet)));
+        assert!(!opcodes("dead_closure_in_closure").contains(&OpCode::CellInit));
+    }
+
+    #[test]
+    fn fuse_instructions() {
+        let clover = Clover::new();
+        let mut program = clover.compile_file("tests/peephole.luck").unwrap();
+        let counting_loop_index = program.find_function("counting_loop").unwrap();
+        let opcodes: Vec<OpCode> = program.functions[counting_loop_index].instructions.iter().map(|instruction| instruction.opcode()).collect();
+
+        assert!(opcodes.contains(&OpCode::CompareJump));
+        assert!(opcodes.contains(&OpCode::JumpIfNot));
+        assert!(opcodes.contains(&OpCode::AddConstant));
+        assert!(!opcodes.contains(&OpCode::Pop));
+        assert!(!opcodes.contains(&OpCode::Not));
+
+        // the opcode set is written to the header
+        assert_eq!(program.opcode_set(), OPCODE_SET_FUSED);
+        let mut buffer = Vec::new();
+        program.serialize(&mut buffer).unwrap();
+        assert_eq!(buffer[6], OPCODE_SET_FUSED);
+
+        let mut newer_opcode_set = buffer.clone();
+        newer_opcode_set[6] = OPCODE_SET_FUSED + 1;
+        assert!(matches!(Program::deserialize(&mut newer_opcode_set.as_slice()), Err(BytecodeError::UnsupportedOpcodeSet(_))));
+
+        let mut base_opcode_set = buffer.clone();
+        base_opcode_set[6] = OPCODE_SET_BASE;
+        assert!(matches!(Program::deserialize(&mut base_opcode_set.as_slice()), Err(BytecodeError::InvalidData(_))));
+
+        // the jump after CompareJump is taken by it
+        let compare_index = opcodes.iter().position(|&opcode| opcode == OpCode::CompareJump).unwrap();
+        program.functions[counting_loop_index].instructions[compare_index + 1] = OpCode::Pop.to_instruction(0);
+        assert!(matches!(program.verify(), Err(BytecodeError::InvalidInstruction(_, _, _))));
+    }
+
+    #[test]
+    fn assemble_opcodes() {
+        let clover = Clover::new();
+        let program = clover.assemble_file("tests/opcodes.lasm").unwrap();
+
+        let mut state: State = reload_program(&program).into();
+
+        for function_name in [ "for_next", "context_set", "jump_by_label", "rescue_by_handler", "fused_opcodes" ] {
+            execute_function(&mut state, function_name);
+        };
+    }
+
+    #[test]
+    fn assemble_disassembled_program() {
+        let clover = Clover::new();
+        let program = clover.compile_file("tests/closure.luck").unwrap();
+
+        let listing = program.disassemble();
+        let assembled = Program::assemble(&listing, "closure.lasm").unwrap();
+
+        assert_eq!(assembled.disassemble(), listing);
+
+        let mut state: State = assembled.into();
+        for function_name in [ "anonymous", "capture", "capture_by_reference", "nested", "capture_in_loop" ] {
+            execute_function(&mut state, function_name);
+        };
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let errors = Program::assemble("function 0 main\n    Jump nowhere\n    Unknown\n    PushConstant 99\n", "error.lasm").unwrap_err();
+        let messages: Vec<&str> = errors.errors.iter().map(|error| error.message.as_str()).collect();
+
+        assert_eq!(messages, vec![ "unknown instruction", "label not found" ]);
+        assert_eq!(errors.errors[0].token.position.line, 3);
+
+        // the program is verified after assembled
+        assert!(Program::assemble("function 0 main\n    PushConstant 99\n    Return\n", "error.lasm").is_err());
+    }
+
+    #[test]
+    fn traceback_of_runtime_error() {
+        let clover = Clover::new();
+        let mut state = clover.create_state_by_filename("tests/error_handling.luck").unwrap();
+
+        let error = state.call("error_to_host", &[]).unwrap_err();
+
+        let message = error.to_string();
+        let lines: Vec<&str> = message.lines().collect();
+
+        assert_eq!(lines, vec![
+            "at (10, 7) - divide by zero",
+            "stack traceback:",
+            "    tests/error_handling.luck:10:7: in function 'function_with_error'",
+            "    tests/error_handling.luck:71:24: in function 'error_to_host'"
+        ]);
     }
 
     #[test]
@@ -144,6 +928,11 @@ mod tests {
         execute("tests/function.luck", &[ "recursive", "with_return", "first_class_function", "instance_first_class_function" ]);
     }
 
+    #[test]
+    fn closure() {
+        execute("tests/closure.luck", &[ "anonymous", "capture", "capture_by_reference", "modify_enclosing_local", "nested", "capture_parameter", "capture_in_loop" ]);
+    }
+
     #[test]
     fn include() {
         execute("tests/include.luck", &[ "include_function", "include_with_nickname", "include_model" ]);
@@ -154,6 +943,11 @@ mod tests {
         execute("tests/model.luck", &[ "regular", "with_apply" ]);
     }
 
+    #[test]
+    fn map() {
+        execute("tests/map.luck", &[ "literal", "set_value", "missing_key", "for_map", "nests", "expression_value", "typed_keys", "insertion_order", "insert_while_iterating", "length_entry", "evaluation_order" ]);
+    }
+
     #[test]
     fn local() {
         execute("tests/local.luck", &[ "in_file", "in_file_again", "in_function", "in_scope" ]);
@@ -163,4 +957,50 @@ mod tests {
     fn convert() {
         execute("tests/convert.luck", &[ "string_to_integer", "string_to_float", "integer_to_string", "integer_to_float", "float_to_string", "float_to_integer" ]);
     }
+
+    #[test]
+    fn repl_keeps_definitions() {
+        let mut repl = Repl::new();
+
+        assert!(matches!(repl.eval("function add(a, b)\n    a + b\nend"), Ok(Object::Null)));
+        assert!(matches!(repl.eval("add(1, 2)"), Ok(Object::Integer(3))));
+
+        assert!(repl.eval("model Point\n    x\n    y\nend\nimplement Point\n    function sum(this)\n        this.x + this.y\n    end\nend").is_ok());
+        assert!(repl.eval("local point = Point(3, 4)").is_ok());
+        assert!(matches!(repl.eval("point.sum()"), Ok(Object::Integer(7))));
+
+        // redefine a function, the functions compiled before use the new one too
+        assert!(repl.eval("function twice(a)\n    add(a, a)\nend").is_ok());
+        assert!(repl.eval("function add(a, b)\n    a * b\nend").is_ok());
+        assert!(matches!(repl.eval("twice(5)"), Ok(Object::Integer(25))));
+
+        assert!(matches!(repl.state_mut().call("twice", &[ Object::Integer(3) ]), Ok(Object::Integer(9))));
+    }
+
+    #[test]
+    fn repl_include() {
+        let mut repl = Repl::new();
+
+        assert!(repl.eval("include factorial from \"tests/function.luck\"").is_ok());
+        assert!(matches!(repl.eval("factorial(5)"), Ok(Object::Integer(120))));
+    }
+
+    #[test]
+    fn repl_errors() {
+        let mut repl = Repl::new();
+
+        assert!(repl.eval("local count = 1").is_ok());
+
+        // nothing is defined by the input which has error
+        let error = repl.eval("local other = 2\ncount = )").unwrap_err();
+        assert!(!error.is_incomplete());
+        assert!(repl.eval("other").is_err());
+
+        assert!(repl.eval("function unfinished()\n    1 +").unwrap_err().is_incomplete());
+        assert!(repl.eval("[ 1, 2").unwrap_err().is_incomplete());
+
+        assert!(matches!(repl.eval("1 / 0"), Err(ReplError::Runtime(_))));
+
+        assert!(matches!(repl.eval("count + 1"), Ok(Object::Integer(2))));
+    }
 }
@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::backend::compiler::{InteractiveCompiler, DefaultStorage, Storage};
+use crate::intermediate::CompileErrorList;
+use crate::runtime::object::Object;
+use crate::runtime::program::RuntimeError;
+use crate::runtime::state::State;
+
+#[derive(Debug)]
+pub enum ReplError {
+    Compile(CompileErrorList),
+    Runtime(RuntimeError)
+}
+
+impl ReplError {
+    // the input ends in the middle of a definition or statement, like a function without end
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, ReplError::Compile(errors) if errors.is_incomplete())
+    }
+}
+
+impl Display for ReplError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplError::Compile(errors) => errors.fmt(formatter),
+            ReplError::Runtime(error) => error.fmt(formatter)
+        }
+    }
+}
+
+impl Error for ReplError {}
+
+// keep one state alive and run the inputs in it, models, functions and locals defined by earlier inputs stay usable
+pub struct Repl {
+    compiler: InteractiveCompiler,
+    storage: Box<dyn Storage>,
+    state: State
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub const FILENAME: &'static str = "<repl>";
+
+    pub fn new_with_file_loader(storage: Box<dyn Storage>) -> Repl {
+        let compiler = InteractiveCompiler::new(Repl::FILENAME);
+        let state = compiler.to_program().into();
+
+        Repl {
+            compiler,
+            storage,
+            state
+        }
+    }
+
+    pub fn new() -> Repl {
+        Repl::new_with_file_loader(Box::new(DefaultStorage::new()))
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // use to add natives or globals
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    // the value of last expression in source
+    pub fn eval(&mut self, source: &str) -> Result<Object, ReplError> {
+        let function_index = self.compiler.compile(source, self.storage.as_ref()).map_err(ReplError::Compile)?;
+
+        self.state.update_program(self.compiler.to_program());
+
+        self.state.execute_by_function_index(function_index, &[]).map_err(ReplError::Runtime)
+    }
+}
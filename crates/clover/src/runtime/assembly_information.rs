@@ -1,4 +1,47 @@
-use crate::intermediate::Positions;
+use std::io::{Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::intermediate::{Position, Positions};
+use crate::runtime::program::{serialize_string, deserialize_string, BytecodeError};
+
+fn serialize_indices(indices: &[usize], writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    writer.write_u32::<LittleEndian>(indices.len() as u32)?;
+    for &index in indices {
+        writer.write_u32::<LittleEndian>(index as u32)?;
+    };
+
+    Ok(())
+}
+
+fn deserialize_indices(reader: &mut dyn Read) -> Result<Vec<usize>, BytecodeError> {
+    let count = reader.read_u32::<LittleEndian>()?;
+    let mut indices = Vec::new();
+
+    for _ in 0..count {
+        indices.push(reader.read_u32::<LittleEndian>()? as usize);
+    };
+
+    Ok(indices)
+}
+
+fn serialize_strings(strings: &[String], writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    writer.write_u32::<LittleEndian>(strings.len() as u32)?;
+    for string in strings {
+        serialize_string(string, writer)?;
+    };
+
+    Ok(())
+}
+
+fn deserialize_strings(reader: &mut dyn Read) -> Result<Vec<String>, BytecodeError> {
+    let count = reader.read_u32::<LittleEndian>()?;
+    let mut strings = Vec::new();
+
+    for _ in 0..count {
+        strings.push(deserialize_string(reader)?);
+    };
+
+    Ok(strings)
+}
 
 #[derive(Debug, Clone)]
 pub struct DebugInfo {
@@ -11,6 +54,41 @@ impl DebugInfo {
             functions: Vec::new()
         }
     }
+
+    pub fn serialize(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+        writer.write_u32::<LittleEndian>(self.functions.len() as u32)?;
+
+        for positions in &self.functions {
+            writer.write_u32::<LittleEndian>(positions.len() as u32)?;
+
+            for position in positions {
+                writer.write_u16::<LittleEndian>(position.line)?;
+                writer.write_u16::<LittleEndian>(position.column)?;
+            };
+        };
+
+        Ok(())
+    }
+
+    pub fn deserialize(reader: &mut dyn Read) -> Result<DebugInfo, BytecodeError> {
+        let mut debug_info = DebugInfo::new();
+        let function_count = reader.read_u32::<LittleEndian>()?;
+
+        for _ in 0..function_count {
+            let position_count = reader.read_u32::<LittleEndian>()?;
+            let mut positions = Positions::new();
+
+            for _ in 0..position_count {
+                let line = reader.read_u16::<LittleEndian>()?;
+                let column = reader.read_u16::<LittleEndian>()?;
+                positions.push(Position::new(line, column));
+            };
+
+            debug_info.functions.push(positions);
+        };
+
+        Ok(debug_info)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,4 +113,24 @@ impl FileInfo {
             function_names: Vec::new()
         }
     }
+
+    pub fn serialize(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+        serialize_strings(&self.filenames, writer)?;
+        serialize_indices(&self.model_files, writer)?;
+        serialize_strings(&self.model_names, writer)?;
+        serialize_indices(&self.function_files, writer)?;
+        serialize_strings(&self.function_names, writer)?;
+
+        Ok(())
+    }
+
+    pub fn deserialize(reader: &mut dyn Read) -> Result<FileInfo, BytecodeError> {
+        Ok(FileInfo {
+            filenames: deserialize_strings(reader)?,
+            model_files: deserialize_indices(reader)?,
+            model_names: deserialize_strings(reader)?,
+            function_files: deserialize_indices(reader)?,
+            function_names: deserialize_strings(reader)?
+        })
+    }
 }
\ No newline at end of file
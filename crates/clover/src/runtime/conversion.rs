@@ -0,0 +1,269 @@
+use std::fmt::Display;
+use std::ops::Deref;
+use crate::runtime::object::{Object, Reference, NativeModelInstance, NativeFunction, make_reference};
+use crate::debug::Position;
+use crate::runtime::program::RuntimeError;
+use crate::runtime::state::State;
+use crate::runtime::shared::{Shared, MaybeSync};
+
+// convert objects from script to rust values
+pub trait FromObject: Sized {
+    // name of the expected type in error messages
+    fn type_name() -> String;
+
+    fn from_object(object: &Object) -> Option<Self>;
+}
+
+// convert rust values to objects for script
+pub trait IntoObject {
+    fn into_object(self) -> Object;
+}
+
+impl FromObject for Object {
+    fn type_name() -> String { "Object".to_string() }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        Some(object.clone())
+    }
+}
+
+impl FromObject for i64 {
+    fn type_name() -> String { "Integer".to_string() }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        if let Object::Integer(value) = object {
+            Some(*value)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromObject for f64 {
+    fn type_name() -> String { "Float".to_string() }
+
+    // integer can be used as float, same as Object::float_value
+    fn from_object(object: &Object) -> Option<Self> {
+        match object {
+            Object::Float(value) => Some(*value),
+            Object::Integer(value) => Some(*value as f64),
+            _ => None
+        }
+    }
+}
+
+impl FromObject for bool {
+    fn type_name() -> String { "Boolean".to_string() }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        if let Object::Boolean(value) = object {
+            Some(*value)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromObject for String {
+    fn type_name() -> String { "String".to_string() }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        if let Object::String(value) = object {
+            Some(value.borrow().deref().clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: FromObject> FromObject for Vec<T> {
+    fn type_name() -> String { format!("Array of {}", T::type_name()) }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        if let Object::Array(array) = object {
+            array.borrow().iter().map(T::from_object).collect()
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: FromObject> FromObject for Option<T> {
+    fn type_name() -> String { format!("{} or Null", T::type_name()) }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        if object.is_null() {
+            Some(None)
+        } else {
+            T::from_object(object).map(Some)
+        }
+    }
+}
+
+impl FromObject for Reference<dyn NativeModelInstance> {
+    fn type_name() -> String { "Instance".to_string() }
+
+    fn from_object(object: &Object) -> Option<Self> {
+        if let Object::NativeInstance(instance) = object {
+            Some(instance.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl IntoObject for Object {
+    fn into_object(self) -> Object { self }
+}
+
+impl IntoObject for () {
+    fn into_object(self) -> Object { Object::Null }
+}
+
+impl IntoObject for i64 {
+    fn into_object(self) -> Object { Object::Integer(self) }
+}
+
+impl IntoObject for f64 {
+    fn into_object(self) -> Object { Object::Float(self) }
+}
+
+impl IntoObject for bool {
+    fn into_object(self) -> Object { Object::Boolean(self) }
+}
+
+impl IntoObject for String {
+    fn into_object(self) -> Object { Object::String(make_reference(self)) }
+}
+
+impl IntoObject for &str {
+    fn into_object(self) -> Object { Object::String(make_reference(self.to_string())) }
+}
+
+impl<T: IntoObject> IntoObject for Vec<T> {
+    fn into_object(self) -> Object {
+        Object::Array(make_reference(self.into_iter().map(IntoObject::into_object).collect()))
+    }
+}
+
+impl<T: IntoObject> IntoObject for Option<T> {
+    fn into_object(self) -> Object {
+        match self {
+            Some(value) => value.into_object(),
+            None => Object::Null
+        }
+    }
+}
+
+impl IntoObject for Reference<dyn NativeModelInstance> {
+    fn into_object(self) -> Object { Object::NativeInstance(self) }
+}
+
+// return value of typed functions, a value or a result with any displayable error
+pub trait IntoNativeResult {
+    fn into_native_result(self, state: &State) -> Result<Object, RuntimeError>;
+}
+
+impl<T: IntoObject> IntoNativeResult for T {
+    fn into_native_result(self, _state: &State) -> Result<Object, RuntimeError> {
+        Ok(self.into_object())
+    }
+}
+
+impl<T: IntoObject, E: Display> IntoNativeResult for Result<T, E> {
+    fn into_native_result(self, state: &State) -> Result<Object, RuntimeError> {
+        self.map(IntoObject::into_object).map_err(|error| RuntimeError::new(&error.to_string(), state.last_position()))
+    }
+}
+
+// return value of native constructors, an instance or a result with any displayable error
+pub trait IntoNativeInstance {
+    fn into_native_instance(self, state: &State) -> Result<Object, RuntimeError>;
+}
+
+impl<T: NativeModelInstance + 'static> IntoNativeInstance for T {
+    fn into_native_instance(self, _state: &State) -> Result<Object, RuntimeError> {
+        Ok(Object::NativeInstance(make_reference(self)))
+    }
+}
+
+impl<T: NativeModelInstance + 'static, E: Display> IntoNativeInstance for Result<T, E> {
+    fn into_native_instance(self, state: &State) -> Result<Object, RuntimeError> {
+        match self {
+            Ok(instance) => instance.into_native_instance(state),
+            Err(error) => Err(RuntimeError::new(&error.to_string(), state.last_position()))
+        }
+    }
+}
+
+pub fn expect_parameter_count(state: &State, parameters: &[Object], count: usize) -> Result<(), RuntimeError> {
+    if parameters.len() == count {
+        Ok(())
+    } else {
+        Err(RuntimeError::new(&format!("need {} parameters, got {}", count, parameters.len()), state.last_position()))
+    }
+}
+
+// optional parameters can be omitted, so only the upper bound is checked
+pub fn expect_max_parameter_count(state: &State, parameters: &[Object], count: usize) -> Result<(), RuntimeError> {
+    if parameters.len() <= count {
+        Ok(())
+    } else {
+        Err(RuntimeError::new(&format!("need {} parameters, got {}", count, parameters.len()), state.last_position()))
+    }
+}
+
+// convert the value assigned to a native property, there is no state when setting property
+pub fn expect_property<T: FromObject>(key: &str, value: &Object) -> Result<T, RuntimeError> {
+    T::from_object(value).ok_or_else(|| RuntimeError::new(&format!("property [{}] need {}, got {}", key, T::type_name(), value.type_name()), Position::none()))
+}
+
+// parameters missing at the end are null, so they can be Option
+pub fn expect_parameter<T: FromObject>(state: &State, parameters: &[Object], index: usize) -> Result<T, RuntimeError> {
+    let object = parameters.get(index).unwrap_or(&Object::Null);
+
+    if let Some(value) = T::from_object(object) {
+        return Ok(value);
+    };
+
+    let message = if index < parameters.len() {
+        format!("parameter {} need {}, got {}", index + 1, T::type_name(), object.type_name())
+    } else {
+        format!("parameter {} need {}, got nothing", index + 1, T::type_name())
+    };
+
+    Err(RuntimeError::new(&message, state.last_position()))
+}
+
+// rust functions which take FromObject parameters and return IntoNativeResult, Parameters is the tuple of parameter types
+pub trait TypedFunction<Parameters> {
+    fn call_typed(&self, state: &State, parameters: &[Object]) -> Result<Object, RuntimeError>;
+}
+
+macro_rules! impl_typed_function {
+    ($count:expr $(, $parameter:ident $index:tt)*) => {
+        impl<F, R $(, $parameter)*> TypedFunction<($($parameter,)*)> for F
+            where F: Fn($($parameter),*) -> R, R: IntoNativeResult $(, $parameter: FromObject)*
+        {
+            fn call_typed(&self, state: &State, parameters: &[Object]) -> Result<Object, RuntimeError> {
+                expect_max_parameter_count(state, parameters, $count)?;
+
+                self($(expect_parameter::<$parameter>(state, parameters, $index)?),*).into_native_result(state)
+            }
+        }
+    };
+}
+
+impl_typed_function!(0);
+impl_typed_function!(1, A 0);
+impl_typed_function!(2, A 0, B 1);
+impl_typed_function!(3, A 0, B 1, C 2);
+impl_typed_function!(4, A 0, B 1, C 2, D 3);
+impl_typed_function!(5, A 0, B 1, C 2, D 3, E 4);
+impl_typed_function!(6, A 0, B 1, C 2, D 3, E 4, G 5);
+
+pub fn make_typed_function<F, Parameters>(function: F) -> NativeFunction
+    where F: TypedFunction<Parameters> + MaybeSync + 'static, Parameters: 'static
+{
+    Shared::new(move |state: &mut State, parameters: &[Object]| function.call_typed(state, parameters))
+}
@@ -0,0 +1,246 @@
+use std::fmt::{self, Display, Formatter};
+use crate::runtime::program::{Program, Function, Upvalue};
+use crate::runtime::opcode::{OpCode, Instruction};
+use crate::runtime::object::Object;
+use crate::runtime::opcode::{OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, OPERATION_DIVIDE, OPERATION_MOD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL, OPERATION_AND, OPERATION_OR};
+
+pub fn operation_symbol(operand: usize) -> Option<&'static str> {
+    match operand {
+        OPERATION_ADD => Some("+"),
+        OPERATION_SUB => Some("-"),
+        OPERATION_MULTIPLY => Some("*"),
+        OPERATION_DIVIDE => Some("/"),
+        OPERATION_MOD => Some("%"),
+        OPERATION_EQUAL => Some("=="),
+        OPERATION_GREATER => Some(">"),
+        OPERATION_LESS => Some("<"),
+        OPERATION_GREATER_EQUAL => Some(">="),
+        OPERATION_LESS_EQUAL => Some("<="),
+        OPERATION_AND => Some("and"),
+        OPERATION_OR => Some("or"),
+        _ => None
+    }
+}
+
+// the text of a constant, strings are quoted and floats always have a dot or exponent, so the type can be told
+pub fn constant_to_string(constant: &Object) -> String {
+    match constant {
+        Object::Integer(value) => value.to_string(),
+        Object::Float(value) => format!("{:?}", value),
+        Object::String(value) => format!("{:?}", value.borrow().as_str()),
+        Object::Function(function_index) => format!("function {}", function_index),
+        Object::Model(model_index) => format!("model {}", model_index),
+        _ => constant.to_string()
+    }
+}
+
+// a readable listing of the whole program, every table of the program is written so it can be assembled back
+pub struct Disassembly<'a>(pub &'a Program);
+
+impl<'a> Disassembly<'a> {
+    fn function_name(&self, function_index: usize) -> Option<&'a str> {
+        self.0.file_info.as_ref().and_then(|file_info| file_info.function_names.get(function_index)).map(String::as_str)
+    }
+
+    fn model_name(&self, model_index: usize) -> Option<&'a str> {
+        self.0.file_info.as_ref().and_then(|file_info| file_info.model_names.get(model_index)).map(String::as_str)
+    }
+
+    fn filename(&self, file_index: usize) -> Option<&'a str> {
+        self.0.file_info.as_ref().and_then(|file_info| file_info.filenames.get(file_index)).map(String::as_str)
+    }
+
+    // constant text with the name of function or model
+    fn describe_constant(&self, constant_index: usize) -> String {
+        let constant = if let Some(constant) = self.0.constants.get(constant_index) { constant } else {
+            return "invalid constant".to_string();
+        };
+
+        let name = match constant {
+            Object::Function(function_index) => self.function_name(*function_index),
+            Object::Model(model_index) => self.model_name(*model_index),
+            _ => None
+        };
+
+        match name {
+            Some(name) => format!("{} {}", constant_to_string(constant), name),
+            None => constant_to_string(constant)
+        }
+    }
+
+    fn describe_local(&self, function: &Function, local_index: usize) -> String {
+        if function.is_instance && local_index == 0 {
+            "this".to_string()
+        } else if local_index < function.parameter_count {
+            format!("parameter {}", local_index)
+        } else {
+            format!("local {}", local_index)
+        }
+    }
+
+    fn describe_operand(&self, function: &Function, instruction: Instruction) -> Option<String> {
+        let operand = instruction.operand() as usize;
+
+        match instruction.opcode() {
+            OpCode::PushConstant | OpCode::AddConstant => Some(self.describe_constant(operand)),
+            OpCode::GlobalGet | OpCode::GlobalSet => Some(match self.0.constants.get(operand) {
+                Some(Object::String(name)) => name.borrow().to_string(),
+                _ => "invalid global name".to_string()
+            }),
+            OpCode::LocalGet | OpCode::LocalSet | OpCode::LocalInit | OpCode::CellGet | OpCode::CellSet | OpCode::CellInit | OpCode::ForNext | OpCode::Iterate => Some(self.describe_local(function, operand)),
+            OpCode::ContextGet | OpCode::ContextSet => Some(match self.0.local_values.get(&operand) {
+                Some(&constant_index) => format!("context {} = {}", operand, self.describe_constant(constant_index)),
+                None => format!("context {}", operand)
+            }),
+            OpCode::UpvalueGet | OpCode::UpvalueSet => Some(match function.upvalues.get(operand) {
+                Some(Upvalue::Local(index)) => format!("upvalue {} from local {}", operand, index),
+                Some(Upvalue::Upvalue(index)) => format!("upvalue {} from upvalue {}", operand, index),
+                None => "invalid upvalue".to_string()
+            }),
+            OpCode::Operation | OpCode::CompareJump => Some(operation_symbol(operand).unwrap_or("invalid operation").to_string()),
+            OpCode::Closure => Some(match self.function_name(operand) {
+                Some(name) => format!("function {} {}", operand, name),
+                None => format!("function {}", operand)
+            }),
+            OpCode::Jump | OpCode::JumpIf | OpCode::JumpIfNot => Some(format!("to {}", operand)),
+            _ => None
+        }
+    }
+
+    fn fmt_header(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        let program = self.0;
+
+        writeln!(formatter, "; opcode set {}", program.opcode_set())?;
+        writeln!(formatter, "entry {}", program.entry_point)?;
+        writeln!(formatter, "locals {}", program.local_count)?;
+
+        let mut local_values: Vec<(&usize, &usize)> = program.local_values.iter().collect();
+        local_values.sort();
+        for (local_index, &constant_index) in local_values {
+            writeln!(formatter, "local {} {} ; {}", local_index, constant_index, self.describe_constant(constant_index))?;
+        };
+
+        let mut definitions: Vec<(&String, &usize)> = program.definitions.iter().collect();
+        definitions.sort();
+        for (name, constant_index) in definitions {
+            writeln!(formatter, "definition {} {}", name, constant_index)?;
+        };
+
+        let mut global_dependencies = program.global_dependencies.clone();
+        global_dependencies.sort_unstable();
+        for global_index in global_dependencies {
+            writeln!(formatter, "global {} ; {}", global_index, self.describe_constant(global_index))?;
+        };
+
+        if let Some(file_info) = &program.file_info {
+            writeln!(formatter)?;
+            for (file_index, filename) in file_info.filenames.iter().enumerate() {
+                writeln!(formatter, "file {} {:?}", file_index, filename)?;
+            };
+        };
+
+        writeln!(formatter)?;
+        for (constant_index, constant) in program.constants.iter().enumerate() {
+            writeln!(formatter, "constant {} {}", constant_index, constant_to_string(constant))?;
+        };
+
+        Ok(())
+    }
+
+    fn fmt_models(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        for (model_index, model) in self.0.models.iter().enumerate() {
+            writeln!(formatter)?;
+
+            write!(formatter, "model {}", model_index)?;
+            if let Some(name) = self.model_name(model_index) {
+                write!(formatter, " {}", name)?;
+            };
+            if let Some(file_info) = &self.0.file_info {
+                if let Some(&file_index) = file_info.model_files.get(model_index) {
+                    write!(formatter, " file {} ; {}", file_index, self.filename(file_index).unwrap_or_default())?;
+                };
+            };
+            writeln!(formatter)?;
+
+            for property_name in model.property_names.iter() {
+                writeln!(formatter, "    property {}", property_name.borrow())?;
+            };
+
+            let mut functions: Vec<(&String, &usize)> = model.functions.iter().collect();
+            functions.sort();
+            for (name, function_index) in functions {
+                writeln!(formatter, "    method {} {}", name, function_index)?;
+            };
+        };
+
+        Ok(())
+    }
+
+    fn fmt_function(&self, formatter: &mut Formatter<'_>, function_index: usize, function: &Function) -> fmt::Result {
+        write!(formatter, "function {}", function_index)?;
+        if let Some(name) = self.function_name(function_index) {
+            write!(formatter, " {}", name)?;
+        };
+        if let Some(file_info) = &self.0.file_info {
+            if let Some(&file_index) = file_info.function_files.get(function_index) {
+                write!(formatter, " file {} ; {}", file_index, self.filename(file_index).unwrap_or_default())?;
+            };
+        };
+        writeln!(formatter)?;
+
+        writeln!(formatter, "    parameters {}", function.parameter_count)?;
+        writeln!(formatter, "    locals {}", function.local_count)?;
+        if function.is_instance {
+            writeln!(formatter, "    instance")?;
+        };
+
+        for upvalue in function.upvalues.iter() {
+            match upvalue {
+                Upvalue::Local(index) => writeln!(formatter, "    upvalue local {}", index)?,
+                Upvalue::Upvalue(index) => writeln!(formatter, "    upvalue upvalue {}", index)?
+            };
+        };
+
+        for handler in function.handlers.iter() {
+            writeln!(formatter, "    handler {} {} {} {} ; protect [{}, {}) jump to {} with stack size {}", handler.start, handler.end, handler.target, handler.stack_size, handler.start, handler.end, handler.target, handler.stack_size)?;
+        };
+
+        let positions = self.0.debug_info.as_ref().and_then(|debug_info| debug_info.functions.get(function_index));
+
+        for (instruction_index, instruction) in function.instructions.iter().enumerate() {
+            write!(formatter, "    {:>4}", instruction_index)?;
+
+            if let Some(position) = positions.and_then(|positions| positions.get(instruction_index)) {
+                write!(formatter, "  {:<9}", format!("[{}:{}]", position.line, position.column))?;
+            };
+
+            let opcode = format!("{:?}", instruction.opcode());
+
+            match self.describe_operand(function, *instruction) {
+                Some(description) => writeln!(formatter, "  {:<14}{:<8}; {}", opcode, instruction.operand(), description)?,
+                None if instruction.operand() != 0 => writeln!(formatter, "  {:<14}{}", opcode, instruction.operand())?,
+                None => writeln!(formatter, "  {}", opcode)?
+            };
+        };
+
+        Ok(())
+    }
+}
+
+impl<'a> Display for Disassembly<'a> {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_header(formatter)?;
+        self.fmt_models(formatter)?;
+
+        for (function_index, function) in self.0.functions.iter().enumerate() {
+            writeln!(formatter)?;
+            self.fmt_function(formatter, function_index, function)?;
+        };
+
+        Ok(())
+    }
+}
+
+pub fn disassemble(program: &Program) -> String {
+    Disassembly(program).to_string()
+}
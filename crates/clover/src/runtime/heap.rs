@@ -0,0 +1,312 @@
+use std::collections::{HashMap, HashSet};
+use indexmap::IndexMap;
+use std::collections::hash_map::Entry;
+use std::mem::size_of;
+use crate::runtime::object::{Object, Reference, Closure, ModelInstance, MapKey};
+use crate::runtime::shared::{Shared, Lock, Weak};
+
+// approximate bytes owned by the object itself, the objects it refers to are not included
+pub fn object_size(object: &Object) -> usize {
+    match object {
+        Object::String(value) => value.borrow().len(),
+        Object::Array(array) => array.borrow().len() * size_of::<Object>(),
+        Object::Map(map) => map.borrow().keys().map(|key| key.size() + size_of::<Object>()).sum(),
+        Object::Instance(instance) => instance.borrow().properties.len() * size_of::<Object>(),
+        Object::Closure(closure) => closure.borrow().upvalues.len() * size_of::<Reference<Object>>(),
+        Object::NativeInstance(instance) => instance.try_borrow().map(|instance| std::mem::size_of_val(&*instance)).unwrap_or(0),
+        Object::Error(error) => error.try_borrow().map(|error| size_of::<Object>() + error.message.len()).unwrap_or(0),
+        _ => 0
+    }
+}
+
+// sum the sizes of objects reachable from roots, an object referenced several times is counted once
+#[derive(Default)]
+pub struct HeapMeasure {
+    visited: HashSet<*const ()>,
+    // objects to visit, a list instead of recursion so deeply nested objects can not overflow the native stack
+    pending: Vec<Object>,
+    pub size: usize
+}
+
+impl HeapMeasure {
+    fn visit<T: ?Sized>(&mut self, reference: &Reference<T>) -> bool {
+        self.visited.insert(Shared::as_ptr(reference) as *const ())
+    }
+
+    pub fn add_object(&mut self, object: &Object) {
+        self.pending.push(object.clone());
+
+        while let Some(object) = self.pending.pop() {
+            self.measure(&object);
+        };
+    }
+
+    pub fn add_cell(&mut self, cell: &Reference<Object>) {
+        if self.visit(cell) {
+            self.size += size_of::<Object>();
+            let object = cell.borrow().clone();
+            self.add_object(&object);
+        };
+    }
+
+    pub fn add_closure(&mut self, closure: &Reference<Closure>) {
+        self.add_object(&Object::Closure(closure.clone()));
+    }
+
+    fn measure(&mut self, object: &Object) {
+        let first_visit = match object {
+            Object::String(value) => self.visit(value),
+            Object::Array(array) => self.visit(array),
+            Object::Map(map) => self.visit(map),
+            Object::Instance(instance) => self.visit(instance),
+            Object::Closure(closure) => self.visit(closure),
+            Object::NativeInstance(instance) => self.visit(instance),
+            Object::Error(error) => self.visit(error),
+            Object::InstanceFunction(this, _) => {
+                self.pending.push(this.as_ref().clone());
+                false
+            },
+            _ => false
+        };
+
+        if !first_visit {
+            return;
+        };
+
+        self.size += object_size(object);
+
+        match object {
+            Object::Array(array) => self.pending.extend(array.borrow().iter().cloned()),
+            Object::Map(map) => self.pending.extend(map.borrow().values().cloned()),
+            Object::Instance(instance) => self.pending.extend(instance.borrow().properties.iter().cloned()),
+            Object::Error(error) => self.pending.extend(error.borrow().value.clone()),
+            Object::Closure(closure) => {
+                for upvalue in closure.borrow().upvalues.iter() {
+                    if self.visit(upvalue) {
+                        self.size += size_of::<Object>();
+                        self.pending.push(upvalue.borrow().clone());
+                    };
+                };
+            },
+            _ => {}
+        };
+    }
+}
+
+// objects which can refer to other objects, a cycle of them is never freed by reference counting
+#[derive(Clone)]
+enum Node {
+    Array(Reference<Vec<Object>>),
+    Map(Reference<IndexMap<MapKey, Object>>),
+    Instance(Reference<ModelInstance>),
+    Closure(Reference<Closure>),
+    // captured local shared by closures
+    Cell(Reference<Object>)
+}
+
+enum WeakNode {
+    Array(Weak<Lock<Vec<Object>>>),
+    Map(Weak<Lock<IndexMap<MapKey, Object>>>),
+    Instance(Weak<Lock<ModelInstance>>),
+    Closure(Weak<Lock<Closure>>),
+    Cell(Weak<Lock<Object>>)
+}
+
+impl Node {
+    fn from_object(object: &Object) -> Option<Node> {
+        match object {
+            Object::Array(array) => Some(Node::Array(array.clone())),
+            Object::Map(map) => Some(Node::Map(map.clone())),
+            Object::Instance(instance) => Some(Node::Instance(instance.clone())),
+            Object::Closure(closure) => Some(Node::Closure(closure.clone())),
+            Object::InstanceFunction(this, _) => Node::from_object(this),
+            _ => None
+        }
+    }
+
+    fn address(&self) -> *const () {
+        match self {
+            Node::Array(array) => Shared::as_ptr(array) as *const (),
+            Node::Map(map) => Shared::as_ptr(map) as *const (),
+            Node::Instance(instance) => Shared::as_ptr(instance) as *const (),
+            Node::Closure(closure) => Shared::as_ptr(closure) as *const (),
+            Node::Cell(cell) => Shared::as_ptr(cell) as *const ()
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Array(array) => Shared::strong_count(array),
+            Node::Map(map) => Shared::strong_count(map),
+            Node::Instance(instance) => Shared::strong_count(instance),
+            Node::Closure(closure) => Shared::strong_count(closure),
+            Node::Cell(cell) => Shared::strong_count(cell)
+        }
+    }
+
+    fn downgrade(&self) -> WeakNode {
+        match self {
+            Node::Array(array) => WeakNode::Array(Shared::downgrade(array)),
+            Node::Map(map) => WeakNode::Map(Shared::downgrade(map)),
+            Node::Instance(instance) => WeakNode::Instance(Shared::downgrade(instance)),
+            Node::Closure(closure) => WeakNode::Closure(Shared::downgrade(closure)),
+            Node::Cell(cell) => WeakNode::Cell(Shared::downgrade(cell))
+        }
+    }
+
+    // None if the node is borrowed, its children can not be known
+    fn children(&self) -> Option<Vec<Node>> {
+        let children = match self {
+            Node::Array(array) => array.try_borrow().ok()?.iter().filter_map(Node::from_object).collect(),
+            Node::Map(map) => map.try_borrow().ok()?.values().filter_map(Node::from_object).collect(),
+            Node::Instance(instance) => instance.try_borrow().ok()?.properties.iter().filter_map(Node::from_object).collect(),
+            Node::Closure(closure) => closure.try_borrow().ok()?.upvalues.iter().map(|cell| Node::Cell(cell.clone())).collect(),
+            Node::Cell(cell) => Node::from_object(&*cell.try_borrow().ok()?).into_iter().collect()
+        };
+
+        Some(children)
+    }
+
+    // break the cycle, the objects taken out are dropped by caller after all nodes are cleared
+    fn clear(&self, objects: &mut Vec<Object>, cells: &mut Vec<Reference<Object>>) {
+        match self {
+            Node::Array(array) => objects.append(&mut array.borrow_mut()),
+            Node::Map(map) => objects.extend(map.borrow_mut().drain(..).map(|(_, object)| object)),
+            Node::Instance(instance) => objects.append(&mut instance.borrow_mut().properties),
+            Node::Closure(closure) => cells.append(&mut closure.borrow_mut().upvalues),
+            Node::Cell(cell) => objects.push(std::mem::replace(&mut *cell.borrow_mut(), Object::Null))
+        };
+    }
+}
+
+impl WeakNode {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            WeakNode::Array(array) => array.upgrade().map(Node::Array),
+            WeakNode::Map(map) => map.upgrade().map(Node::Map),
+            WeakNode::Instance(instance) => instance.upgrade().map(Node::Instance),
+            WeakNode::Closure(closure) => closure.upgrade().map(Node::Closure),
+            WeakNode::Cell(cell) => cell.upgrade().map(Node::Cell)
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            WeakNode::Array(array) => array.strong_count() > 0,
+            WeakNode::Map(map) => map.strong_count() > 0,
+            WeakNode::Instance(instance) => instance.strong_count() > 0,
+            WeakNode::Closure(closure) => closure.strong_count() > 0,
+            WeakNode::Cell(cell) => cell.strong_count() > 0
+        }
+    }
+}
+
+// collection starts when this count of objects is tracked
+const MIN_COLLECT_THRESHOLD: usize = 4096;
+
+// find cycles of objects which are only referenced by each other, references from anywhere else
+// like stack, globals, natives or host are not known, they are found by the difference of reference counts
+pub struct CycleCollector {
+    tracked: Vec<WeakNode>,
+    threshold: usize
+}
+
+impl Default for CycleCollector {
+    fn default() -> Self {
+        CycleCollector {
+            tracked: Vec::new(),
+            threshold: MIN_COLLECT_THRESHOLD
+        }
+    }
+}
+
+impl CycleCollector {
+    pub fn track(&mut self, object: &Object) {
+        if let Some(node) = Node::from_object(object) {
+            self.tracked.push(node.downgrade());
+        };
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.tracked.len() >= self.threshold
+    }
+
+    // forget the freed objects without collecting, the weak references keep their memory
+    pub fn prune(&mut self) {
+        self.tracked.retain(WeakNode::is_alive);
+        self.threshold = MIN_COLLECT_THRESHOLD.max(self.tracked.len() * 2);
+    }
+
+    // returns the count of objects freed
+    pub fn collect(&mut self) -> usize {
+        let mut nodes: Vec<Node> = self.tracked.iter().filter_map(WeakNode::upgrade).collect();
+        let mut indices: HashMap<*const (), usize> = HashMap::new();
+        nodes.retain(|node| indices.insert(node.address(), indices.len()).is_none());
+
+        // objects created by natives are not tracked, find them from the tracked objects,
+        // the children are read once, objects shared with other threads may change while collecting
+        let mut edges: Vec<Vec<usize>> = Vec::new();
+        while edges.len() < nodes.len() {
+            let children = if let Some(children) = nodes[edges.len()].children() { children } else {
+                return 0;
+            };
+
+            let child_indices = children.into_iter().map(|child| match indices.entry(child.address()) {
+                Entry::Occupied(entry) => *entry.get(),
+                Entry::Vacant(entry) => {
+                    entry.insert(nodes.len());
+                    nodes.push(child);
+                    nodes.len() - 1
+                }
+            }).collect();
+
+            edges.push(child_indices);
+        };
+
+        // references which do not come from nodes, the one in nodes list is not counted
+        let strong_counts: Vec<usize> = nodes.iter().map(Node::strong_count).collect();
+        let mut external_counts: Vec<usize> = strong_counts.iter().map(|count| count - 1).collect();
+
+        for &index in edges.iter().flatten() {
+            external_counts[index] = external_counts[index].saturating_sub(1);
+        };
+
+        // nodes referenced from outside and the nodes they refer to are alive
+        let mut alive = vec![ false; nodes.len() ];
+        let mut pending: Vec<usize> = (0..nodes.len()).filter(|&index| external_counts[index] > 0).collect();
+
+        while let Some(index) = pending.pop() {
+            if alive[index] {
+                continue;
+            };
+            alive[index] = true;
+            pending.extend(&edges[index]);
+        };
+
+        // a garbage object referenced again after counting is taken by another thread, leave it to next time
+        if nodes.iter().zip(strong_counts.iter()).zip(alive.iter()).any(|((node, &count), &alive)| !alive && node.strong_count() != count) {
+            return 0;
+        };
+
+        let mut garbage_objects = Vec::new();
+        let mut garbage_cells = Vec::new();
+        let mut freed_count = 0;
+
+        for (node, alive) in nodes.iter().zip(alive.iter()) {
+            if !alive {
+                node.clear(&mut garbage_objects, &mut garbage_cells);
+                freed_count += 1;
+            };
+        };
+
+        // alive objects may be in a cycle later, the ones found from tracked objects are tracked from now
+        self.tracked = nodes.iter().zip(alive.iter()).filter(|(_, alive)| **alive).map(|(node, _)| node.downgrade()).collect();
+        self.threshold = MIN_COLLECT_THRESHOLD.max(self.tracked.len() * 2);
+
+        drop(garbage_objects);
+        drop(garbage_cells);
+        drop(nodes);
+
+        freed_count
+    }
+}
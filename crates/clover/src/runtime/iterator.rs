@@ -6,23 +6,18 @@ impl State {
     pub fn for_next(&mut self, enumerable_index: usize) -> Result<(), RuntimeError> {
         let iterator_index = enumerable_index + 1;
 
-        let enumerable = self.current_frame().locals[enumerable_index].clone();
+        let enumerable = self.get_local(enumerable_index);
 
-        let iterator = if let Object::Integer(iterator) = self.current_frame().locals[iterator_index].clone() {
+        let iterator = if let Object::Integer(iterator) = self.get_local(iterator_index) {
             iterator
         } else {
             0
         };
 
         let jump = match enumerable {
-            Object::Integer(value) => {
-                if iterator < value {
-                    self.push(Object::Integer(iterator));
-                    false
-                } else {
-                    // iterator greater than enumerable object, finish loop
-                    true
-                }
+            Object::Integer(value) if iterator < value => {
+                self.push(Object::Integer(iterator));
+                false
             },
             Object::Array(array) => {
                 let index = iterator as usize;
@@ -35,6 +30,19 @@ impl State {
                     true
                 }
             },
+            Object::Map(map) => {
+                let index = iterator as usize;
+
+                // by position, keys inserted while iterating are appended and visited later
+                let key = map.borrow().get_index(index).map(|(key, _)| key.to_object());
+
+                if let Some(key) = key {
+                    self.push(key);
+                    false
+                } else {
+                    true
+                }
+            },
             Object::Instance(instance) => {
                 let model_index = instance.borrow().model_index;
                 let model = &self.get_program().models[model_index];
@@ -48,6 +56,7 @@ impl State {
                     true
                 }
             }
+            // iterator greater than enumerable object or not a enumerable object, finish loop
             _ => true
         };
 
@@ -57,8 +66,8 @@ impl State {
     }
 
     pub fn iterate(&mut self, iterator_index: usize) {
-        if let Object::Integer(iterator) = self.current_frame().locals[iterator_index].clone() {
-            self.current_frame_as_mut().locals[iterator_index] = Object::Integer(iterator + 1);
+        if let Object::Integer(iterator) = self.get_local(iterator_index) {
+            self.set_local(iterator_index, Object::Integer(iterator + 1));
         };
     }
 }
\ No newline at end of file
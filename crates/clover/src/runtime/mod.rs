@@ -3,7 +3,12 @@ pub mod program;
 pub mod opcode;
 pub mod assembly_information;
 pub mod state;
+pub mod conversion;
+pub mod shared;
+pub mod disassembler;
 
 mod operation;
 mod object_property;
 mod iterator;
+mod heap;
+mod verifier;
@@ -1,18 +1,90 @@
-use std::cell::RefCell;
+use std::any::{Any, type_name};
+use indexmap::IndexMap;
 use std::fmt;
-use std::rc::Rc;
+use std::marker::PhantomData;
 use crate::runtime::state::State;
 use crate::runtime::program::RuntimeError;
-use std::ops::Deref;
+use crate::runtime::shared::{Shared, Lock, Ref, RefMut, MaybeSync};
+use std::ops::{Deref, DerefMut};
 use crate::debug::Position;
 
-pub type Reference<T> = Rc<RefCell<T>>;
+pub type Reference<T> = Shared<Lock<T>>;
 
 pub fn make_reference<T>(object: T) -> Reference<T> {
-    Rc::new(RefCell::new(object))
+    Shared::new(Lock::new(object))
 }
 
-pub type NativeFunction = fn(&mut State, &[Object]) -> Result<Object, RuntimeError>;
+// natives are closures, so they can capture the state of host
+#[cfg(not(feature = "sync"))]
+pub type NativeFunction = Shared<dyn Fn(&mut State, &[Object]) -> Result<Object, RuntimeError>>;
+#[cfg(feature = "sync")]
+pub type NativeFunction = Shared<dyn Fn(&mut State, &[Object]) -> Result<Object, RuntimeError> + Send + Sync>;
+
+pub fn make_native_function<F>(function: F) -> NativeFunction
+    where F: Fn(&mut State, &[Object]) -> Result<Object, RuntimeError> + MaybeSync + 'static
+{
+    Shared::new(function)
+}
+
+// wrap a FnMut, it can not be called again while it is running
+pub fn make_native_function_mut<F>(function: F) -> NativeFunction
+    where F: FnMut(&mut State, &[Object]) -> Result<Object, RuntimeError> + MaybeSync + 'static
+{
+    let function = Lock::new(function);
+
+    Shared::new(move |state: &mut State, parameters: &[Object]| {
+        if let Ok(mut function) = function.try_borrow_mut() {
+            function(state, parameters)
+        } else {
+            Err(RuntimeError::new("native function is already running", state.last_position()))
+        }
+    })
+}
+
+// objects which can be keys of map, 1 and "1" are different keys
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Integer(i64),
+    Boolean(bool),
+    String(String)
+}
+
+impl MapKey {
+    pub fn from_object(object: &Object) -> Option<MapKey> {
+        match object {
+            Object::Integer(value) => Some(MapKey::Integer(*value)),
+            Object::Boolean(value) => Some(MapKey::Boolean(*value)),
+            Object::String(value) => Some(MapKey::String(value.borrow().clone())),
+            _ => None
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        match self {
+            MapKey::Integer(value) => Object::Integer(*value),
+            MapKey::Boolean(value) => Object::Boolean(*value),
+            MapKey::String(value) => Object::String(make_reference(value.clone()))
+        }
+    }
+
+    // approximate bytes of the key, for heap limit
+    pub fn size(&self) -> usize {
+        match self {
+            MapKey::String(value) => value.len(),
+            _ => 0
+        }
+    }
+}
+
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::Integer(value) => write!(f, "{}", value),
+            MapKey::Boolean(value) => write!(f, "{}", value),
+            MapKey::String(value) => f.write_str(value)
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ModelInstance {
@@ -20,14 +92,21 @@ pub struct ModelInstance {
     pub properties: Vec<Object>
 }
 
-pub trait NativeModel {
+#[derive(Debug)]
+pub struct Closure {
+    pub function_index: usize,
+    pub upvalues: Vec<Reference<Object>>
+}
+
+pub trait NativeModel: MaybeSync {
     // model constructor
     fn call(&mut self, state: &mut State, _parameters: &[Object]) -> Result<Object, RuntimeError> { Err(RuntimeError::new("this native model do not have constructor", state.last_position())) }
 
     fn model_get(&self, key: &str) -> Result<Object, RuntimeError> { Err(RuntimeError::new(&format!("this native do not have property [{}]", key), Position::none())) }
 }
 
-pub trait NativeModelInstance {
+// Any is required so native instances can be downcast to the rust type
+pub trait NativeModelInstance: Any + MaybeSync {
     fn index_get(&self, this: Reference<dyn NativeModelInstance>, index: &Object) -> Result<Object, RuntimeError>;
     fn index_set(&mut self, this: Reference<dyn NativeModelInstance>, index: &Object, value: Object) -> Result<(), RuntimeError>;
     fn instance_get(&self, this: Reference<dyn NativeModelInstance>, key: &str) -> Result<Object, RuntimeError>;
@@ -41,6 +120,19 @@ pub trait NativeModelInstance {
     fn raw_get_byte_array(&self, _key: &str) -> Option<&[u8]> { None }
 }
 
+// methods, functions and constructor of a native type, usually implemented by #[native_methods] of clover-derive
+pub trait NativeMethods {
+    fn has_method(_key: &str) -> bool { false }
+
+    // None when there is no method named key
+    fn call_method(&mut self, _state: &mut State, _key: &str, _parameters: &[Object]) -> Option<Result<Object, RuntimeError>> { None }
+
+    // functions and constants of the model
+    fn model_get(_key: &str) -> Option<Object> { None }
+
+    fn construct(_state: &mut State, _parameters: &[Object]) -> Option<Result<Object, RuntimeError>> { None }
+}
+
 pub fn ensure_parameters_length(parameters: &[Object], length: usize) -> Result<(), RuntimeError> {
     if parameters.len() == length {
         Ok(())
@@ -57,6 +149,7 @@ pub enum Object {
 
     Function(usize),
     InstanceFunction(Box<Object>, usize),
+    Closure(Reference<Closure>),
     NativeFunction(NativeFunction),
     InstanceNativeFunction(Reference<dyn NativeModelInstance>, String),
 
@@ -69,6 +162,11 @@ pub enum Object {
     NativeInstance(Reference<dyn NativeModelInstance>),
 
     Array(Reference<Vec<Object>>),
+    // keeps the insertion order, so iterating by position is stable while the map grows
+    Map(Reference<IndexMap<MapKey, Object>>),
+
+    // error object given to rescue and ensure blocks
+    Error(Reference<RuntimeError>),
 }
 
 impl fmt::Debug for Object {
@@ -82,9 +180,13 @@ impl fmt::Debug for Object {
             Object::Boolean(value) => struct_format.field("Boolean", value),
             Object::Null => struct_format.field("Null", &"Null".to_string()),
 
+            Object::Function(value) => struct_format.field("Function", value),
+            Object::Closure(closure) => struct_format.field("Closure", &closure.borrow().function_index),
             Object::Model(value) => struct_format.field("Model", value),
             Object::Instance(value) => struct_format.field("Instance", value),
             Object::Array(array) => struct_format.field("Array", array.deref()),
+            Object::Map(map) => struct_format.field("Map", map.deref()),
+            Object::Error(error) => struct_format.field("Error", &error.borrow().message),
             _ => struct_format.field("Unknown", &"Unknown".to_string())
         }.finish()
     }
@@ -100,18 +202,37 @@ impl Clone for Object {
             Object::Null => Object::Null,
             Object::Function(index) => Object::Function(*index),
             Object::InstanceFunction(this, index) => Object::InstanceFunction(this.clone(), *index),
-            Object::NativeFunction(function) => Object::NativeFunction(*function),
+            Object::Closure(closure) => Object::Closure(closure.clone()),
+            Object::NativeFunction(function) => Object::NativeFunction(function.clone()),
             Object::InstanceNativeFunction(this, function_name) => Object::InstanceNativeFunction(this.clone(), function_name.clone()),
             Object::Model(index) => Object::Model(*index),
             Object::NativeModel(index) => Object::NativeModel(*index),
             Object::Instance(instance) => Object::Instance(instance.clone()),
             Object::NativeInstance(instance) => Object::NativeInstance(instance.clone()),
-            Object::Array(value) => Object::Array(value.clone())
+            Object::Array(value) => Object::Array(value.clone()),
+            Object::Map(value) => Object::Map(value.clone()),
+            Object::Error(value) => Object::Error(value.clone())
         }
     }
 }
 
 impl Object {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Integer(_) => "Integer",
+            Object::Float(_) => "Float",
+            Object::Boolean(_) => "Boolean",
+            Object::Null => "Null",
+            Object::Function(_) | Object::InstanceFunction(_, _) | Object::Closure(_) | Object::NativeFunction(_) | Object::InstanceNativeFunction(_, _) => "Function",
+            Object::Model(_) | Object::NativeModel(_) => "Model",
+            Object::String(_) => "String",
+            Object::Instance(_) | Object::NativeInstance(_) => "Instance",
+            Object::Array(_) => "Array",
+            Object::Map(_) => "Map",
+            Object::Error(_) => "Error"
+        }
+    }
+
     pub fn is_string(&self) -> bool {
         matches!(self, Object::String(_))
     }
@@ -168,15 +289,96 @@ impl Object {
         }
     }
 
+    pub fn native_instance_as<T: NativeModelInstance>(&self) -> Result<NativeInstanceRef<'_, T>, RuntimeError> {
+        let instance = self.native_instance_reference()?.try_borrow().map_err(|_| native_instance_borrowed())?;
+
+        if !(&*instance as &dyn Any).is::<T>() {
+            return Err(native_instance_type_mismatch::<T>());
+        };
+
+        Ok(NativeInstanceRef { instance, phantom: PhantomData })
+    }
+
+    pub fn native_instance_as_mut<T: NativeModelInstance>(&self) -> Result<NativeInstanceRefMut<'_, T>, RuntimeError> {
+        let instance = self.native_instance_reference()?.try_borrow_mut().map_err(|_| native_instance_borrowed())?;
+
+        if !(&*instance as &dyn Any).is::<T>() {
+            return Err(native_instance_type_mismatch::<T>());
+        };
+
+        Ok(NativeInstanceRefMut { instance, phantom: PhantomData })
+    }
+
+    fn native_instance_reference(&self) -> Result<&Reference<dyn NativeModelInstance>, RuntimeError> {
+        if let Object::NativeInstance(instance) = self {
+            Ok(instance)
+        } else {
+            Err(RuntimeError::new("value is not a native instance", Position::none()))
+        }
+    }
+
+}
+
+// borrowed native instance which is known to be T, the guard of RwLock can not be mapped to another type
+pub struct NativeInstanceRef<'a, T> {
+    instance: Ref<'a, dyn NativeModelInstance>,
+    phantom: PhantomData<T>
 }
 
-fn objects_to_string(objects: &Vec<Object>) -> String {
+impl<T: NativeModelInstance> Deref for NativeInstanceRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        (&*self.instance as &dyn Any).downcast_ref::<T>().unwrap()
+    }
+}
+
+pub struct NativeInstanceRefMut<'a, T> {
+    instance: RefMut<'a, dyn NativeModelInstance>,
+    phantom: PhantomData<T>
+}
+
+impl<T: NativeModelInstance> Deref for NativeInstanceRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        (&*self.instance as &dyn Any).downcast_ref::<T>().unwrap()
+    }
+}
+
+impl<T: NativeModelInstance> DerefMut for NativeInstanceRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        (&mut *self.instance as &mut dyn Any).downcast_mut::<T>().unwrap()
+    }
+}
+
+impl<T: NativeModelInstance + fmt::Debug> fmt::Debug for NativeInstanceRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+impl<T: NativeModelInstance + fmt::Debug> fmt::Debug for NativeInstanceRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+fn native_instance_borrowed() -> RuntimeError {
+    RuntimeError::new("native instance is already borrowed", Position::none())
+}
+
+fn native_instance_type_mismatch<T>() -> RuntimeError {
+    RuntimeError::new(&format!("native instance is not [{}]", type_name::<T>()), Position::none())
+}
+
+fn objects_to_string(objects: &[Object]) -> String {
     objects.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", ")
 }
 
-impl ToString for Object {
-    fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let string = match self {
             Object::Integer(value) => value.to_string(),
             Object::Float(value) => value.to_string(),
             Object::String(value) => value.borrow().deref().clone(),
@@ -186,7 +388,11 @@ impl ToString for Object {
             Object::Model(index) => "{ (".to_string() + index.to_string().as_str() + ") }",
             Object::Instance(instance) => "{ (".to_string() + instance.borrow().deref().model_index.to_string().as_str() + ") " + objects_to_string(&instance.borrow().deref().properties).as_str() + " }",
             Object::Array(array) => "[ ".to_string() + objects_to_string(array.borrow().deref()).as_str() + " ]",
+            Object::Map(map) => "{ ".to_string() + map.borrow().iter().map(|(key, value)| format!("{}: {}", key, value)).collect::<Vec<String>>().join(", ").as_str() + " }",
+            Object::Error(error) => error.borrow().to_string(),
             _ => "Unknown".to_string()
-        }
+        };
+
+        f.write_str(&string)
     }
 }
\ No newline at end of file
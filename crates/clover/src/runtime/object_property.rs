@@ -1,6 +1,9 @@
-use crate::runtime::object::{Object, Reference, make_reference};
+use crate::runtime::object::{Object, Reference, MapKey, make_reference, make_native_function};
 use crate::runtime::state::State;
 use crate::runtime::program::RuntimeError;
+use crate::runtime::conversion::expect_parameter_count;
+use crate::runtime::shared::Shared;
+use indexmap::IndexMap;
 use std::ops::Deref;
 
 pub fn instance_get_integer(state: &mut State, value: i64, key: &str) -> Result<(), RuntimeError> {
@@ -66,4 +69,85 @@ pub fn instance_get_array(state: &mut State, array: Reference<Vec<Object>>, key:
         },
         _ => Err(RuntimeError::new("unknown property", state.last_position()))
     }
-}
\ No newline at end of file
+}
+
+fn map_key(state: &State, index: &Object) -> Result<MapKey, RuntimeError> {
+    MapKey::from_object(index).ok_or_else(|| RuntimeError::new(&format!("can not use {} as map key", index.type_name()), state.last_position()))
+}
+
+// entries come first, so a "length" entry can be read and changed like other entries
+pub fn instance_get_map(state: &mut State, map: Reference<IndexMap<MapKey, Object>>, key: &str) -> Result<(), RuntimeError> {
+    let entry = map.borrow().get(&MapKey::String(key.to_string())).cloned();
+
+    let object = match (entry, key) {
+        (Some(object), _) => object,
+        (None, "length") => make_map_length(&map),
+        (None, _) => Object::Null
+    };
+
+    state.push(object);
+
+    Ok(())
+}
+
+// length is a method, the map is weakly referenced so storing the method in the map does not make a cycle
+fn make_map_length(map: &Reference<IndexMap<MapKey, Object>>) -> Object {
+    let map = Shared::downgrade(map);
+
+    Object::NativeFunction(make_native_function(move |state: &mut State, parameters: &[Object]| {
+        expect_parameter_count(state, parameters, 0)?;
+
+        Ok(map.upgrade().map(|map| Object::Integer(map.borrow().len() as i64)).unwrap_or(Object::Null))
+    }))
+}
+
+pub fn instance_set_map(state: &mut State, map: Reference<IndexMap<MapKey, Object>>, key: &str) -> Result<(), RuntimeError> {
+    index_set_map(state, map, &Object::String(make_reference(key.to_string())))
+}
+
+pub fn index_get_map(state: &mut State, map: Reference<IndexMap<MapKey, Object>>, index: &Object) -> Result<(), RuntimeError> {
+    let key = map_key(state, index)?;
+    let object = map.borrow().get(&key).cloned().unwrap_or(Object::Null);
+
+    state.push(object);
+
+    Ok(())
+}
+
+pub fn index_set_map(state: &mut State, map: Reference<IndexMap<MapKey, Object>>, index: &Object) -> Result<(), RuntimeError> {
+    let key = map_key(state, index)?;
+
+    state.allocate(key.size() + std::mem::size_of::<Object>())?;
+    map.borrow_mut().insert(key, state.top());
+
+    Ok(())
+}
+
+pub fn instance_get_error(state: &mut State, error: Reference<RuntimeError>, key: &str) -> Result<(), RuntimeError> {
+    let object = match key {
+        "message" => Object::String(make_reference(error.borrow().message.clone())),
+        "line" => Object::Integer(error.borrow().position.line as i64),
+        "column" => Object::Integer(error.borrow().position.column as i64),
+        "value" => error.borrow().value.clone().unwrap_or(Object::Null),
+        "stack" => {
+            let mut stack = Vec::new();
+
+            for trace_frame in state.make_traceback(&error.borrow().stack) {
+                let mut frame_object = IndexMap::new();
+                frame_object.insert(MapKey::String("function_name".to_string()), trace_frame.function_name.map(|name| Object::String(make_reference(name))).unwrap_or(Object::Null));
+                frame_object.insert(MapKey::String("filename".to_string()), trace_frame.filename.map(|name| Object::String(make_reference(name))).unwrap_or(Object::Null));
+                frame_object.insert(MapKey::String("line".to_string()), Object::Integer(trace_frame.position.line as i64));
+                frame_object.insert(MapKey::String("column".to_string()), Object::Integer(trace_frame.position.column as i64));
+
+                stack.push(Object::Map(make_reference(frame_object)));
+            };
+
+            Object::Array(make_reference(stack))
+        },
+        _ => { return Err(RuntimeError::new("unknown property", state.last_position())); }
+    };
+
+    state.push(object);
+
+    Ok(())
+}
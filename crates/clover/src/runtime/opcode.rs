@@ -16,6 +16,11 @@ pub const OPERATION_LESS_EQUAL: usize = 9;
 pub const OPERATION_AND: usize = 256 | 1;
 pub const OPERATION_OR: usize = 256 | 2;
 
+// the opcodes a program may use, recorded in the bytecode header, fused opcodes are produced by the peephole pass
+pub const OPCODE_SET_BASE: u8 = 0;
+pub const OPCODE_SET_FUSED: u8 = 1;
+pub const OPCODE_SET_LATEST: u8 = OPCODE_SET_FUSED;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OpCode {
     Pop             = 0x01,
@@ -23,6 +28,8 @@ pub enum OpCode {
     // operand -> index of constant
     PushConstant    = 0x02,
 
+    // raise the object on the top of stack as error
+    Raise           = 0x04,
     Return          = 0x05,
 
     // operand -> index of local
@@ -32,9 +39,21 @@ pub enum OpCode {
     // operand -> index of local
     LocalInit       = 0x08,
 
+    // operand -> index of local captured by closure
+    CellSet         = 0x09,
+    // operand -> index of local captured by closure
+    CellGet         = 0x0A,
+    // operand -> index of local captured by closure
+    CellInit        = 0x0B,
+
     ContextSet      = 0x0C,
     ContextGet      = 0x0D,
 
+    // operand -> index of upvalue
+    UpvalueSet      = 0x0E,
+    // operand -> index of upvalue
+    UpvalueGet      = 0x0F,
+
     // operand -> index of constant (global name)
     GlobalSet       = 0x11,
     // operand -> index of constant (global name)
@@ -49,7 +68,10 @@ pub enum OpCode {
     Operation       = 0x21,
     Not             = 0x22,
     Negative        = 0x23,
+    // operand -> index of constant, add the constant to the object on the top of stack
+    AddConstant     = 0x24,
 
+    // operand -> index of function
     Closure         = 0x31,
 
     // operand -> parameter count
@@ -64,6 +86,11 @@ pub enum OpCode {
     Jump            = 0x51,
     // operand -> position
     JumpIf          = 0x52,
+    // operand -> position, jump when the condition is false
+    JumpIfNot       = 0x53,
+    // operand is comparison operator, must be followed by JumpIf or JumpIfNot
+    // numbers are compared and jump by the next instruction directly, other objects do the operation only
+    CompareJump     = 0x54,
     // operand -> enumerable index
     ForNext         = 0x56,
     // operand -> iterator index
@@ -78,8 +105,8 @@ impl From<u64> for Instruction {
     }
 }
 
-impl Into<u64> for &Instruction {
-    fn into(self) -> u64 { self.0 }
+impl From<&Instruction> for u64 {
+    fn from(instruction: &Instruction) -> u64 { instruction.0 }
 }
 
 impl Instruction {
@@ -90,15 +117,23 @@ impl Instruction {
             0x01 => OpCode::Pop,
             0x02 => OpCode::PushConstant,
 
+            0x04 => OpCode::Raise,
             0x05 => OpCode::Return,
 
             0x06 => OpCode::LocalSet,
             0x07 => OpCode::LocalGet,
             0x08 => OpCode::LocalInit,
 
+            0x09 => OpCode::CellSet,
+            0x0A => OpCode::CellGet,
+            0x0B => OpCode::CellInit,
+
             0x0C => OpCode::ContextSet,
             0x0D => OpCode::ContextGet,
 
+            0x0E => OpCode::UpvalueSet,
+            0x0F => OpCode::UpvalueGet,
+
             0x11 => OpCode::GlobalSet,
             0x12 => OpCode::GlobalGet,
             0x13 => OpCode::InstanceSet,
@@ -110,6 +145,7 @@ impl Instruction {
             0x21 => OpCode::Operation,
             0x22 => OpCode::Not,
             0x23 => OpCode::Negative,
+            0x24 => OpCode::AddConstant,
 
             0x31 => OpCode::Closure,
             0x32 => OpCode::Call,
@@ -120,6 +156,8 @@ impl Instruction {
 
             0x51 => OpCode::Jump,
             0x52 => OpCode::JumpIf,
+            0x53 => OpCode::JumpIfNot,
+            0x54 => OpCode::CompareJump,
 
             0x56 => OpCode::ForNext,
             0x57 => OpCode::Iterate,
@@ -128,8 +166,43 @@ impl Instruction {
         }
     }
 
+    pub const MAX_OPERAND: u64 = 0x00FFFFFFFFFFFFFF;
+
     pub fn operand(&self) -> u64 {
-        self.0 & 0x00FFFFFFFFFFFFFF
+        self.0 & Instruction::MAX_OPERAND
+    }
+
+    // how many objects this instruction pops from and pushes to the stack
+    // ForNext also pushes the value of iterator before the condition when the loop is not finished
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self.opcode() {
+            OpCode::Pop => (1, 0),
+            OpCode::PushConstant => (0, 1),
+            OpCode::Raise | OpCode::Return => (1, 0),
+
+            OpCode::LocalSet | OpCode::CellSet | OpCode::ContextSet | OpCode::UpvalueSet | OpCode::GlobalSet => (0, 0),
+            OpCode::LocalGet | OpCode::CellGet | OpCode::ContextGet | OpCode::UpvalueGet | OpCode::GlobalGet => (0, 1),
+            OpCode::LocalInit | OpCode::CellInit => (1, 0),
+
+            // the value to set stays on the stack
+            OpCode::InstanceSet | OpCode::IndexSet => (2, 0),
+            OpCode::InstanceGet | OpCode::IndexGet => (2, 1),
+
+            OpCode::Operation | OpCode::CompareJump => (2, 1),
+            OpCode::Not | OpCode::Negative | OpCode::AddConstant => (1, 1),
+
+            OpCode::Closure => (0, 1),
+            OpCode::Call => (self.operand() as usize + 1, 1),
+            OpCode::Array => (self.operand() as usize, 1),
+            OpCode::PushNewMap => (0, 1),
+
+            OpCode::Jump => (0, 0),
+            OpCode::JumpIf | OpCode::JumpIfNot => (1, 0),
+            OpCode::ForNext => (0, 1),
+            OpCode::Iterate => (0, 0),
+
+            OpCode::Unknown => (0, 0)
+        }
     }
 }
 
@@ -143,7 +216,70 @@ impl fmt::Debug for Instruction {
 }
 
 impl OpCode {
-    pub fn to_instruction(&self, operand: u64) -> Instruction {
-        Instruction((*self as u64) << 56 | operand)
+    pub fn to_instruction(self, operand: u64) -> Instruction {
+        Instruction((self as u64) << 56 | operand)
+    }
+
+    pub fn opcode_set(self) -> u8 {
+        match self {
+            OpCode::AddConstant | OpCode::JumpIfNot | OpCode::CompareJump => OPCODE_SET_FUSED,
+            _ => OPCODE_SET_BASE
+        }
+    }
+
+    // the name used by disassembler and assembler
+    pub fn from_name(name: &str) -> Option<OpCode> {
+        let opcode = match name {
+            "Pop" => OpCode::Pop,
+            "PushConstant" => OpCode::PushConstant,
+
+            "Raise" => OpCode::Raise,
+            "Return" => OpCode::Return,
+
+            "LocalSet" => OpCode::LocalSet,
+            "LocalGet" => OpCode::LocalGet,
+            "LocalInit" => OpCode::LocalInit,
+
+            "CellSet" => OpCode::CellSet,
+            "CellGet" => OpCode::CellGet,
+            "CellInit" => OpCode::CellInit,
+
+            "ContextSet" => OpCode::ContextSet,
+            "ContextGet" => OpCode::ContextGet,
+
+            "UpvalueSet" => OpCode::UpvalueSet,
+            "UpvalueGet" => OpCode::UpvalueGet,
+
+            "GlobalSet" => OpCode::GlobalSet,
+            "GlobalGet" => OpCode::GlobalGet,
+            "InstanceSet" => OpCode::InstanceSet,
+            "InstanceGet" => OpCode::InstanceGet,
+            "IndexSet" => OpCode::IndexSet,
+            "IndexGet" => OpCode::IndexGet,
+
+            "Operation" => OpCode::Operation,
+            "Not" => OpCode::Not,
+            "Negative" => OpCode::Negative,
+            "AddConstant" => OpCode::AddConstant,
+
+            "Closure" => OpCode::Closure,
+            "Call" => OpCode::Call,
+
+            "Array" => OpCode::Array,
+
+            "PushNewMap" => OpCode::PushNewMap,
+
+            "Jump" => OpCode::Jump,
+            "JumpIf" => OpCode::JumpIf,
+            "JumpIfNot" => OpCode::JumpIfNot,
+            "CompareJump" => OpCode::CompareJump,
+
+            "ForNext" => OpCode::ForNext,
+            "Iterate" => OpCode::Iterate,
+
+            _ => return None
+        };
+
+        Some(opcode)
     }
 }
\ No newline at end of file
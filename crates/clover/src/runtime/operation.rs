@@ -3,9 +3,32 @@ use crate::runtime::program::RuntimeError;
 use crate::runtime::opcode::{OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, OPERATION_DIVIDE, OPERATION_MOD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL};
 use crate::runtime::state::State;
 use std::ops::Deref;
+use std::cmp::Ordering;
 
 const META_METHODS: &[ &str ] = &[ "_add", "_sub", "_mul", "_div", "_mod", "_eq", "_gt", "_lt", "_gte", "_lte" ];
 
+// the result of comparison when both objects are numbers, same as the operations of integer and float
+// None for other objects, they need the full operation
+pub fn compare_numbers(left: &Object, right: &Object, operand: usize) -> Option<bool> {
+    let ordering = match (left, right) {
+        (Object::Integer(left), Object::Integer(right)) => left.partial_cmp(right),
+        (Object::Integer(left), Object::Float(right)) => (*left as f64).partial_cmp(right),
+        (Object::Float(left), Object::Integer(right)) => left.partial_cmp(&(*right as f64)),
+        (Object::Float(left), Object::Float(right)) => left.partial_cmp(right),
+        _ => return None
+    };
+
+    // nan is not equal, greater or less than anything
+    match operand {
+        OPERATION_EQUAL => Some(ordering == Some(Ordering::Equal)),
+        OPERATION_GREATER => Some(ordering == Some(Ordering::Greater)),
+        OPERATION_LESS => Some(ordering == Some(Ordering::Less)),
+        OPERATION_GREATER_EQUAL => Some(matches!(ordering, Some(Ordering::Greater | Ordering::Equal))),
+        OPERATION_LESS_EQUAL => Some(matches!(ordering, Some(Ordering::Less | Ordering::Equal))),
+        _ => None
+    }
+}
+
 impl State {
 
     fn integer_add(&self, left: i64, right: &Object) -> Result<Object, RuntimeError> {
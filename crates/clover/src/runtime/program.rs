@@ -1,41 +1,156 @@
-use std::collections::{HashMap, LinkedList};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
 use crate::intermediate::Position;
 use crate::runtime::assembly_information::{DebugInfo, FileInfo};
 use crate::runtime::object::{Object, Reference, make_reference};
-use crate::runtime::opcode::Instruction;
+use crate::runtime::opcode::{Instruction, OPCODE_SET_BASE, OPCODE_SET_LATEST};
 use crate::runtime::state::Frame;
 use std::io::{Write, Read};
 use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
+use crate::runtime::verifier::verify;
+use crate::runtime::disassembler::disassemble;
+use crate::backend::assembler::assemble;
+use crate::intermediate::CompileErrorList;
+
+// readable information of a frame in call stack
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    pub function_index: usize,
+    pub function_name: Option<String>,
+    pub filename: Option<String>,
+    pub position: Position
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        let filename = self.filename.as_deref().unwrap_or("?");
+
+        formatter.write_fmt(format_args!("{}:{}:{}: in ", filename, self.position.line, self.position.column))?;
+
+        if let Some(function_name) = &self.function_name {
+            formatter.write_fmt(format_args!("function '{}'", function_name))
+        } else {
+            formatter.write_fmt(format_args!("function <{}>", self.function_index))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    // errors from script and natives, can be rescued by script
+    Script,
+    // the instruction budget is used up, the execution can be resumed after adding fuel
+    OutOfFuel,
+    // the host interrupted the execution, it can be resumed
+    Interrupted,
+    // frame, stack or heap limit of state is exceeded
+    LimitExceeded
+}
+
+impl RuntimeErrorKind {
+    // the errors which stop untrusted scripts can not be rescued by the script itself
+    pub fn is_rescuable(&self) -> bool {
+        *self == RuntimeErrorKind::Script
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
     pub message: String,
     pub position: Position,
-    pub stack: LinkedList<Frame>
+    // frames from where the error occurs to the outermost one
+    pub stack: Vec<Frame>,
+    // the object raised by script, None if the error is not raised by script
+    pub value: Option<Object>,
+    // filled by state when the error is returned to host, the first one is the outermost frame
+    pub traceback: Vec<TraceFrame>
 }
 
 impl RuntimeError {
     pub fn new(message: &str, position: Position) -> RuntimeError {
+        RuntimeError::with_kind(RuntimeErrorKind::Script, message, position)
+    }
+
+    pub fn with_kind(kind: RuntimeErrorKind, message: &str, position: Position) -> RuntimeError {
         RuntimeError {
+            kind,
             message: message.to_string(),
             position,
-            stack: LinkedList::new()
+            stack: Vec::new(),
+            value: None,
+            traceback: Vec::new()
+        }
+    }
+
+    pub fn raise(value: Object, position: Position) -> RuntimeError {
+        RuntimeError {
+            kind: RuntimeErrorKind::Script,
+            message: value.to_string(),
+            position,
+            stack: Vec::new(),
+            value: Some(value),
+            traceback: Vec::new()
         }
     }
 }
 
 impl Display for RuntimeError {
     fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
-        formatter.write_fmt(format_args!("at ({}, {}) - {}", self.position.line, self.position.column, self.message))
-        // TODO : add print call stack
+        formatter.write_fmt(format_args!("at ({}, {}) - {}", self.position.line, self.position.column, self.message))?;
+
+        if !self.traceback.is_empty() {
+            formatter.write_str("\nstack traceback:")?;
+
+            // most recent call first
+            for trace_frame in self.traceback.iter().rev() {
+                formatter.write_fmt(format_args!("\n    {}", trace_frame))?;
+            };
+        };
+
+        Ok(())
     }
 }
 
 impl Error for RuntimeError {}
 
+#[derive(Debug)]
+pub enum BytecodeError {
+    // can not read the bytecode, include unexpected end of data
+    Io(std::io::Error),
+    InvalidHeader,
+    // format version of the bytecode
+    VersionMismatch(u16),
+    // the opcode set in header is newer than the runtime
+    UnsupportedOpcodeSet(u8),
+    InvalidData(String),
+    // function index, instruction index and the reason
+    InvalidInstruction(usize, usize, String)
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::Io(error) => formatter.write_fmt(format_args!("can not read bytecode - {}", error)),
+            BytecodeError::InvalidHeader => formatter.write_str("invalid bytecode header"),
+            BytecodeError::VersionMismatch(version) => formatter.write_fmt(format_args!("bytecode format version {} does not match runtime format version {}", version, crate::version::BYTECODE_FORMAT_VERSION)),
+            BytecodeError::UnsupportedOpcodeSet(opcode_set) => formatter.write_fmt(format_args!("bytecode uses opcode set {} which is not supported by runtime", opcode_set)),
+            BytecodeError::InvalidData(message) => formatter.write_fmt(format_args!("invalid bytecode - {}", message)),
+            BytecodeError::InvalidInstruction(function_index, instruction_index, message) => formatter.write_fmt(format_args!("invalid instruction at {} of function {} - {}", instruction_index, function_index, message))
+        }
+    }
+}
+
+impl Error for BytecodeError {}
+
+impl From<std::io::Error> for BytecodeError {
+    fn from(error: std::io::Error) -> Self {
+        BytecodeError::Io(error)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Model {
     pub property_indices: HashMap<String, usize>,
@@ -81,7 +196,7 @@ impl Model {
         Ok(())
     }
 
-    fn deserialize(reader: &mut dyn Read) -> Result<Model, std::io::Error> {
+    fn deserialize(reader: &mut dyn Read) -> Result<Model, BytecodeError> {
         let mut model = Model::new();
         let property_count = reader.read_u32::<LittleEndian>()?;
 
@@ -103,24 +218,54 @@ impl Model {
 
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Upvalue {
+    // index of local in the enclosing function
+    Local(usize),
+    // index of upvalue in the enclosing function
+    Upvalue(usize)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Handler {
+    // instructions in [start, end) are protected by this handler
+    pub start: usize,
+    pub end: usize,
+    // where to jump when error occurs, the error object will be pushed to stack
+    pub target: usize,
+    // stack size above the frame to keep when jump to target
+    pub stack_size: usize
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
     pub parameter_count: usize,
     pub local_count: usize,
-    pub rescue_position: usize,
     pub is_instance: bool,
 
+    // handlers of rescue and ensure blocks, inner handlers are in front of outer handlers
+    pub handlers: Vec<Handler>,
+
+    // where to find the captured variables when create a closure of this function
+    pub upvalues: Vec<Upvalue>,
+
     pub instructions: Vec<Instruction>
 }
 
 impl Function {
+    const UPVALUE_TYPE_LOCAL: u8 = 0;
+    const UPVALUE_TYPE_UPVALUE: u8 = 1;
+
     pub fn new() -> Function {
         Function {
             parameter_count: 0,
             local_count: 0,
-            rescue_position: 0,
             is_instance: false,
 
+            handlers: Vec::new(),
+
+            upvalues: Vec::new(),
+
             instructions: Vec::new()
         }
     }
@@ -128,9 +273,30 @@ impl Function {
     fn serialize(&self, writer: &mut dyn Write) -> Result<(), std::io::Error>  {
         writer.write_u32::<LittleEndian>(self.parameter_count as u32)?;
         writer.write_u32::<LittleEndian>(self.local_count as u32)?;
-        writer.write_u32::<LittleEndian>(self.rescue_position as u32)?;
         writer.write_u8(if self.is_instance { 1 } else { 0 })?;
 
+        writer.write_u32::<LittleEndian>(self.handlers.len() as u32)?;
+        for handler in &self.handlers {
+            writer.write_u32::<LittleEndian>(handler.start as u32)?;
+            writer.write_u32::<LittleEndian>(handler.end as u32)?;
+            writer.write_u32::<LittleEndian>(handler.target as u32)?;
+            writer.write_u32::<LittleEndian>(handler.stack_size as u32)?;
+        };
+
+        writer.write_u32::<LittleEndian>(self.upvalues.len() as u32)?;
+        for upvalue in &self.upvalues {
+            match upvalue {
+                Upvalue::Local(index) => {
+                    writer.write_u8(Function::UPVALUE_TYPE_LOCAL)?;
+                    writer.write_u32::<LittleEndian>(*index as u32)?;
+                },
+                Upvalue::Upvalue(index) => {
+                    writer.write_u8(Function::UPVALUE_TYPE_UPVALUE)?;
+                    writer.write_u32::<LittleEndian>(*index as u32)?;
+                }
+            }
+        };
+
         writer.write_u32::<LittleEndian>(self.instructions.len() as u32)?;
 
         for instruction in &self.instructions {
@@ -140,12 +306,39 @@ impl Function {
         Ok(())
     }
 
-    fn deserialize(reader: &mut dyn Read) -> Result<Function, std::io::Error> {
+    fn deserialize(reader: &mut dyn Read) -> Result<Function, BytecodeError> {
         let parameter_count = reader.read_u32::<LittleEndian>()? as usize;
         let local_count = reader.read_u32::<LittleEndian>()? as usize;
-        let rescue_position = reader.read_u32::<LittleEndian>()? as usize;
         let is_instance = reader.read_u8()?;
 
+        let handler_count = reader.read_u32::<LittleEndian>()?;
+        let mut handlers = Vec::new();
+
+        for _ in 0..handler_count {
+            handlers.push(Handler {
+                start: reader.read_u32::<LittleEndian>()? as usize,
+                end: reader.read_u32::<LittleEndian>()? as usize,
+                target: reader.read_u32::<LittleEndian>()? as usize,
+                stack_size: reader.read_u32::<LittleEndian>()? as usize
+            });
+        };
+
+        let upvalue_count = reader.read_u32::<LittleEndian>()?;
+        let mut upvalues = Vec::new();
+
+        for _ in 0..upvalue_count {
+            let upvalue_type = reader.read_u8()?;
+            let index = reader.read_u32::<LittleEndian>()? as usize;
+
+            upvalues.push(match upvalue_type {
+                Function::UPVALUE_TYPE_LOCAL => Upvalue::Local(index),
+                Function::UPVALUE_TYPE_UPVALUE => Upvalue::Upvalue(index),
+                _ => {
+                    return Err(BytecodeError::InvalidData(format!("unknown upvalue type {}", upvalue_type)));
+                }
+            });
+        };
+
         let instruction_count = reader.read_u32::<LittleEndian>()?;
         let mut instructions = Vec::new();
 
@@ -156,14 +349,16 @@ impl Function {
         Ok(Function {
             parameter_count,
             local_count,
-            rescue_position,
             is_instance: is_instance == 1,
+            handlers,
+            upvalues,
             instructions
         })
     }
 }
 
-#[derive(Debug)]
+// constants are never changed by script, so clones of program share them
+#[derive(Debug, Clone)]
 pub struct Program {
     pub models: Vec<Model>,
     pub functions: Vec<Function>,
@@ -180,11 +375,14 @@ pub struct Program {
     // entry_point - 1 is the function index
     pub entry_point: usize,
 
+    // top level functions and models can be found by name, key is name, value is constant index
+    pub definitions: HashMap<String, usize>,
+
     pub file_info: Option<FileInfo>,
     pub debug_info: Option<DebugInfo>
 }
 
-fn serialize_string(string: &str, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+pub(crate) fn serialize_string(string: &str, writer: &mut dyn Write) -> Result<(), std::io::Error> {
     let string_binary = string.as_bytes();
 
     writer.write_u32::<LittleEndian>(string_binary.len() as u32)?;
@@ -193,19 +391,18 @@ fn serialize_string(string: &str, writer: &mut dyn Write) -> Result<(), std::io:
     Ok(())
 }
 
-fn deserialize_string(reader: &mut dyn Read) -> Result<String, std::io::Error> {
-    let string_length = reader.read_u32::<LittleEndian>()? as usize;
+pub(crate) fn deserialize_string(reader: &mut dyn Read) -> Result<String, BytecodeError> {
+    let string_length = reader.read_u32::<LittleEndian>()? as u64;
 
-    let mut buffer: Vec<u8> = vec![0; string_length];
+    // do not trust the length before the data is really read
+    let mut buffer: Vec<u8> = Vec::new();
+    reader.take(string_length).read_to_end(&mut buffer)?;
 
-    reader.read(&mut buffer)?;
+    if buffer.len() as u64 != string_length {
+        return Err(BytecodeError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+    };
 
-    if let Ok(string) = String::from_utf8(buffer) {
-        Ok(string)
-    } else {
-        // TODO : change error type
-        Err(std::io::Error::from_raw_os_error(0))
-    }
+    String::from_utf8(buffer).map_err(|_| BytecodeError::InvalidData("string is not utf-8".to_string()))
 }
 
 impl Program {
@@ -220,15 +417,18 @@ impl Program {
     const OBJECT_TYPE_MODEL: u8 = 3;
     const OBJECT_TYPE_FUNCTION: u8 = 4;
 
+    // optional sections after entry point, the section list ends with SECTION_END
+    const SECTION_END: u8 = 0;
+    const SECTION_FILE_INFO: u8 = 1;
+    const SECTION_DEBUG_INFO: u8 = 2;
+
     // luck
     const HEADER: u32 = 0x6b63756c;
 
     pub fn serialize(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
         writer.write_u32::<LittleEndian>(Program::HEADER)?;
-        writer.write_u8(crate::version::MAJOR)?;
-        writer.write_u8(crate::version::MINOR)?;
-        writer.write_u8(crate::version::PATCH)?;
-        writer.write_u8(0)?;
+        writer.write_u16::<LittleEndian>(crate::version::BYTECODE_FORMAT_VERSION)?;
+        writer.write_u8(self.opcode_set())?;
 
         // models
         writer.write_u32::<LittleEndian>(self.models.len() as u32)?;
@@ -269,8 +469,8 @@ impl Program {
                     writer.write_u32::<LittleEndian>(*function_index as u32)?;
                 },
                 _ => {
-                    // can't be here
-                    return Err(std::io::Error::from_raw_os_error(0));
+                    // compiler only makes the constants above, a program built by host may have others
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("constant {} is {} which can not be serialized", i, object.type_name())));
                 }
             }
         };
@@ -294,28 +494,90 @@ impl Program {
         // entry point
         writer.write_u32::<LittleEndian>(self.entry_point as u32)?;
 
+        // definitions, sorted so the same program always has the same bytecode
+        let mut definitions: Vec<(&String, &usize)> = self.definitions.iter().collect();
+        definitions.sort();
+
+        writer.write_u32::<LittleEndian>(definitions.len() as u32)?;
+        for (name, &constant_index) in definitions {
+            serialize_string(name, writer)?;
+            writer.write_u32::<LittleEndian>(constant_index as u32)?;
+        };
+
+        // optional sections
+        if let Some(file_info) = &self.file_info {
+            writer.write_u8(Program::SECTION_FILE_INFO)?;
+            file_info.serialize(writer)?;
+        };
+
+        if let Some(debug_info) = &self.debug_info {
+            writer.write_u8(Program::SECTION_DEBUG_INFO)?;
+            debug_info.serialize(writer)?;
+        };
+
+        writer.write_u8(Program::SECTION_END)?;
+
         Ok(())
     }
 
-    pub fn deserialize(reader: &mut dyn Read) -> Result<Program, std::io::Error> {
+    pub fn find_function(&self, name: &str) -> Option<usize> {
+        if let Some(Object::Function(function_index)) = self.definitions.get(name).and_then(|&constant_index| self.constants.get(constant_index)) {
+            Some(*function_index)
+        } else {
+            None
+        }
+    }
+
+    pub fn find_model(&self, name: &str) -> Option<usize> {
+        if let Some(Object::Model(model_index)) = self.definitions.get(name).and_then(|&constant_index| self.constants.get(constant_index)) {
+            Some(*model_index)
+        } else {
+            None
+        }
+    }
+
+    // the opcode set needed to run this program, it is written to the header of bytecode
+    pub fn opcode_set(&self) -> u8 {
+        self.functions.iter().flat_map(|function| function.instructions.iter()).map(|instruction| instruction.opcode().opcode_set()).max().unwrap_or(OPCODE_SET_BASE)
+    }
+
+    pub fn verify(&self) -> Result<(), BytecodeError> {
+        verify(self)
+    }
+
+    // readable listing of constants, models and instructions, use to debug the compiler
+    pub fn disassemble(&self) -> String {
+        disassemble(self)
+    }
+
+    // build a program from the text format written by disassemble, labels can be used as jump targets
+    pub fn assemble(source: &str, filename: &str) -> Result<Program, CompileErrorList> {
+        assemble(source, filename)
+    }
+
+    // remove file info and debug info, runtime errors will not have position and function names
+    pub fn strip_debug_info(&mut self) {
+        self.file_info = None;
+        self.debug_info = None;
+    }
+
+    // read and verify a program, the program is safe to run if no error
+    pub fn deserialize(reader: &mut dyn Read) -> Result<Program, BytecodeError> {
         if Program::HEADER != reader.read_u32::<LittleEndian>()? {
-            println!("warn: header not match");
+            return Err(BytecodeError::InvalidHeader);
         };
 
-        if crate::version::MAJOR != reader.read_u8()? {
-            println!("warn: major version not match");
-        };
+        // the layout after the version can not be read if the format does not match
+        let version = reader.read_u16::<LittleEndian>()?;
 
-        if crate::version::MINOR != reader.read_u8()? {
-            println!("warn: minor version not match");
+        if version != crate::version::BYTECODE_FORMAT_VERSION {
+            return Err(BytecodeError::VersionMismatch(version));
         };
 
-        if crate::version::PATCH != reader.read_u8()? {
-            println!("warn: patch version not match");
-        };
+        let opcode_set = reader.read_u8()?;
 
-        if 0 != reader.read_u8()? {
-            println!("warn: header end not match");
+        if opcode_set > OPCODE_SET_LATEST {
+            return Err(BytecodeError::UnsupportedOpcodeSet(opcode_set));
         };
 
         // models
@@ -358,8 +620,7 @@ impl Program {
                     Object::Function(reader.read_u32::<LittleEndian>()? as usize)
                 },
                 _ => {
-                    // can't be here
-                    return Err(std::io::Error::from_raw_os_error(0));
+                    return Err(BytecodeError::InvalidData(format!("unknown constant type {}", object_type)));
                 }
             };
 
@@ -385,7 +646,29 @@ impl Program {
 
         let entry_point = reader.read_u32::<LittleEndian>()? as usize;
 
-        Ok(Program {
+        let mut definitions = HashMap::new();
+        let definition_count = reader.read_u32::<LittleEndian>()?;
+        for _ in 0..definition_count {
+            let name = deserialize_string(reader)?;
+            let constant_index = reader.read_u32::<LittleEndian>()? as usize;
+            definitions.insert(name, constant_index);
+        };
+
+        let mut file_info = None;
+        let mut debug_info = None;
+
+        loop {
+            match reader.read_u8()? {
+                Program::SECTION_END => break,
+                Program::SECTION_FILE_INFO => file_info = Some(FileInfo::deserialize(reader)?),
+                Program::SECTION_DEBUG_INFO => debug_info = Some(DebugInfo::deserialize(reader)?),
+                section => {
+                    return Err(BytecodeError::InvalidData(format!("unknown section {}", section)));
+                }
+            };
+        };
+
+        let program = Program {
             models,
             functions,
             constants,
@@ -396,9 +679,19 @@ impl Program {
 
             entry_point,
 
-            file_info: None,
-            debug_info: None
-        })
+            definitions,
+
+            file_info,
+            debug_info
+        };
+
+        if program.opcode_set() > opcode_set {
+            return Err(BytecodeError::InvalidData("instruction is not in the opcode set of header".to_string()));
+        };
+
+        verify(&program)?;
+
+        Ok(program)
     }
 
 }
\ No newline at end of file
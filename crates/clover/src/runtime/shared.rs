@@ -0,0 +1,81 @@
+// the pointer and cell types behind Reference, the sync feature switches them to thread safe ones,
+// so State can be sent to another thread and Program can be shared by threads
+
+#[cfg(not(feature = "sync"))]
+pub use std::rc::{Rc as Shared, Weak};
+#[cfg(not(feature = "sync"))]
+pub use std::cell::{RefCell as Lock, Ref, RefMut};
+
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Shared, Weak};
+#[cfg(feature = "sync")]
+pub use self::lock::{Lock, Ref, RefMut};
+
+// natives and native instances must be Send and Sync in sync build
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+#[cfg(feature = "sync")]
+pub trait MaybeSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> MaybeSync for T {}
+
+#[cfg(feature = "sync")]
+mod lock {
+    use std::fmt;
+    use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    pub type Ref<'a, T> = RwLockReadGuard<'a, T>;
+    pub type RefMut<'a, T> = RwLockWriteGuard<'a, T>;
+
+    #[derive(Debug)]
+    pub struct BorrowError;
+
+    // same interface as RefCell, objects can be shared by states in different threads,
+    // so borrow waits for the other thread, try_borrow does not wait and fails when the lock is held
+    pub struct Lock<T: ?Sized>(RwLock<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Lock<T> {
+            Lock(RwLock::new(value))
+        }
+    }
+
+    impl<T: ?Sized> Lock<T> {
+        // a panic while borrowing does not make the value unusable, same as RefCell
+        pub fn borrow(&self) -> Ref<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn borrow_mut(&self) -> RefMut<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+            match self.0.try_read() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(error)) => Ok(error.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError)
+            }
+        }
+
+        pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowError> {
+            match self.0.try_write() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(error)) => Ok(error.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError)
+            }
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for Lock<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.try_borrow() {
+                Ok(value) => f.debug_struct("Lock").field("value", &&*value).finish(),
+                Err(_) => f.debug_struct("Lock").field("value", &"<borrowed>").finish()
+            }
+        }
+    }
+}
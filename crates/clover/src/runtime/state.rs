@@ -1,46 +1,103 @@
-use crate::runtime::program::{Program, RuntimeError};
-use std::collections::{HashMap, LinkedList};
-use crate::runtime::object::{Object, ModelInstance, Reference, make_reference, NativeModel, NativeFunction, NativeModelInstance};
+use crate::runtime::program::{Program, RuntimeError, RuntimeErrorKind, Handler, TraceFrame};
+use std::collections::HashMap;
+use indexmap::IndexMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::runtime::conversion::{TypedFunction, make_typed_function};
+use crate::runtime::object::{Object, ModelInstance, Reference, make_reference, NativeModel, NativeModelInstance, Closure, make_native_function, make_native_function_mut};
+use crate::runtime::program::Upvalue;
 use crate::intermediate::Position;
-use crate::runtime::opcode::{Instruction, OpCode};
+use crate::runtime::opcode::{Instruction, OpCode, OPERATION_ADD};
+use crate::runtime::operation::compare_numbers;
 use std::ops::Deref;
-use crate::runtime::object_property::{instance_get_array, instance_get_integer, instance_get_float, instance_get_string};
+use crate::runtime::shared::{Shared, MaybeSync};
+use crate::runtime::heap::{HeapMeasure, CycleCollector, object_size};
+use crate::runtime::object_property::{instance_get_array, instance_get_integer, instance_get_float, instance_get_string, instance_get_map, instance_set_map, index_get_map, index_set_map, instance_get_error};
+
+const STACK_CAPACITY: usize = 1024;
+const FRAME_CAPACITY: usize = 64;
 
 #[derive(Debug, Clone)]
 pub struct Frame {
-    pub locals: Vec<Object>,
+    // locals captured by closures, key is local index
+    pub cells: HashMap<usize, Reference<Object>>,
+    // the closure which is running in this frame
+    pub closure: Option<Reference<Closure>>,
     pub program_counter: usize,
     pub function_index: usize,
+    // locals of this frame are slots in the stack start from here, the objects pushed by this frame are after locals
+    pub local_base: usize,
+    // stack size to restore when this frame returns
     pub stack_size: usize
 }
 
 impl Frame {
-    pub fn new(local_count: usize, function_index: usize, stack_size: usize) -> Frame {
-        let mut locals = Vec::new();
-        for _ in 0..local_count {
-            locals.push(Object::Null);
-        };
-
+    pub fn new(function_index: usize, local_base: usize, stack_size: usize) -> Frame {
         Frame {
-            locals,
+            cells: HashMap::new(),
+            closure: None,
             program_counter: 0,
             function_index,
+            local_base,
             stack_size
         }
     }
 }
 
+// set the interrupt flag of a state from any thread, the state stops before the next instruction
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// limits for running untrusted scripts, None is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    // count of frames, includes the frames of nested executions
+    pub max_frames: Option<usize>,
+    // count of objects in the operand stack, locals are in the stack too
+    pub max_stack_size: Option<usize>,
+    // approximate bytes of strings, arrays, maps, instances and closures reachable by script
+    pub max_heap_size: Option<usize>
+}
+
 pub struct State {
     globals: HashMap<String, Object>,
     locals: Vec<Object>,
     native_models: Vec<Reference<dyn NativeModel>>,
-    stack: LinkedList<Object>,
-    frames: LinkedList<Frame>,
-    program: Program
+    stack: Vec<Object>,
+    frames: Vec<Frame>,
+    // reuse the buffer of parameters when calling native functions
+    native_parameters: Vec<Object>,
+    // instructions can be executed, None is unlimited
+    fuel: Option<u64>,
+    interrupted: Arc<AtomicBool>,
+    // count of nested executions, natives can execute script again
+    execution_depth: usize,
+    // frame size to run until when resuming the execution stopped by fuel or interrupt
+    suspended_frame_size: Option<usize>,
+    limits: Limits,
+    // bytes allocated since the heap is measured, plus the size measured
+    allocated_heap_size: usize,
+    collector: CycleCollector,
+    // addresses of native instances and models whose call is running, they stay borrowed until it returns
+    running_natives: Vec<usize>,
+    // states created from the same program share it, everything changed by script is in the state
+    program: Shared<Program>
 }
 
 impl From<Program> for State {
     fn from(program: Program) -> Self {
+        Shared::new(program).into()
+    }
+}
+
+impl From<Shared<Program>> for State {
+    fn from(program: Shared<Program>) -> Self {
         let mut locals = Vec::new();
 
         for i in 0..program.local_count {
@@ -55,8 +112,17 @@ impl From<Program> for State {
             globals: HashMap::new(),
             locals,
             native_models: Vec::new(),
-            stack: LinkedList::new(),
-            frames: LinkedList::new(),
+            stack: Vec::with_capacity(STACK_CAPACITY),
+            frames: Vec::with_capacity(FRAME_CAPACITY),
+            native_parameters: Vec::new(),
+            fuel: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            execution_depth: 0,
+            suspended_frame_size: None,
+            limits: Limits::default(),
+            allocated_heap_size: 0,
+            collector: CycleCollector::default(),
+            running_natives: Vec::new(),
             program
         }
     }
@@ -67,52 +133,88 @@ impl State {
         &self.program
     }
 
-    pub fn call_function_by_index(&mut self, function_index: usize, parameters: &[ Object ]) -> Result<(), RuntimeError> {
-        let function = self.program.functions.get(function_index).unwrap();
+    // create more states from it without cloning the program
+    pub fn get_shared_program(&self) -> Shared<Program> {
+        self.program.clone()
+    }
 
-        // function index is checked outside, no need to check here
-        if parameters.len() > function.parameter_count {
-            return Err(RuntimeError::new("too many parameters", Position::none()));
-        }
+    // the program of interactive mode grows after each input, the values of new or redefined locals are taken from it
+    pub(crate) fn update_program(&mut self, program: Program) {
+        for i in 0..program.local_count {
+            let constant_index = program.local_values.get(&i);
+
+            if i < self.locals.len() && constant_index == self.program.local_values.get(&i) {
+                continue;
+            };
 
-        let mut frame = Frame::new(function.local_count, function_index, self.stack.len());
+            let value = constant_index.map(|&constant_index| program.constants[constant_index].clone()).unwrap_or(Object::Null);
 
-        for (i, object) in parameters.iter().enumerate() {
-            frame.locals[i] = object.clone();
-        }
+            if i < self.locals.len() {
+                self.locals[i] = value;
+            } else {
+                self.locals.push(value);
+            };
+        };
+
+        self.program = Shared::new(program);
+    }
+
+    pub fn call_function_by_index(&mut self, function_index: usize, parameters: &[ Object ]) -> Result<(), RuntimeError> {
+        let stack_size = self.stack.len();
+
+        self.stack.extend_from_slice(parameters);
 
-        self.push_frame(frame);
+        if let Err(error) = self.push_function_frame(function_index, parameters.len(), stack_size) {
+            self.stack.truncate(stack_size);
+            return Err(error);
+        };
 
         Ok(())
     }
 
     pub fn current_frame_as_mut(&mut self) -> &mut Frame {
-        self.frames.back_mut().unwrap()
+        self.frames.last_mut().unwrap()
     }
 
     pub fn current_frame(&self) -> &Frame {
-        self.frames.back().unwrap()
+        self.frames.last().unwrap()
     }
 
     pub fn pop(&mut self) -> Option<Object> {
-        self.stack.pop_back()
+        self.stack.pop()
     }
 
     pub fn push(&mut self, object: Object) {
-        self.stack.push_back(object)
+        self.stack.push(object)
     }
 
     pub fn top(&self) -> Object {
-        self.stack.back().unwrap().clone()
+        self.stack.last().unwrap().clone()
+    }
+
+    pub(crate) fn get_local(&self, local_index: usize) -> Object {
+        self.stack[self.current_frame().local_base + local_index].clone()
+    }
+
+    pub(crate) fn set_local(&mut self, local_index: usize, object: Object) {
+        let index = self.current_frame().local_base + local_index;
+        self.stack[index] = object;
     }
 
     pub fn last_position(&self) -> Position {
-        let program_counter = self.current_frame().program_counter;
-        if let Some(debug_info) = &self.program.debug_info {
-            if program_counter > 0 {
-                let function_index = self.current_frame().function_index;
+        // natives can be called by host directly, there is no frame
+        if let Some(frame) = self.frames.last() {
+            self.frame_position(frame)
+        } else {
+            Position::none()
+        }
+    }
 
-                return debug_info.functions[function_index][program_counter - 1];
+    // position of the last executed instruction in frame
+    pub fn frame_position(&self, frame: &Frame) -> Position {
+        if let Some(debug_info) = &self.program.debug_info {
+            if frame.program_counter > 0 {
+                return debug_info.functions[frame.function_index][frame.program_counter - 1];
             };
         };
 
@@ -120,17 +222,198 @@ impl State {
     }
 
     fn execute_until_frame_size_equal(&mut self, frame_size: usize) -> Result<Object, RuntimeError> {
+        self.execution_depth += 1;
+        let result = self.run_until_frame_size_equal(frame_size);
+        self.execution_depth -= 1;
+
+        result
+    }
+
+    fn run_until_frame_size_equal(&mut self, frame_size: usize) -> Result<Object, RuntimeError> {
         while self.frames.len() != frame_size {
-            self.step()?;
+            if let Err(error) = self.check_budget() {
+                // a nested execution can not be resumed, the native function which runs it is already returned
+                if self.execution_depth > 1 {
+                    return Err(self.unwind(error, frame_size).unwrap_err());
+                };
+
+                self.suspended_frame_size = Some(frame_size);
+                return Err(error);
+            };
+
+            self.step_above(frame_size)?;
         };
 
         self.get_top()
     }
 
+    // limit the count of instructions to execute, None is unlimited
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn get_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupted.clone())
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_frame_size.is_some()
+    }
+
+    // continue the execution stopped by fuel or interrupt
+    pub fn resume(&mut self) -> Result<Object, RuntimeError> {
+        if let Some(frame_size) = self.suspended_frame_size.take() {
+            self.execute_until_frame_size_equal(frame_size)
+        } else {
+            Err(RuntimeError::new("there is no suspended execution", Position::none()))
+        }
+    }
+
+    // drop the execution stopped by fuel or interrupt
+    pub fn abort(&mut self) {
+        if let Some(frame_size) = self.suspended_frame_size.take() {
+            if let Some(frame) = self.frames.get(frame_size) {
+                self.stack.truncate(frame.stack_size);
+            };
+
+            self.frames.truncate(frame_size);
+        };
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        self.limits
+    }
+
+    // approximate bytes of the objects reachable from stack, locals, globals and frames
+    pub fn heap_size(&self) -> usize {
+        let mut measure = HeapMeasure::default();
+
+        for object in self.stack.iter().chain(self.locals.iter()).chain(self.globals.values()) {
+            measure.add_object(object);
+        };
+
+        for frame in self.frames.iter() {
+            for cell in frame.cells.values() {
+                measure.add_cell(cell);
+            };
+
+            if let Some(closure) = &frame.closure {
+                measure.add_closure(closure);
+            };
+        };
+
+        measure.size
+    }
+
+    // count the bytes just allocated by script, the new object must be reachable already
+    pub(crate) fn allocate(&mut self, size: usize) -> Result<(), RuntimeError> {
+        if let Some(max_heap_size) = self.limits.max_heap_size {
+            self.allocated_heap_size += size;
+
+            // garbage is counted too, measure again before raising the error
+            if self.allocated_heap_size > max_heap_size {
+                self.allocated_heap_size = self.heap_size();
+
+                if self.allocated_heap_size > max_heap_size {
+                    return Err(RuntimeError::with_kind(RuntimeErrorKind::LimitExceeded, "heap limit exceeded", self.last_position()));
+                };
+            };
+        };
+
+        Ok(())
+    }
+
+    // free the objects which are only referenced by each other, returns the count of objects freed
+    pub fn collect_garbage(&mut self) -> usize {
+        self.collector.collect()
+    }
+
+    // the object on the top of stack is just created by script
+    fn allocate_top(&mut self) -> Result<(), RuntimeError> {
+        self.collector.track(self.stack.last().unwrap());
+
+        // objects may be shared with other threads in sync build, only the host knows when collecting is safe
+        if self.collector.should_collect() {
+            if cfg!(feature = "sync") {
+                self.collector.prune();
+            } else {
+                self.collector.collect();
+            };
+        };
+
+        if self.limits.max_heap_size.is_none() {
+            return Ok(());
+        };
+
+        let size = object_size(self.stack.last().unwrap());
+        self.allocate(size)
+    }
+
+    fn check_budget(&mut self) -> Result<(), RuntimeError> {
+        if self.interrupted.load(Ordering::Relaxed) {
+            self.interrupted.store(false, Ordering::Relaxed);
+            return Err(self.stop_error(RuntimeErrorKind::Interrupted, "execution is interrupted"));
+        };
+
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                return Err(self.stop_error(RuntimeErrorKind::OutOfFuel, "out of fuel"));
+            };
+
+            *fuel -= 1;
+        };
+
+        Ok(())
+    }
+
+    // frames are kept, so the traceback is made from the running frames
+    fn stop_error(&self, kind: RuntimeErrorKind, message: &str) -> RuntimeError {
+        let mut error = RuntimeError::with_kind(kind, message, self.last_position());
+        error.traceback = self.frames.iter().map(|frame| self.trace_frame(frame)).collect();
+        error
+    }
+
+    pub fn trace_frame(&self, frame: &Frame) -> TraceFrame {
+        let (function_name, filename) = if let Some(file_info) = &self.program.file_info {
+            (
+                file_info.function_names.get(frame.function_index).cloned(),
+                file_info.function_files.get(frame.function_index).and_then(|&file_index| file_info.filenames.get(file_index)).cloned()
+            )
+        } else {
+            (None, None)
+        };
+
+        TraceFrame {
+            function_index: frame.function_index,
+            function_name,
+            filename,
+            position: self.frame_position(frame)
+        }
+    }
+
+    // the error stack starts from the innermost frame, the traceback starts from the outermost frame
+    pub fn make_traceback(&self, frames: &[Frame]) -> Vec<TraceFrame> {
+        frames.iter().rev().map(|frame| self.trace_frame(frame)).collect()
+    }
+
     pub fn execute_by_object(&mut self, object: Object, parameters: &[ Object ]) -> Result<Object, RuntimeError> {
         let frame_size = self.frames.len();
+        let stack_size = self.stack.len();
+
+        self.stack.extend_from_slice(parameters);
 
-        self.call_object(object, parameters)?;
+        if let Err(error) = self.call_object(object, parameters.len(), stack_size) {
+            self.stack.truncate(stack_size);
+            return Err(error);
+        };
 
         self.execute_until_frame_size_equal(frame_size)
     }
@@ -181,8 +464,42 @@ impl State {
         self.execute_by_function_index(self.program.entry_point, &[])
     }
 
-    pub fn add_native_function(&mut self, name: &str, function: NativeFunction)  {
-        self.globals.insert(name.to_string(), Object::NativeFunction(function));
+    pub fn add_native_function<F>(&mut self, name: &str, function: F)
+        where F: Fn(&mut State, &[Object]) -> Result<Object, RuntimeError> + MaybeSync + 'static
+    {
+        self.globals.insert(name.to_string(), Object::NativeFunction(make_native_function(function)));
+    }
+
+    pub fn add_native_function_mut<F>(&mut self, name: &str, function: F)
+        where F: FnMut(&mut State, &[Object]) -> Result<Object, RuntimeError> + MaybeSync + 'static
+    {
+        self.globals.insert(name.to_string(), Object::NativeFunction(make_native_function_mut(function)));
+    }
+
+    // register a rust function with typed parameters, the parameters are checked before calling
+    pub fn add_typed_function<F, Parameters>(&mut self, name: &str, function: F)
+        where F: TypedFunction<Parameters> + MaybeSync + 'static, Parameters: 'static
+    {
+        self.globals.insert(name.to_string(), Object::NativeFunction(make_typed_function(function)));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        self.globals.get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, object: Object) {
+        self.globals.insert(name.to_string(), object);
+    }
+
+    // call a top level function or model of the program by name
+    pub fn call(&mut self, name: &str, parameters: &[ Object ]) -> Result<Object, RuntimeError> {
+        let object = if let Some(&constant_index) = self.program.definitions.get(name) {
+            self.program.constants[constant_index].clone()
+        } else {
+            return Err(RuntimeError::new(&format!("can not found function [{}]", name), Position::none()));
+        };
+
+        self.execute_by_object(object, parameters)
     }
 
     pub fn add_native_model(&mut self, name: &str, native_model: Reference<dyn NativeModel>) -> usize {
@@ -195,35 +512,75 @@ impl State {
     }
 
     pub fn step(&mut self) -> Result<(), RuntimeError> {
-        if let Err(mut error) = self.internal_step() {
+        self.check_budget()?;
+        self.step_above(0)
+    }
 
-            let mut call_stack = LinkedList::new();
+    // frames below frame_size belong to an outer execution, like the script which calls a native function which calls script again,
+    // the error is returned to the outer execution before unwinding them
+    fn step_above(&mut self, frame_size: usize) -> Result<(), RuntimeError> {
+        if let Err(error) = self.internal_step() {
+            self.unwind(error, frame_size)
+        } else {
+            Ok(())
+        }
+    }
 
-            while self.frames.len() > 0 {
-                let rescue_position = self.program.functions.get(self.current_frame().function_index).unwrap().rescue_position;
+    // jump to the handler which rescues the error, or pop frames until frame_size and return the error
+    fn unwind(&mut self, mut error: RuntimeError, frame_size: usize) -> Result<(), RuntimeError> {
+        // an error raised again already has the call stack from where it occurs, include the current frame
+        let mut call_stack = std::mem::take(&mut error.stack);
+        let mut skip_current_frame = call_stack.last().is_some_and(|frame| frame.function_index == self.current_frame().function_index && frame.local_base == self.current_frame().local_base);
 
-                if rescue_position > 0 {
-                    self.current_frame_as_mut().program_counter = rescue_position;
-                    return Ok(());
-                } else {
-                    let frame = self.frames.pop_back().unwrap();
-                    while self.stack.len() > frame.stack_size {
-                        self.stack.pop_back();
-                    };
-                    call_stack.push_front(frame);
-                }
-            }
+        while self.frames.len() > frame_size {
+            let handler = if error.kind.is_rescuable() { self.find_handler(self.current_frame()) } else { None };
+
+            if let Some(handler) = handler {
+                if !skip_current_frame {
+                    call_stack.push(self.current_frame().clone());
+                };
+                error.stack = call_stack;
 
-            error.stack = call_stack;
+                let local_count = self.program.functions[self.current_frame().function_index].local_count;
+                self.stack.truncate(self.current_frame().local_base + local_count + handler.stack_size);
 
-            Err(error)
-        } else {
-            Ok(())
+                // rescue block will take the error object from stack
+                self.push(Object::Error(make_reference(error)));
+
+                self.current_frame_as_mut().program_counter = handler.target;
+                return Ok(());
+            };
+
+            let frame = self.frames.pop().unwrap();
+            self.stack.truncate(frame.stack_size);
+
+            if !skip_current_frame {
+                call_stack.push(frame);
+            };
+            skip_current_frame = false;
         }
+
+        error.stack = call_stack;
+        error.traceback = self.make_traceback(&error.stack);
+
+        Err(error)
     }
 }
 
+// identity of a native, Reference of trait object is a fat pointer, only the data part is kept
+fn reference_address<T: ?Sized>(reference: &Reference<T>) -> usize {
+    Shared::as_ptr(reference) as *const () as usize
+}
+
 impl State {
+    // find the handler which protects the executing instruction of frame
+    fn find_handler(&self, frame: &Frame) -> Option<Handler> {
+        let function = &self.program.functions[frame.function_index];
+        let position = frame.program_counter.checked_sub(1)?;
+
+        function.handlers.iter().find(|handler| handler.start <= position && position < handler.end).copied()
+    }
+
     fn get_top(&mut self) -> Result<Object, RuntimeError> {
         if let Some(object) = self.pop() {
             Ok(object)
@@ -232,76 +589,146 @@ impl State {
         }
     }
 
-    fn call_model_by_index(&mut self, model_index: usize, parameters: &[ Object ]) -> Result<(), RuntimeError> {
-        let model = self.program.models.get(model_index).unwrap();
-        if parameters.len() > model.property_indices.len() {
+    // the parameters are on the top of stack, the stack will be restored to stack_size when the call returns
+    fn push_function_frame(&mut self, function_index: usize, parameter_count: usize, stack_size: usize) -> Result<(), RuntimeError> {
+        let function = &self.program.functions[function_index];
+
+        if parameter_count > function.parameter_count {
             return Err(RuntimeError::new("too many parameters", Position::none()));
         };
 
-        let mut properties = parameters.iter().cloned().collect::<Vec<Object>>();
+        if self.limits.max_frames.is_some_and(|max_frames| self.frames.len() >= max_frames) {
+            return Err(RuntimeError::with_kind(RuntimeErrorKind::LimitExceeded, "frame limit exceeded", self.last_position()));
+        };
+
+        let local_base = self.stack.len() - parameter_count;
 
-        for _ in properties.len()..model.property_indices.len() {
-            properties.push(Object::Null);
+        // locals are allocated when entering the function, the operand stack of each function is small
+        if self.limits.max_stack_size.is_some_and(|max_stack_size| local_base + function.local_count > max_stack_size) {
+            return Err(RuntimeError::with_kind(RuntimeErrorKind::LimitExceeded, "stack limit exceeded", self.last_position()));
         };
 
+        self.stack.resize(local_base + function.local_count, Object::Null);
+
+        self.frames.push(Frame::new(function_index, local_base, stack_size));
+
+        Ok(())
+    }
+
+    fn call_model_by_index(&mut self, model_index: usize, parameter_count: usize, stack_size: usize) -> Result<(), RuntimeError> {
+        let model = self.program.models.get(model_index).unwrap();
+        if parameter_count > model.property_indices.len() {
+            return Err(RuntimeError::new("too many parameters", Position::none()));
+        };
+
+        let property_count = model.property_indices.len();
+
+        let mut properties: Vec<Object> = self.stack.drain(self.stack.len() - parameter_count..).collect();
+        properties.resize(property_count, Object::Null);
+
+        self.stack.truncate(stack_size);
+
         self.push(Object::Instance(make_reference(ModelInstance {
             model_index,
             properties
         })));
 
-        Ok(())
+        self.allocate_top()
     }
 
-    fn call_native_model_by_index(&mut self, model_index: usize, parameters: &[ Object ]) -> Result<(), RuntimeError> {
-        let native_model = self.native_models.get(model_index).unwrap().clone();
+    fn call_native_object(&mut self, object: Object, parameter_count: usize, stack_size: usize) -> Result<(), RuntimeError> {
+        // native function may call back to script and call another native function, so take the buffer out
+        let mut parameters = std::mem::take(&mut self.native_parameters);
+        parameters.extend(self.stack.drain(self.stack.len() - parameter_count..));
 
-        let result = native_model.borrow_mut().call(self, parameters)?;
+        self.stack.truncate(stack_size);
 
-        self.push(result);
+        let result = match object {
+            Object::NativeFunction(function) => function(self, &parameters),
+            Object::InstanceNativeFunction(instance, function_name) => self.call_native_instance(instance, &function_name, &parameters),
+            Object::NativeModel(model_index) => {
+                let native_model = self.native_models.get(model_index).unwrap().clone();
+                self.call_native_model(native_model, &parameters)
+            },
+            _ => Err(RuntimeError::new(&format!("can not call {:?}", object), self.last_position()))
+        };
 
-        Ok(())
+        parameters.clear();
+        self.native_parameters = parameters;
+
+        self.push(result?);
+
+        self.allocate_top()
     }
 
-    fn call_native_function(&mut self, function: NativeFunction, parameters: &[ Object ]) -> Result<(), RuntimeError> {
-            let result = function(self, parameters)?;
-            self.push(result);
+    // the borrow is held while native calls back to script, touching the same native again would wait for itself
+    fn ensure_native_not_running(&self, address: usize) -> Result<(), RuntimeError> {
+        if self.running_natives.contains(&address) {
+            Err(RuntimeError::new("native instance is already borrowed", self.last_position()))
+        } else {
             Ok(())
+        }
     }
 
-    fn call_instance_native_function(&mut self, instance: Reference<dyn NativeModelInstance>, function_name: &str, parameters: &[ Object ]) -> Result<(), RuntimeError> {
+    fn call_native_instance(&mut self, instance: Reference<dyn NativeModelInstance>, function_name: &str, parameters: &[Object]) -> Result<Object, RuntimeError> {
+        let address = reference_address(&instance);
+        self.ensure_native_not_running(address)?;
+
+        self.running_natives.push(address);
         let instance_copy = instance.clone();
-        let result = instance.borrow_mut().call(instance_copy, self, function_name, parameters)?;
-        self.push(result);
-        Ok(())
+        let result = instance.borrow_mut().call(instance_copy, self, function_name, parameters);
+        self.running_natives.pop();
+
+        result
+    }
+
+    fn call_native_model(&mut self, native_model: Reference<dyn NativeModel>, parameters: &[Object]) -> Result<Object, RuntimeError> {
+        let address = reference_address(&native_model);
+        self.ensure_native_not_running(address)?;
+
+        self.running_natives.push(address);
+        let result = native_model.borrow_mut().call(self, parameters);
+        self.running_natives.pop();
+
+        result
     }
 
-    fn call_object(&mut self, object: Object, parameters: &[ Object ]) -> Result<(), RuntimeError> {
+    // call object with the parameters on the top of stack
+    fn call_object(&mut self, object: Object, parameter_count: usize, stack_size: usize) -> Result<(), RuntimeError> {
         match object {
-            Object::Function(function_index) => self.call_function_by_index(function_index, parameters),
-            Object::InstanceFunction(model, function_index) => self.call_function_by_index(function_index,&make_instance_call_parameters(model.deref().clone(), parameters)),
-            Object::NativeFunction(function) => self.call_native_function(function, parameters),
-            Object::InstanceNativeFunction(instance, function_name) => self.call_instance_native_function(instance, &function_name, parameters),
-            Object::NativeModel(model_index) => self.call_native_model_by_index(model_index, parameters),
-            Object::Model(model_index) => self.call_model_by_index(model_index, parameters),
+            Object::Function(function_index) => self.push_function_frame(function_index, parameter_count, stack_size),
+            Object::Closure(closure) => {
+                let function_index = closure.borrow().function_index;
+
+                self.push_function_frame(function_index, parameter_count, stack_size)?;
+                self.current_frame_as_mut().closure = Some(closure);
+
+                Ok(())
+            },
+            Object::InstanceFunction(this, function_index) => {
+                // the instance is the first parameter of instance function
+                let position = self.stack.len() - parameter_count;
+                self.stack.insert(position, this.deref().clone());
+
+                self.push_function_frame(function_index, parameter_count + 1, stack_size)
+            },
+            Object::Model(model_index) => self.call_model_by_index(model_index, parameter_count, stack_size),
+            Object::NativeFunction(_) | Object::InstanceNativeFunction(_, _) | Object::NativeModel(_) => self.call_native_object(object, parameter_count, stack_size),
             _ => Err(RuntimeError::new(&format!("can not call {:?}", object), self.last_position()))
         }
     }
 
     fn execute_call_opcode(&mut self, parameter_count: usize) -> Result<(), RuntimeError> {
-        let mut parameters = vec![Object::Null; parameter_count];
-
-        for i in (0..parameter_count).rev() {
-            parameters[i] = self.stack.pop_back().unwrap();
-        };
-
-        let function_object = self.stack.pop_back().unwrap();
+        // the function object is below the parameters, it will be removed when the call returns
+        let stack_size = self.stack.len() - parameter_count - 1;
+        let function_object = self.stack[stack_size].clone();
 
-        self.call_object(function_object, &parameters)
+        self.call_object(function_object, parameter_count, stack_size)
     }
 
     fn current_instruction(&self) -> Instruction {
         let (function_index, program_counter) = {
-            let frame = self.frames.back().unwrap();
+            let frame = self.frames.last().unwrap();
             (frame.function_index, frame.program_counter)
         };
 
@@ -310,29 +737,20 @@ impl State {
         function.instructions[program_counter]
     }
 
-    fn push_frame(&mut self, frame: Frame) {
-        self.frames.push_back(frame);
-    }
-
     fn pop_frame(&mut self) {
-        let frame = self.frames.pop_back().unwrap();
-
-        if self.stack.len() > frame.stack_size + 1 {
-            return;
-        };
+        let frame = self.frames.pop().unwrap();
 
         let return_value = self.pop().unwrap();
 
-        // clean up stack
-        while self.stack.len() > frame.stack_size {
-            self.pop();
-        };
+        // clean up locals and objects left by the frame
+        self.stack.truncate(frame.stack_size);
 
         self.push(return_value);
     }
 
     fn instance_get_native_model(&mut self, model_index: usize, key: &str) -> Result<(), RuntimeError> {
-        let model = self.native_models.get(model_index).unwrap();
+        let model = self.native_models.get(model_index).unwrap().clone();
+        self.ensure_native_not_running(reference_address(&model))?;
         let result = model.borrow().model_get(key)?;
         self.push(result);
 
@@ -340,6 +758,7 @@ impl State {
     }
 
     fn instance_get_native_instance(&mut self, instance: Reference<dyn NativeModelInstance>, key: &str) -> Result<(), RuntimeError> {
+        self.ensure_native_not_running(reference_address(&instance))?;
         let instance_copy = instance.clone();
         let result = instance.borrow().instance_get(instance_copy, key)?;
         self.push(result);
@@ -358,6 +777,8 @@ impl State {
             Object::String(value) => instance_get_string(self, value, index.as_reference_string().borrow().deref())?,
 
             Object::Array(array) => instance_get_array(self, array, index.as_reference_string().borrow().deref())?,
+            Object::Map(map) => instance_get_map(self, map, index.as_reference_string().borrow().deref())?,
+            Object::Error(error) => instance_get_error(self, error, index.as_reference_string().borrow().deref())?,
             _ => {
                 return Err(RuntimeError::new("this object's instance get did not implemented yet", self.last_position()));
             }
@@ -446,7 +867,9 @@ impl State {
             Object::Model(model_index) => self.index_get_model(model_index, &index)?,
             Object::Instance(model_instance) => self.index_get_model_instance(model_instance, &index)?,
             Object::Array(array) => self.index_get_array(array, &index)?,
+            Object::Map(map) => index_get_map(self, map, &index)?,
             Object::NativeInstance(instance) => {
+                self.ensure_native_not_running(reference_address(&instance))?;
                 let instance_copy = instance.clone();
                 self.push(instance.borrow_mut().index_get(instance_copy, &index)?);
             }
@@ -465,9 +888,11 @@ impl State {
         match instance {
             Object::Instance(model_instance) => self.index_set_model_instance(model_instance, &index)?,
             Object::NativeInstance(instance) => {
+                self.ensure_native_not_running(reference_address(&instance))?;
                 let instance_copy = instance.clone();
                 instance.borrow_mut().instance_set(instance_copy, index.as_reference_string().borrow().deref(), self.top())?
             },
+            Object::Map(map) => instance_set_map(self, map, index.as_reference_string().borrow().deref())?,
             _ => {
                 return Err(RuntimeError::new("this object's instance set did not implemented yet", self.last_position()));
             }
@@ -477,7 +902,7 @@ impl State {
     }
 
     fn index_set_model_instance_by_index(&mut self, model_instance: Reference<ModelInstance>, index: usize) -> Result<(), RuntimeError> {
-        if let Some(object) = model_instance.borrow_mut().properties.get_mut(index as usize) {
+        if let Some(object) = model_instance.borrow_mut().properties.get_mut(index) {
             *object = self.top();
             Ok(())
         } else {
@@ -525,10 +950,12 @@ impl State {
         match instance {
             Object::Instance(model_instance) => self.index_set_model_instance(model_instance, &index)?,
             Object::NativeInstance(instance) => {
+                self.ensure_native_not_running(reference_address(&instance))?;
                 let instance_copy = instance.clone();
                 instance.borrow_mut().index_set(instance_copy, &index, self.top())?
             },
             Object::Array(array) => self.index_set_array(array, &index)?,
+            Object::Map(map) => index_set_map(self, map, &index)?,
             _ => {
                 return Err(RuntimeError::new("this object's instance set did not implemented yet", self.last_position()));
             }
@@ -548,14 +975,91 @@ impl State {
 
         self.push(Object::Array(make_reference(array)));
 
-        Ok(())
+        self.allocate_top()
+    }
+
+    // get the cell of a captured local, the local will move into a new cell at first time
+    fn get_cell(&mut self, local_index: usize) -> Reference<Object> {
+        if let Some(cell) = self.current_frame().cells.get(&local_index) {
+            return cell.clone();
+        };
+
+        let cell = make_reference(self.get_local(local_index));
+        self.current_frame_as_mut().cells.insert(local_index, cell.clone());
+
+        cell
+    }
+
+    // the verifier makes sure functions with upvalues only run as closures, a function called by host directly may not
+    fn get_upvalue(&self, upvalue_index: usize) -> Result<Reference<Object>, RuntimeError> {
+        self.current_frame().closure.as_ref()
+            .and_then(|closure| closure.borrow().upvalues.get(upvalue_index).cloned())
+            .ok_or_else(|| RuntimeError::new("upvalue not found, the function is not called as closure", self.last_position()))
+    }
+
+    fn push_closure(&mut self, function_index: usize) -> Result<(), RuntimeError> {
+        let upvalue_sources = self.program.functions[function_index].upvalues.clone();
+
+        let mut upvalues = Vec::new();
+
+        for upvalue_source in upvalue_sources {
+            upvalues.push(match upvalue_source {
+                Upvalue::Local(local_index) => self.get_cell(local_index),
+                Upvalue::Upvalue(upvalue_index) => self.get_upvalue(upvalue_index)?
+            });
+        };
+
+        self.push(Object::Closure(make_reference(Closure {
+            function_index,
+            upvalues
+        })));
+
+        self.allocate_top()
     }
 
     fn binary_operation(&mut self, operand: usize) -> Result<(), RuntimeError> {
         let right = self.pop().unwrap();
         let left = self.pop().unwrap();
 
-        self.binary_operation_with_parameters(&left, &right, operand)?;
+        self.binary_operation_with_objects(&left, &right, operand)
+    }
+
+    fn binary_operation_with_objects(&mut self, left: &Object, right: &Object, operand: usize) -> Result<(), RuntimeError> {
+        let frame_count = self.frames.len();
+
+        self.binary_operation_with_parameters(left, right, operand)?;
+
+        // meta methods push a frame instead of the result, only concatenation creates objects
+        if self.frames.len() == frame_count && matches!(self.stack.last(), Some(Object::String(_))) {
+            self.allocate_top()?;
+        };
+
+        Ok(())
+    }
+
+    fn add_constant(&mut self, constant_index: usize) -> Result<(), RuntimeError> {
+        let left = self.pop().unwrap();
+        let right = self.program.constants[constant_index].clone();
+
+        self.binary_operation_with_objects(&left, &right, OPERATION_ADD)
+    }
+
+    // the next instruction is JumpIf or JumpIfNot, numbers jump by it here and skip it
+    // the result of other objects may come from a meta method, so it is pushed for the next instruction to check
+    fn compare_jump(&mut self, operand: usize) -> Result<(), RuntimeError> {
+        let length = self.stack.len();
+
+        let result = if let Some(result) = compare_numbers(&self.stack[length - 2], &self.stack[length - 1], operand) { result } else {
+            return self.binary_operation(operand);
+        };
+
+        self.stack.truncate(length - 2);
+
+        let jump_instruction = self.current_instruction();
+        let should_jump = if jump_instruction.opcode() == OpCode::JumpIf { result } else { !result };
+
+        let frame = self.current_frame_as_mut();
+        frame.program_counter = if should_jump { jump_instruction.operand() as usize } else { frame.program_counter + 1 };
 
         Ok(())
     }
@@ -567,16 +1071,51 @@ impl State {
         self.current_frame_as_mut().program_counter += 1;
 
         match opcode {
-            OpCode::Pop => { self.stack.pop_back(); },
+            OpCode::Pop => { self.stack.pop(); },
             OpCode::PushConstant => {
                 let constant = self.program.constants[instruction.operand() as usize].clone();
                 self.push(constant);
             },
             OpCode::Return => { self.pop_frame(); },
+            OpCode::Raise => {
+                let value = self.pop().unwrap();
+
+                // raise an error object again keeps the original error
+                if let Object::Error(error) = value {
+                    return Err(error.borrow().clone());
+                };
+
+                return Err(RuntimeError::raise(value, self.last_position()));
+            },
+
+            OpCode::LocalGet => self.push(self.get_local(instruction.operand() as usize)),
+            OpCode::LocalSet => self.set_local(instruction.operand() as usize, self.top()),
+            OpCode::LocalInit => {
+                let object = self.pop().unwrap();
+                self.set_local(instruction.operand() as usize, object);
+            },
+
+            OpCode::CellGet => {
+                let cell = self.get_cell(instruction.operand() as usize);
+                self.push(cell.borrow().clone());
+            },
+            OpCode::CellSet => {
+                let cell = self.get_cell(instruction.operand() as usize);
+                *cell.borrow_mut() = self.top();
+            },
+            OpCode::CellInit => {
+                let object = self.pop().unwrap();
+                self.current_frame_as_mut().cells.insert(instruction.operand() as usize, make_reference(object));
+            },
 
-            OpCode::LocalGet => self.push(self.current_frame().locals.get(instruction.operand() as usize).unwrap().clone()),
-            OpCode::LocalSet => { self.current_frame_as_mut().locals[instruction.operand() as usize] = self.top(); },
-            OpCode::LocalInit => { self.current_frame_as_mut().locals[instruction.operand() as usize] = self.pop().unwrap(); },
+            OpCode::UpvalueGet => {
+                let upvalue = self.get_upvalue(instruction.operand() as usize)?;
+                self.push(upvalue.borrow().clone());
+            },
+            OpCode::UpvalueSet => {
+                let upvalue = self.get_upvalue(instruction.operand() as usize)?;
+                *upvalue.borrow_mut() = self.top();
+            },
 
             OpCode::ContextGet => self.push(self.locals.get(instruction.operand() as usize).unwrap().clone()),
             OpCode::ContextSet => { self.locals[instruction.operand() as usize] = self.top(); },
@@ -600,7 +1139,7 @@ impl State {
             OpCode::GlobalSet => {
                 if let Some(Object::String(global_name)) = self.program.constants.get(instruction.operand() as usize) {
                     if let Some(object) = self.globals.get_mut(global_name.borrow().deref()) {
-                        *object = self.stack.back().unwrap().clone();
+                        *object = self.stack.last().unwrap().clone();
                     } else {
                         return Err(RuntimeError::new("global not found", self.last_position()));
                     }
@@ -610,9 +1149,16 @@ impl State {
             OpCode::InstanceSet => self.instance_set()?,
             OpCode::IndexGet => self.index_get()?,
             OpCode::IndexSet => self.index_set()?,
+            OpCode::Closure => self.push_closure(instruction.operand() as usize)?,
             OpCode::Call => self.execute_call_opcode(instruction.operand() as usize)?,
             OpCode::Array => self.push_array(instruction.operand() as usize)?,
+            OpCode::PushNewMap => {
+                self.push(Object::Map(make_reference(IndexMap::new())));
+                self.allocate_top()?;
+            },
             OpCode::Operation => self.binary_operation(instruction.operand() as usize)?,
+            OpCode::AddConstant => self.add_constant(instruction.operand() as usize)?,
+            OpCode::CompareJump => self.compare_jump(instruction.operand() as usize)?,
             OpCode::Not => {
                 let value = Object::Boolean(!self.pop().unwrap().to_bool());
                 self.push(value);
@@ -628,6 +1174,12 @@ impl State {
                     self.current_frame_as_mut().program_counter = instruction.operand() as usize;
                 };
             },
+            OpCode::JumpIfNot => {
+                let object = self.pop().unwrap();
+                if !object.to_bool() {
+                    self.current_frame_as_mut().program_counter = instruction.operand() as usize;
+                };
+            },
             OpCode::ForNext => { self.for_next(instruction.operand() as usize)?; },
             OpCode::Iterate => { self.iterate(instruction.operand() as usize); },
             _ => {
@@ -639,9 +1191,3 @@ impl State {
     }
 }
 
-// helpers
-fn make_instance_call_parameters(object: Object, parameters: &[ Object ]) -> Vec<Object> {
-    let mut new_parameters = vec![ object ];
-    new_parameters.extend_from_slice(parameters);
-    new_parameters
-}
\ No newline at end of file
@@ -0,0 +1,261 @@
+use crate::runtime::program::{Program, Function, BytecodeError, Upvalue};
+use crate::runtime::opcode::OpCode;
+use crate::runtime::object::Object;
+use crate::runtime::opcode::{OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, OPERATION_DIVIDE, OPERATION_MOD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL, OPERATION_AND, OPERATION_OR};
+
+const OPERATIONS: &[ usize ] = &[ OPERATION_ADD, OPERATION_SUB, OPERATION_MULTIPLY, OPERATION_DIVIDE, OPERATION_MOD, OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL, OPERATION_AND, OPERATION_OR ];
+const COMPARISONS: &[ usize ] = &[ OPERATION_EQUAL, OPERATION_GREATER, OPERATION_LESS, OPERATION_GREATER_EQUAL, OPERATION_LESS_EQUAL ];
+
+fn invalid_data(message: &str) -> BytecodeError {
+    BytecodeError::InvalidData(message.to_string())
+}
+
+fn invalid_instruction(function_index: usize, instruction_index: usize, message: &str) -> BytecodeError {
+    BytecodeError::InvalidInstruction(function_index, instruction_index, message.to_string())
+}
+
+// check everything the state will access without checking, so a loaded program can not make the state panic
+pub fn verify(program: &Program) -> Result<(), BytecodeError> {
+    verify_program(program)?;
+
+    for (function_index, function) in program.functions.iter().enumerate() {
+        verify_function(program, function_index, function)?;
+    };
+
+    Ok(())
+}
+
+fn verify_program(program: &Program) -> Result<(), BytecodeError> {
+    if program.entry_point >= program.functions.len() {
+        return Err(invalid_data("entry point out of range"));
+    };
+
+    // a function with upvalues can only run as a closure, the upvalues are captured by Closure instruction
+    let has_upvalues = |function_index: usize| !program.functions[function_index].upvalues.is_empty();
+
+    if has_upvalues(program.entry_point) {
+        return Err(invalid_data("entry point has upvalues"));
+    };
+
+    for constant in &program.constants {
+        match constant {
+            Object::Model(model_index) if *model_index >= program.models.len() => return Err(invalid_data("model constant out of range")),
+            Object::Function(function_index) if *function_index >= program.functions.len() => return Err(invalid_data("function constant out of range")),
+            Object::Function(function_index) if has_upvalues(*function_index) => return Err(invalid_data("function constant has upvalues")),
+            _ => {}
+        };
+    };
+
+    for model in &program.models {
+        if model.functions.values().any(|&function_index| function_index >= program.functions.len()) {
+            return Err(invalid_data("model function out of range"));
+        };
+
+        if model.functions.values().any(|&function_index| has_upvalues(function_index)) {
+            return Err(invalid_data("model function has upvalues"));
+        };
+    };
+
+    for &constant_index in program.definitions.values() {
+        if !matches!(program.constants.get(constant_index), Some(Object::Function(_)) | Some(Object::Model(_))) {
+            return Err(invalid_data("definition is not a function or model constant"));
+        };
+    };
+
+    for &global_index in &program.global_dependencies {
+        if !matches!(program.constants.get(global_index), Some(Object::String(_))) {
+            return Err(invalid_data("global dependency is not a string constant"));
+        };
+    };
+
+    for (&local_index, &constant_index) in &program.local_values {
+        if local_index >= program.local_count || constant_index >= program.constants.len() {
+            return Err(invalid_data("local value out of range"));
+        };
+    };
+
+    if let Some(file_info) = &program.file_info {
+        if file_info.function_names.len() != program.functions.len() || file_info.function_files.len() != program.functions.len() {
+            return Err(invalid_data("function count of file info does not match"));
+        };
+
+        if file_info.model_names.len() != program.models.len() || file_info.model_files.len() != program.models.len() {
+            return Err(invalid_data("model count of file info does not match"));
+        };
+
+        if file_info.function_files.iter().chain(file_info.model_files.iter()).any(|&file_index| file_index >= file_info.filenames.len()) {
+            return Err(invalid_data("file index out of range"));
+        };
+    };
+
+    if let Some(debug_info) = &program.debug_info {
+        if debug_info.functions.len() != program.functions.len() {
+            return Err(invalid_data("function count of debug info does not match"));
+        };
+
+        for (positions, function) in debug_info.functions.iter().zip(program.functions.iter()) {
+            if positions.len() != function.instructions.len() {
+                return Err(invalid_data("position count of debug info does not match"));
+            };
+        };
+    };
+
+    Ok(())
+}
+
+fn verify_closure(program: &Program, function: &Function, closure_function_index: usize) -> bool {
+    if let Some(closure_function) = program.functions.get(closure_function_index) {
+        // upvalues are captured from the function which creates the closure
+        closure_function.upvalues.iter().all(|upvalue| match upvalue {
+            Upvalue::Local(local_index) => *local_index < function.local_count,
+            Upvalue::Upvalue(upvalue_index) => *upvalue_index < function.upvalues.len()
+        })
+    } else {
+        false
+    }
+}
+
+fn verify_operands(program: &Program, function_index: usize, function: &Function) -> Result<(), BytecodeError> {
+    let instruction_count = function.instructions.len();
+
+    for (index, instruction) in function.instructions.iter().enumerate() {
+        let opcode = instruction.opcode();
+        let operand = instruction.operand() as usize;
+
+        let is_valid = match opcode {
+            OpCode::PushConstant | OpCode::AddConstant => operand < program.constants.len(),
+            OpCode::LocalSet | OpCode::LocalGet | OpCode::LocalInit |
+            OpCode::CellSet | OpCode::CellGet | OpCode::CellInit |
+            OpCode::Iterate => operand < function.local_count,
+            // ForNext uses the enumerable local and the iterator local after it
+            OpCode::ForNext => operand < function.local_count && operand + 1 < function.local_count,
+            OpCode::ContextSet | OpCode::ContextGet => operand < program.local_count,
+            OpCode::UpvalueSet | OpCode::UpvalueGet => operand < function.upvalues.len(),
+            OpCode::GlobalSet | OpCode::GlobalGet => matches!(program.constants.get(operand), Some(Object::String(_))),
+            OpCode::Closure => verify_closure(program, function, operand),
+            OpCode::Operation => OPERATIONS.contains(&operand),
+            OpCode::CompareJump => COMPARISONS.contains(&operand),
+            OpCode::Jump | OpCode::JumpIf | OpCode::JumpIfNot => operand < instruction_count,
+            OpCode::Unknown => return Err(invalid_instruction(function_index, index, "unknown opcode")),
+            _ => true
+        };
+
+        if !is_valid {
+            return Err(invalid_instruction(function_index, index, &format!("invalid operand {} for {:?}", operand, opcode)));
+        };
+
+        if opcode == OpCode::ForNext && !matches!(function.instructions.get(index + 1).map(|next| next.opcode()), Some(OpCode::JumpIf)) {
+            return Err(invalid_instruction(function_index, index, "ForNext must be followed by JumpIf"));
+        };
+
+        if opcode == OpCode::CompareJump && !matches!(function.instructions.get(index + 1).map(|next| next.opcode()), Some(OpCode::JumpIf | OpCode::JumpIfNot)) {
+            return Err(invalid_instruction(function_index, index, "CompareJump must be followed by JumpIf or JumpIfNot"));
+        };
+    };
+
+    Ok(())
+}
+
+// make sure every instruction has the same stack size from every path, and never pop more than it has
+fn verify_stack(function_index: usize, function: &Function) -> Result<(), BytecodeError> {
+    let instructions = &function.instructions;
+    let mut stack_sizes: Vec<Option<usize>> = vec![None; instructions.len()];
+
+    // the JumpIf after ForNext can only be reached from ForNext
+    let mut is_for_condition = vec![false; instructions.len()];
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.opcode() == OpCode::ForNext {
+            is_for_condition[index + 1] = true;
+        };
+    };
+
+    let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+
+    // the error object is pushed when jumping to a handler
+    for handler in &function.handlers {
+        pending.push((handler.target, handler.stack_size + 1));
+    };
+
+    while let Some((index, stack_size)) = pending.pop() {
+        if index >= instructions.len() {
+            return Err(invalid_instruction(function_index, index, "execution runs out of function"));
+        };
+
+        if is_for_condition[index] {
+            return Err(invalid_instruction(function_index, index, "jump into the condition of for loop"));
+        };
+
+        if let Some(existing_stack_size) = stack_sizes[index] {
+            if existing_stack_size != stack_size {
+                return Err(invalid_instruction(function_index, index, "stack size is not balanced"));
+            };
+
+            continue;
+        };
+
+        stack_sizes[index] = Some(stack_size);
+
+        let instruction = instructions[index];
+        let opcode = instruction.opcode();
+        let (pop_count, push_count) = instruction.stack_effect();
+
+        // set instructions keep the value on the top of stack
+        let required_count = match opcode {
+            OpCode::LocalSet | OpCode::CellSet | OpCode::ContextSet | OpCode::UpvalueSet | OpCode::GlobalSet |
+            OpCode::InstanceSet | OpCode::IndexSet => pop_count + 1,
+            _ => pop_count
+        };
+
+        if stack_size < required_count {
+            return Err(invalid_instruction(function_index, index, "stack underflow"));
+        };
+
+        let next_stack_size = stack_size - pop_count + push_count;
+
+        match opcode {
+            OpCode::Return | OpCode::Raise => {},
+            OpCode::Jump => pending.push((instruction.operand() as usize, next_stack_size)),
+            OpCode::JumpIf | OpCode::JumpIfNot => {
+                pending.push((instruction.operand() as usize, next_stack_size));
+                pending.push((index + 1, next_stack_size));
+            },
+            OpCode::ForNext => {
+                // the condition is popped by JumpIf, the value of iterator is left when the loop continues
+                stack_sizes[index + 1] = Some(next_stack_size);
+                pending.push((instructions[index + 1].operand() as usize, stack_size));
+                pending.push((index + 2, stack_size + 1));
+            },
+            _ => pending.push((index + 1, next_stack_size))
+        };
+    };
+
+    // handlers can only drop objects from the stack
+    for handler in &function.handlers {
+        for stack_size in stack_sizes[handler.start..handler.end].iter().flatten() {
+            if handler.stack_size > *stack_size {
+                return Err(invalid_instruction(function_index, handler.start, "stack size of handler is larger than the protected instructions"));
+            };
+        };
+    };
+
+    Ok(())
+}
+
+fn verify_function(program: &Program, function_index: usize, function: &Function) -> Result<(), BytecodeError> {
+    if function.parameter_count > function.local_count {
+        return Err(invalid_instruction(function_index, 0, "parameter count is larger than local count"));
+    };
+
+    if function.instructions.is_empty() {
+        return Err(invalid_instruction(function_index, 0, "function has no instruction"));
+    };
+
+    for handler in &function.handlers {
+        if handler.start > handler.end || handler.end > function.instructions.len() || handler.target >= function.instructions.len() {
+            return Err(invalid_instruction(function_index, handler.start, "handler out of range"));
+        };
+    };
+
+    verify_operands(program, function_index, function)?;
+    verify_stack(function_index, function)
+}
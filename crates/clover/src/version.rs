@@ -1,3 +1,2 @@
-pub const MAJOR: u8 = 0;
-pub const MINOR: u8 = 1;
-pub const PATCH: u8 = 13;
\ No newline at end of file
+// layout version of bytecode, checked when loading, bump it whenever the layout changes
+pub const BYTECODE_FORMAT_VERSION: u16 = 1;